@@ -38,6 +38,8 @@
         craneLib = crane.lib.${system}.overrideToolchain toolchain;
         runtimeInputs = with pkgs; [
           cloud-hypervisor
+          virtiofsd
+          qemu-utils
           nftables
         ];
         buildInputs = with pkgs; [
@@ -89,7 +91,12 @@
                 boot = {
                   kernelParams = [ "console=ttyS0" "panic=1" "boot.panic_on_fail" ];
                   initrd.kernelModules = [ "virtio_scsi" "virtio_pci" "virtio_net" ];
-                  kernelModules = [ "virtio_pci" "virtio_net" ];
+                  kernelModules = [ "virtio_pci" "virtio_net" "vmw_vsock_virtio_transport" ];
+                };
+
+                systemd.services.tinyvmm-agent = {
+                  wantedBy = [ "multi-user.target" ];
+                  serviceConfig.ExecStart = "${tinyvmm}/bin/tinyvmm-agent";
                 };
               })
             ];
@@ -142,12 +149,14 @@
           config = mkIf cfg.enable {
             systemd.services.tinyvmm = {
               wantedBy = [ "multi-user.target" ];
+              path = [ pkgs.qemu-utils ];
               script = ''
                 ${self.packages.${pkgs.system}.default}/bin/tinyvmm \
                 --store ''${STATE_DIRECTORY}/store.db \
                 serve \
                 --listen ''${RUNTIME_DIRECTORY}/sock \
                 --listen-dns "${cfg.dnsAddress}" \
+                --virtiofsd ${pkgs.virtiofsd}/bin/virtiofsd \
                 -vvv
               '';
               serviceConfig = {
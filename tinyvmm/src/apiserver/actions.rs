@@ -0,0 +1,197 @@
+use std::{fmt, str::FromStr};
+
+use actix_web::{post, web, HttpResponse};
+use log::info;
+use serde::Serialize;
+
+use super::error::ApiError;
+use crate::{
+    ch::{api::Client, runtime},
+    database::{
+        entity::Entity,
+        error::Error,
+        store::Store,
+        virtual_machine::{RunState, RunStrategy, VirtualMachine},
+    },
+    systemd,
+};
+
+/// How many times the desired state is written again when the vm changed
+/// under the action, the conflict is returned after that.
+const UPDATE_ATTEMPTS: usize = 5;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum Action {
+    Start,
+    Stop,
+    Restart,
+    Reboot,
+    Pause,
+    Resume,
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "start" => Ok(Action::Start),
+            "stop" => Ok(Action::Stop),
+            "restart" => Ok(Action::Restart),
+            "reboot" => Ok(Action::Reboot),
+            "pause" => Ok(Action::Pause),
+            "resume" => Ok(Action::Resume),
+            _ => Err(format!("unknown vm action `{s}`")),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self {
+            Action::Start => "start",
+            Action::Stop => "stop",
+            Action::Restart => "restart",
+            Action::Reboot => "reboot",
+            Action::Pause => "pause",
+            Action::Resume => "resume",
+        };
+        write!(f, "{action}")
+    }
+}
+
+impl Action {
+    fn desired_state(&self) -> RunState {
+        match self {
+            Action::Stop => RunState::Stopped,
+            Action::Pause => RunState::Paused,
+            _ => RunState::Running,
+        }
+    }
+
+    /// Switches the vms kept running or halted by the unit server, so that it
+    /// doesn't undo the action.
+    fn run_strategy(&self, current: RunStrategy) -> RunStrategy {
+        match (self, current) {
+            (Action::Stop, RunStrategy::Always | RunStrategy::RerunOnFailure) => {
+                RunStrategy::Halted
+            }
+            (Action::Start | Action::Restart, RunStrategy::Halted) => RunStrategy::Always,
+            (_, current) => current,
+        }
+    }
+
+    /// The actions that go through the hypervisor need it to be up.
+    fn needs_running_vm(&self) -> bool {
+        matches!(self, Action::Reboot | Action::Pause | Action::Resume)
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ActionOutcome {
+    action: Action,
+    desired_state: RunState,
+    unit_active_state: String,
+}
+
+/// Records the requested state in the vm status and the matching run strategy,
+/// retrying a few times if the vm was updated concurrently by the unit server.
+pub(super) fn record_desired_state(store: &Store, name: &str, action: Action) -> Result<(), Error> {
+    let mut attempts = 0;
+    loop {
+        let mut vm = VirtualMachine::get(store, name)?;
+        vm.status.desired_state = Some(action.desired_state());
+        vm.spec.run_strategy = action.run_strategy(vm.spec.run_strategy);
+        match vm.update(store) {
+            Err(Error::Conflict { .. }) if attempts + 1 < UPDATE_ATTEMPTS => attempts += 1,
+            result => return result.map(|_| ()),
+        }
+    }
+}
+
+async fn run(name: &str, action: Action) -> Result<(), String> {
+    let api = Client::for_vm(name);
+    match action {
+        Action::Start => systemd::start_service(name)
+            .await
+            .map_err(|e| e.to_string()),
+        Action::Stop => systemd::stop_service(name).await.map_err(|e| e.to_string()),
+        Action::Restart => systemd::restart_service(name)
+            .await
+            .map_err(|e| e.to_string()),
+        Action::Reboot => runtime::reboot_vm(&api).await.map_err(|e| e.to_string()),
+        Action::Pause => runtime::pause_vm(&api).await.map_err(|e| e.to_string()),
+        Action::Resume => runtime::resume_vm(&api).await.map_err(|e| e.to_string()),
+    }
+}
+
+#[post("{name}/{action}")]
+async fn vm_action(
+    store: web::Data<Store>,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, ApiError> {
+    let (name, action) = path.into_inner();
+    let action: Action = action.parse().map_err(ApiError::BadRequest)?;
+
+    let vm = VirtualMachine::get(&store, &name)?;
+    if vm.metadata.deletion_timestamp.is_some() {
+        return Err(ApiError::InvalidState(format!("{name} is being deleted")));
+    }
+
+    let state = systemd::get_service_state(&name)
+        .await
+        .map_err(|e| ApiError::Action(format!("failed to get the state of {name}: {e}")))?;
+    if action.needs_running_vm() && state.active_state != "active" {
+        return Err(ApiError::InvalidState(format!(
+            "{name} is not running, its unit is {}",
+            state.active_state
+        )));
+    }
+
+    record_desired_state(&store, &name, action)?;
+
+    info!("running {} on {}", action, name);
+    run(&name, action)
+        .await
+        .map_err(|e| ApiError::Action(format!("failed to {action} {name}: {e}")))?;
+
+    let state = systemd::get_service_state(&name)
+        .await
+        .map_err(|e| ApiError::Action(format!("failed to get the state of {name}: {e}")))?;
+
+    Ok(HttpResponse::Ok().json(ActionOutcome {
+        action,
+        desired_state: action.desired_state(),
+        unit_active_state: state.active_state,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, RunState, RunStrategy};
+
+    #[test]
+    fn keeps_the_unit_server_from_undoing_the_actions() {
+        use RunStrategy::*;
+
+        for (action, current, strategy) in [
+            (Action::Stop, Always, Halted),
+            (Action::Stop, RerunOnFailure, Halted),
+            (Action::Stop, Manual, Manual),
+            (Action::Start, Halted, Always),
+            (Action::Restart, Halted, Always),
+            (Action::Start, Manual, Manual),
+            (Action::Pause, Always, Always),
+        ] {
+            assert_eq!(
+                action.run_strategy(current),
+                strategy,
+                "{action} {current:?}"
+            );
+        }
+        assert_eq!(Action::Pause.desired_state(), RunState::Paused);
+        assert_eq!(Action::Reboot.desired_state(), RunState::Running);
+    }
+}
@@ -0,0 +1,118 @@
+use std::time::Duration;
+
+use actix_web::{get, post, web, HttpResponse};
+use log::info;
+use tinyvmm_agent::{Exec, ReadFile, WriteFile};
+
+use super::error::ApiError;
+use crate::{
+    ch::agent::Agent,
+    database::{entity::Entity, store::Store, virtual_machine::VirtualMachine},
+    systemd,
+};
+
+/// The commands run through the agent may take a while.
+const EXEC_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The agent of the vm, once it's known to be running.
+async fn agent(store: &Store, name: &str) -> Result<Agent, ApiError> {
+    let vm = VirtualMachine::get(store, name)?;
+    if vm.metadata.deletion_timestamp.is_some() {
+        return Err(ApiError::InvalidState(format!("{name} is being deleted")));
+    }
+
+    let state = systemd::get_service_state(name)
+        .await
+        .map_err(|e| ApiError::Action(format!("failed to get the state of {name}: {e}")))?;
+    if state.active_state != "active" {
+        return Err(ApiError::InvalidState(format!(
+            "{name} is not running, its unit is {}",
+            state.active_state
+        )));
+    }
+
+    Ok(Agent::for_vm(name))
+}
+
+fn agent_error(name: &str, e: impl ToString) -> ApiError {
+    ApiError::Action(format!("the agent of {name} failed: {}", e.to_string()))
+}
+
+#[get("{name}/agent/interfaces")]
+async fn guest_interfaces(
+    store: web::Data<Store>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let agent = agent(&store, &name).await?;
+    let interfaces = agent
+        .interfaces()
+        .await
+        .map_err(|e| agent_error(&name, e))?;
+
+    Ok(HttpResponse::Ok().json(interfaces))
+}
+
+/// Asks the guest to power off, unlike the stop action the run strategy is
+/// left alone.
+#[post("{name}/agent/shutdown")]
+async fn guest_shutdown(
+    store: web::Data<Store>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let agent = agent(&store, &name).await?;
+    info!("asking {} to shut down", name);
+    agent.shutdown().await.map_err(|e| agent_error(&name, e))?;
+
+    Ok(HttpResponse::Accepted().finish())
+}
+
+#[post("{name}/agent/exec")]
+async fn guest_exec(
+    store: web::Data<Store>,
+    name: web::Path<String>,
+    exec: web::Json<Exec>,
+) -> Result<HttpResponse, ApiError> {
+    if exec.command.is_empty() {
+        return Err(ApiError::BadRequest("the command is empty".into()));
+    }
+
+    let agent = agent(&store, &name).await?.with_timeout(EXEC_TIMEOUT);
+    info!("running {:?} in {}", exec.command, name);
+    let result = agent
+        .exec(exec.into_inner())
+        .await
+        .map_err(|e| agent_error(&name, e))?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[post("{name}/agent/read")]
+async fn guest_read_file(
+    store: web::Data<Store>,
+    name: web::Path<String>,
+    read: web::Json<ReadFile>,
+) -> Result<HttpResponse, ApiError> {
+    let agent = agent(&store, &name).await?;
+    let content = agent
+        .read_file(read.into_inner())
+        .await
+        .map_err(|e| agent_error(&name, e))?;
+
+    Ok(HttpResponse::Ok().json(content))
+}
+
+#[post("{name}/agent/write")]
+async fn guest_write_file(
+    store: web::Data<Store>,
+    name: web::Path<String>,
+    write: web::Json<WriteFile>,
+) -> Result<HttpResponse, ApiError> {
+    let agent = agent(&store, &name).await?;
+    info!("writing {} in {}", write.path, name);
+    agent
+        .write_file(write.into_inner())
+        .await
+        .map_err(|e| agent_error(&name, e))?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -1,22 +1,35 @@
-use actix_web::{delete, get, post, web, Responder};
+use actix_web::{delete, get, patch, post, put, web, HttpResponse, Responder};
 use serde_valid::json::FromJsonValue;
 
-use crate::database::{bridge::Bridge, entity::Entity, store::Store};
+use super::{
+    error::ApiError,
+    watch::{watch, WatchCache},
+    ListOptions,
+};
+use crate::database::{
+    admission::admit_bridge, bridge::Bridge, entity::Entity, serde::merge_patch, store::Store,
+};
 
 #[get("")]
 async fn list_bridges(
     store: web::Data<Store>,
-) -> Result<impl Responder, Box<dyn std::error::Error>> {
-    let vms = Bridge::list(&store)?;
+    cache: web::Data<WatchCache>,
+    query: web::Query<ListOptions>,
+) -> Result<HttpResponse, ApiError> {
+    let selector = query.selector()?;
+    if query.watch {
+        return watch::<Bridge>(&cache, selector, query.resource_version).await;
+    }
+    let bridges = Bridge::list_matching(&store, &selector)?;
 
-    Ok(web::Json(vms))
+    Ok(HttpResponse::Ok().json(bridges))
 }
 
 #[get("{name}")]
 async fn get_bridge(
     store: web::Data<Store>,
     path: web::Path<String>,
-) -> Result<impl Responder, Box<dyn std::error::Error>> {
+) -> Result<impl Responder, ApiError> {
     let vms = Bridge::get(&store, path.into_inner())?;
 
     Ok(web::Json(vms))
@@ -26,21 +39,61 @@ async fn get_bridge(
 async fn delete_bridge(
     store: web::Data<Store>,
     path: web::Path<String>,
-) -> Result<impl Responder, Box<dyn std::error::Error>> {
-    Bridge::delete(&store, path.into_inner())?;
-
-    Ok(web::Bytes::from(""))
+) -> Result<HttpResponse, ApiError> {
+    if Bridge::delete(&store, path.into_inner())? {
+        Ok(HttpResponse::Ok().finish())
+    } else {
+        Ok(HttpResponse::Accepted().finish())
+    }
 }
 
 #[post("")]
 async fn create_bridge(
     store: web::Data<Store>,
     vm: web::Json<serde_json::Value>,
-) -> Result<impl Responder, Box<dyn std::error::Error>> {
+) -> Result<HttpResponse, ApiError> {
     let vm = Bridge::from_json_value(vm.0)?;
-    vm.create(&store)?;
+    admit_bridge(&store, &vm)?;
+    let vm = vm.create(&store)?;
+
+    Ok(HttpResponse::Ok().json(vm))
+}
+
+fn update(store: &Store, name: &str, bridge: serde_json::Value) -> Result<HttpResponse, ApiError> {
+    let bridge = Bridge::from_json_value(bridge)?;
+    if bridge.metadata.name != name {
+        return Err(ApiError::BadRequest(
+            "metadata.name doesn't match the request path".into(),
+        ));
+    }
+    admit_bridge(store, &bridge)?;
+
+    Ok(HttpResponse::Ok().json(bridge.update(store)?))
+}
+
+#[put("{name}")]
+async fn update_bridge(
+    store: web::Data<Store>,
+    path: web::Path<String>,
+    bridge: web::Json<serde_json::Value>,
+) -> Result<HttpResponse, ApiError> {
+    update(&store, &path.into_inner(), bridge.0)
+}
+
+#[patch("{name}")]
+async fn patch_bridge(
+    store: web::Data<Store>,
+    path: web::Path<String>,
+    patch: web::Bytes,
+) -> Result<HttpResponse, ApiError> {
+    let name = path.into_inner();
+    let patch: serde_json::Value = serde_json::from_slice(&patch)
+        .map_err(|e| ApiError::BadRequest(format!("invalid patch: {e}")))?;
+
+    let mut bridge = serde_json::to_value(Bridge::get(&store, &name)?)?;
+    merge_patch(&mut bridge, &patch);
 
-    Ok(web::Json(vm))
+    update(&store, &name, bridge)
 }
 
 pub fn bridges_apis(cfg: &mut web::ServiceConfig) {
@@ -49,6 +102,8 @@ pub fn bridges_apis(cfg: &mut web::ServiceConfig) {
             .service(list_bridges)
             .service(create_bridge)
             .service(get_bridge)
+            .service(update_bridge)
+            .service(patch_bridge)
             .service(delete_bridge),
     );
 }
@@ -0,0 +1,267 @@
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::database::error::{Cause, Error};
+
+/// Why a request failed, in the error responses.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Reason {
+    BadRequest,
+    NotFound,
+    AlreadyExists,
+    Conflict,
+    Invalid,
+    Expired,
+    InternalError,
+}
+
+/// The body of every error response.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Status {
+    pub code: u16,
+    pub reason: Reason,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub causes: Vec<Cause>,
+}
+
+#[derive(Error, Debug)]
+pub enum ApiError {
+    #[error(transparent)]
+    Database(#[from] Error),
+
+    #[error("{0}")]
+    BadRequest(String),
+
+    #[error("{0}")]
+    InvalidState(String),
+
+    #[error("{0}")]
+    Action(String),
+
+    #[error("cannot resume from resource version {0}, list the entities again")]
+    Expired(u64),
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(e: serde_json::Error) -> Self {
+        ApiError::Database(e.into())
+    }
+}
+
+impl From<serde_valid::Error<serde_json::Error>> for ApiError {
+    fn from(e: serde_valid::Error<serde_json::Error>) -> Self {
+        ApiError::Database(e.into())
+    }
+}
+
+/// Flattens the serde_valid errors tree into the causes, one per message.
+fn validation_causes(path: &str, errors: &Value, causes: &mut Vec<Cause>) {
+    let field = |name: &str| match path {
+        "" => name.to_string(),
+        path => format!("{path}.{name}"),
+    };
+
+    if let Some(messages) = errors.get("errors").and_then(Value::as_array) {
+        for message in messages {
+            causes.push(Cause {
+                field: path.into(),
+                message: message
+                    .as_str()
+                    .map(String::from)
+                    .unwrap_or_else(|| message.to_string()),
+            });
+        }
+    }
+    if let Some(properties) = errors.get("properties").and_then(Value::as_object) {
+        for (name, errors) in properties {
+            validation_causes(&field(name), errors, causes);
+        }
+    }
+    if let Some(items) = errors.get("items").and_then(Value::as_object) {
+        for (index, errors) in items {
+            validation_causes(&format!("{path}[{index}]"), errors, causes);
+        }
+    }
+}
+
+impl ApiError {
+    fn reason(&self) -> Reason {
+        match self {
+            ApiError::BadRequest(_) => Reason::BadRequest,
+            ApiError::Expired(_) => Reason::Expired,
+            ApiError::InvalidState(_) => Reason::Conflict,
+            ApiError::Action(_) => Reason::InternalError,
+            ApiError::Database(e) => match e {
+                Error::NotFound => Reason::NotFound,
+                Error::KeyExists { .. } => Reason::AlreadyExists,
+                Error::Conflict { .. } => Reason::Conflict,
+                Error::Invalid { .. } => Reason::Invalid,
+                Error::SerializeValidation(serde_valid::Error::ValidationError(_)) => {
+                    Reason::Invalid
+                }
+                Error::SerializeValidation(serde_valid::Error::DeserializeError(_))
+                | Error::InvalidSelector(_) => Reason::BadRequest,
+                _ => Reason::InternalError,
+            },
+        }
+    }
+
+    fn causes(&self) -> Vec<Cause> {
+        match self {
+            ApiError::Database(Error::Invalid { causes, .. }) => causes.clone(),
+            ApiError::Database(Error::SerializeValidation(
+                serde_valid::Error::ValidationError(errors),
+            )) => {
+                let mut causes = vec![];
+                if let Ok(errors) = serde_json::to_value(errors) {
+                    validation_causes("", &errors, &mut causes);
+                }
+                causes
+            }
+            _ => vec![],
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        Status {
+            code: self.status_code().as_u16(),
+            reason: self.reason(),
+            message: self.to_string(),
+            causes: self.causes(),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self.reason() {
+            Reason::BadRequest => StatusCode::BAD_REQUEST,
+            Reason::NotFound => StatusCode::NOT_FOUND,
+            Reason::AlreadyExists | Reason::Conflict => StatusCode::CONFLICT,
+            Reason::Invalid => StatusCode::UNPROCESSABLE_ENTITY,
+            Reason::Expired => StatusCode::GONE,
+            Reason::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(self.status())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{body::to_bytes, http::StatusCode, ResponseError};
+    use serde_json::json;
+
+    use super::{validation_causes, ApiError, Reason, Status};
+    use crate::{
+        client::error::Error as ClientError,
+        database::error::{Cause, Error},
+    };
+
+    #[test]
+    fn maps_the_store_errors_to_their_status() {
+        for (error, code, reason) in [
+            (Error::NotFound.into(), 404, Reason::NotFound),
+            (
+                Error::KeyExists {
+                    kind: "VirtualMachine".into(),
+                    name: "vm1".into(),
+                }
+                .into(),
+                409,
+                Reason::AlreadyExists,
+            ),
+            (
+                Error::Conflict {
+                    kind: "VirtualMachine".into(),
+                    name: "vm1".into(),
+                }
+                .into(),
+                409,
+                Reason::Conflict,
+            ),
+            (
+                Error::InvalidSelector("-env".into()).into(),
+                400,
+                Reason::BadRequest,
+            ),
+            (Error::MissingKey("kind").into(), 500, Reason::InternalError),
+            (ApiError::Expired(3), 410, Reason::Expired),
+            (
+                ApiError::InvalidState("vm1 is being deleted".into()),
+                409,
+                Reason::Conflict,
+            ),
+        ] {
+            let error: ApiError = error;
+            assert_eq!(error.status_code().as_u16(), code, "{error}");
+            assert_eq!(error.status().reason, reason, "{error}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn returns_the_causes_the_client_decodes() {
+        let error = ApiError::from(Error::Invalid {
+            kind: "VirtualMachine",
+            name: "vm1".into(),
+            causes: vec![Cause {
+                field: "spec.interfaces[0].mac".into(),
+                message: "02:00:00:00:00:01 is already allocated to `vm2`".into(),
+            }],
+        });
+        let response = error.error_response();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let body = to_bytes(response.into_body()).await.unwrap();
+        let status: Status = serde_json::from_slice(&body).unwrap();
+        assert_eq!(status.code, 422);
+        match ClientError::from(status) {
+            ClientError::Invalid { causes, .. } => {
+                assert_eq!(causes.len(), 1);
+                assert_eq!(causes[0].field, "spec.interfaces[0].mac");
+            }
+            e => panic!("unexpected {e:?}"),
+        }
+    }
+
+    #[test]
+    fn flattens_the_validation_errors() {
+        let errors = json!({
+            "errors": [],
+            "properties": {
+                "spec": {
+                    "errors": [],
+                    "properties": {
+                        "cpus": { "errors": ["the number must be `>= 1`."] },
+                        "disks": {
+                            "errors": [],
+                            "items": {
+                                "1": { "errors": ["disk path `/nope` doesn't exist"] },
+                            },
+                        },
+                    },
+                },
+            },
+        });
+        let mut causes = vec![];
+        validation_causes("", &errors, &mut causes);
+
+        let causes: Vec<_> = causes
+            .iter()
+            .map(|c| (c.field.as_str(), c.message.as_str()))
+            .collect();
+        assert_eq!(
+            causes,
+            [
+                ("spec.cpus", "the number must be `>= 1`."),
+                ("spec.disks[1]", "disk path `/nope` doesn't exist"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,43 @@
+use actix_web::{get, web, HttpResponse};
+use serde::Deserialize;
+
+use super::error::ApiError;
+
+use crate::database::{
+    backup::{encode_entity, Format},
+    store::Store,
+};
+
+#[derive(Deserialize, Debug)]
+struct ExportOptions {
+    #[serde(default)]
+    format: Format,
+}
+
+#[get("")]
+async fn export(
+    store: web::Data<Store>,
+    query: web::Query<ExportOptions>,
+) -> Result<HttpResponse, ApiError> {
+    let format = query.format;
+    let entities = store.snapshot()?;
+
+    let chunks = entities.into_iter().map(move |entity| {
+        encode_entity(&entity, format)
+            .map(web::Bytes::from)
+            .map_err(actix_web::error::ErrorInternalServerError)
+    });
+
+    let content_type = match format {
+        Format::JsonLines => "application/x-ndjson",
+        Format::Yaml => "application/yaml",
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(content_type)
+        .streaming(futures::stream::iter(chunks)))
+}
+
+pub fn export_apis(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::scope("/api/v1/export").service(export));
+}
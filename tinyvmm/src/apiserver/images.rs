@@ -0,0 +1,130 @@
+use actix_web::{delete, get, patch, post, put, web, HttpResponse, Responder};
+use serde_valid::json::FromJsonValue;
+
+use super::{
+    error::ApiError,
+    watch::{watch, WatchCache},
+    ListOptions,
+};
+use crate::database::{
+    admission::admit_image,
+    entity::Entity,
+    image::{self, Image},
+    serde::merge_patch,
+    store::Store,
+};
+
+#[get("")]
+async fn list_images(
+    store: web::Data<Store>,
+    cache: web::Data<WatchCache>,
+    query: web::Query<ListOptions>,
+) -> Result<HttpResponse, ApiError> {
+    let selector = query.selector()?;
+    if query.watch {
+        return watch::<Image>(&cache, selector, query.resource_version).await;
+    }
+    let images = Image::list_matching(&store, &selector)?;
+
+    Ok(HttpResponse::Ok().json(images))
+}
+
+#[get("{name}")]
+async fn get_image(
+    store: web::Data<Store>,
+    path: web::Path<String>,
+) -> Result<impl Responder, ApiError> {
+    let image = Image::get(&store, path.into_inner())?;
+
+    Ok(web::Json(image))
+}
+
+/// The cached copy is removed by the unit server, an image can't be
+/// deleted while volumes are created from it.
+#[delete("{name}")]
+async fn delete_image(
+    store: web::Data<Store>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let name = path.into_inner();
+    Image::get(&store, &name)?;
+
+    let volumes = image::dependents(&store, &name)?;
+    if !volumes.is_empty() {
+        return Err(ApiError::InvalidState(format!(
+            "{name} is the image of {}",
+            volumes.join(", ")
+        )));
+    }
+
+    if Image::delete(&store, &name)? {
+        Ok(HttpResponse::Ok().finish())
+    } else {
+        Ok(HttpResponse::Accepted().finish())
+    }
+}
+
+#[post("")]
+async fn create_image(
+    store: web::Data<Store>,
+    image: web::Json<serde_json::Value>,
+) -> Result<HttpResponse, ApiError> {
+    let mut image = Image::from_json_value(image.0)?;
+    image.status = Default::default();
+    admit_image(&store, &image)?;
+    let image = image.create(&store)?;
+
+    Ok(HttpResponse::Ok().json(image))
+}
+
+fn update(store: &Store, name: &str, image: serde_json::Value) -> Result<HttpResponse, ApiError> {
+    let mut image = Image::from_json_value(image)?;
+    if image.metadata.name != name {
+        return Err(ApiError::BadRequest(
+            "metadata.name doesn't match the request path".into(),
+        ));
+    }
+
+    // the status is only maintained by the unit server
+    image.status = Image::get(store, name)?.status;
+    admit_image(store, &image)?;
+
+    Ok(HttpResponse::Ok().json(image.update(store)?))
+}
+
+#[put("{name}")]
+async fn update_image(
+    store: web::Data<Store>,
+    path: web::Path<String>,
+    image: web::Json<serde_json::Value>,
+) -> Result<HttpResponse, ApiError> {
+    update(&store, &path.into_inner(), image.0)
+}
+
+#[patch("{name}")]
+async fn patch_image(
+    store: web::Data<Store>,
+    path: web::Path<String>,
+    patch: web::Bytes,
+) -> Result<HttpResponse, ApiError> {
+    let name = path.into_inner();
+    let patch: serde_json::Value = serde_json::from_slice(&patch)
+        .map_err(|e| ApiError::BadRequest(format!("invalid patch: {e}")))?;
+
+    let mut image = serde_json::to_value(Image::get(&store, &name)?)?;
+    merge_patch(&mut image, &patch);
+
+    update(&store, &name, image)
+}
+
+pub fn images_apis(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/api/v1/images")
+            .service(list_images)
+            .service(create_image)
+            .service(get_image)
+            .service(update_image)
+            .service(patch_image)
+            .service(delete_image),
+    );
+}
@@ -1,11 +1,44 @@
 use actix_web::{web, App, HttpServer};
 use eyre::Context;
 use log::info;
+use serde::Deserialize;
 
-use crate::database::store::Store;
+use self::{error::ApiError, watch::WatchCache};
+use crate::database::{error::Error, selector::LabelSelector, store::Store};
 
+mod actions;
+mod agent;
 mod bridges;
+pub mod error;
+mod export;
+mod images;
+mod snapshots;
 mod virtualmachines;
+mod volumes;
+mod watch;
+
+/// Large enough for the files copied through the agents.
+const MAX_BODY_SIZE: usize = 64 << 20;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ListOptions {
+    label_selector: Option<String>,
+    /// Streams the changes instead of listing the entities.
+    #[serde(default)]
+    watch: bool,
+    /// The version to resume the watch after.
+    resource_version: Option<u64>,
+}
+
+impl ListOptions {
+    fn selector(&self) -> Result<LabelSelector, Error> {
+        match &self.label_selector {
+            Some(selector) => selector.parse(),
+            None => Ok(LabelSelector::default()),
+        }
+    }
+}
 
 pub async fn run_server<P>(uds_path: P, store: Store) -> eyre::Result<()>
 where
@@ -13,14 +46,32 @@ where
 {
     info!("starting the api server");
 
-    let server = HttpServer::new(move || {
-        App::new()
-            .app_data(web::Data::new(store.clone()))
-            .configure(virtualmachines::vms_apis)
-            .configure(bridges::bridges_apis)
-    })
-    .bind_uds(uds_path)
-    .wrap_err("failed to bind the api server listener")?;
+    let cache = WatchCache::start(&store).wrap_err("failed to start the watch cache")?;
+
+    let server =
+        HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(store.clone()))
+                .app_data(web::Data::new(cache.clone()))
+                .app_data(
+                    web::JsonConfig::default()
+                        .limit(MAX_BODY_SIZE)
+                        .error_handler(|e, _| {
+                            ApiError::BadRequest(format!("invalid request body: {e}")).into()
+                        }),
+                )
+                .app_data(web::QueryConfig::default().error_handler(|e, _| {
+                    ApiError::BadRequest(format!("invalid query: {e}")).into()
+                }))
+                .configure(virtualmachines::vms_apis)
+                .configure(bridges::bridges_apis)
+                .configure(volumes::volumes_apis)
+                .configure(images::images_apis)
+                .configure(snapshots::snapshots_apis)
+                .configure(export::export_apis)
+        })
+        .bind_uds(uds_path)
+        .wrap_err("failed to bind the api server listener")?;
 
     server.run().await.wrap_err("failed to run the api server")
 }
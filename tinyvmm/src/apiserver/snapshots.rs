@@ -0,0 +1,198 @@
+use actix_web::{delete, get, patch, post, put, web, HttpResponse, Responder};
+use log::{info, warn};
+use serde_valid::json::FromJsonValue;
+
+use super::{
+    actions::{record_desired_state, Action},
+    error::ApiError,
+    watch::{watch, WatchCache},
+    ListOptions,
+};
+use crate::{
+    ch,
+    database::{
+        admission::admit_snapshot,
+        entity::Entity,
+        serde::merge_patch,
+        snapshot::{self, snapshot_dir, Snapshot, SnapshotPhase},
+        store::Store,
+        virtual_machine::{full_spec_hash, VirtualMachine},
+    },
+    systemd,
+};
+
+#[get("")]
+async fn list_snapshots(
+    store: web::Data<Store>,
+    cache: web::Data<WatchCache>,
+    query: web::Query<ListOptions>,
+) -> Result<HttpResponse, ApiError> {
+    let selector = query.selector()?;
+    if query.watch {
+        return watch::<Snapshot>(&cache, selector, query.resource_version).await;
+    }
+    let snapshots = Snapshot::list_matching(&store, &selector)?;
+
+    Ok(HttpResponse::Ok().json(snapshots))
+}
+
+#[get("{name}")]
+async fn get_snapshot(
+    store: web::Data<Store>,
+    path: web::Path<String>,
+) -> Result<impl Responder, ApiError> {
+    let snapshot = Snapshot::get(&store, path.into_inner())?;
+
+    Ok(web::Json(snapshot))
+}
+
+/// The snapshots of the vm, in the order they were taken.
+#[get("{name}/snapshots")]
+pub(super) async fn list_vm_snapshots(
+    store: web::Data<Store>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let name = path.into_inner();
+    VirtualMachine::get(&store, &name)?;
+
+    Ok(HttpResponse::Ok().json(snapshot::of_vm(&store, &name)?))
+}
+
+/// The files are removed by the unit server.
+#[delete("{name}")]
+async fn delete_snapshot(
+    store: web::Data<Store>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let name = path.into_inner();
+    Snapshot::get(&store, &name)?;
+
+    if Snapshot::delete(&store, &name)? {
+        Ok(HttpResponse::Ok().finish())
+    } else {
+        Ok(HttpResponse::Accepted().finish())
+    }
+}
+
+#[post("")]
+async fn create_snapshot(
+    store: web::Data<Store>,
+    snapshot: web::Json<serde_json::Value>,
+) -> Result<HttpResponse, ApiError> {
+    let mut snapshot = Snapshot::from_json_value(snapshot.0)?;
+    snapshot.status = Default::default();
+    admit_snapshot(&store, &snapshot)?;
+    let snapshot = snapshot.create(&store)?;
+
+    Ok(HttpResponse::Ok().json(snapshot))
+}
+
+fn update(
+    store: &Store,
+    name: &str,
+    snapshot: serde_json::Value,
+) -> Result<HttpResponse, ApiError> {
+    let mut snapshot = Snapshot::from_json_value(snapshot)?;
+    if snapshot.metadata.name != name {
+        return Err(ApiError::BadRequest(
+            "metadata.name doesn't match the request path".into(),
+        ));
+    }
+
+    // the status is only maintained by the unit server
+    snapshot.status = Snapshot::get(store, name)?.status;
+    admit_snapshot(store, &snapshot)?;
+
+    Ok(HttpResponse::Ok().json(snapshot.update(store)?))
+}
+
+#[put("{name}")]
+async fn update_snapshot(
+    store: web::Data<Store>,
+    path: web::Path<String>,
+    snapshot: web::Json<serde_json::Value>,
+) -> Result<HttpResponse, ApiError> {
+    update(&store, &path.into_inner(), snapshot.0)
+}
+
+#[patch("{name}")]
+async fn patch_snapshot(
+    store: web::Data<Store>,
+    path: web::Path<String>,
+    patch: web::Bytes,
+) -> Result<HttpResponse, ApiError> {
+    let name = path.into_inner();
+    let patch: serde_json::Value = serde_json::from_slice(&patch)
+        .map_err(|e| ApiError::BadRequest(format!("invalid patch: {e}")))?;
+
+    let mut snapshot = serde_json::to_value(Snapshot::get(&store, &name)?)?;
+    merge_patch(&mut snapshot, &patch);
+
+    update(&store, &name, snapshot)
+}
+
+/// Restarts the vm from the snapshot, its disks are put back as they were by
+/// the unit before cloud-hypervisor restores the memory and devices.
+#[post("{name}/restore")]
+async fn restore_snapshot(
+    store: web::Data<Store>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let name = path.into_inner();
+    let snapshot = Snapshot::get(&store, &name)?;
+    if snapshot.metadata.deletion_timestamp.is_some() {
+        return Err(ApiError::InvalidState(format!("{name} is being deleted")));
+    }
+    if snapshot.status.phase != SnapshotPhase::Ready {
+        return Err(ApiError::InvalidState(format!(
+            "{name} is {:?}, not Ready",
+            snapshot.status.phase
+        )));
+    }
+
+    let vm_name = &snapshot.spec.vm;
+    let vm = VirtualMachine::get(&store, vm_name)?;
+    if vm.metadata.deletion_timestamp.is_some() {
+        return Err(ApiError::InvalidState(format!(
+            "{vm_name} is being deleted"
+        )));
+    }
+    // the devices of the snapshot have to match the config of the vm
+    if snapshot.status.spec_hash != Some(full_spec_hash(&vm)?) {
+        return Err(ApiError::InvalidState(format!(
+            "the spec of {vm_name} changed since {name} was taken"
+        )));
+    }
+
+    info!("restoring {} from {}", vm_name, name);
+    ch::snapshot::request_restore(vm_name, &name, &snapshot_dir(&snapshot))
+        .map_err(|e| ApiError::Action(format!("failed to restore {vm_name}: {e}")))?;
+    let restarted = match record_desired_state(&store, vm_name, Action::Restart) {
+        Ok(()) => systemd::restart_service(vm_name)
+            .await
+            .map_err(|e| ApiError::Action(format!("failed to restart {vm_name}: {e}"))),
+        Err(e) => Err(e.into()),
+    };
+    if restarted.is_err() {
+        // the next start boots the vm afresh instead of restoring it unasked
+        if let Err(e) = ch::snapshot::cancel_restore(vm_name) {
+            warn!("failed to cancel the restore of {}: {}", vm_name, e);
+        }
+    }
+    restarted?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub fn snapshots_apis(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/api/v1/snapshots")
+            .service(list_snapshots)
+            .service(create_snapshot)
+            .service(get_snapshot)
+            .service(update_snapshot)
+            .service(patch_snapshot)
+            .service(delete_snapshot)
+            .service(restore_snapshot),
+    );
+}
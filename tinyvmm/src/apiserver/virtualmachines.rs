@@ -1,20 +1,43 @@
-use actix_web::{delete, get, post, web, Responder};
-use serde_valid::json::FromJsonValue;
+use actix_web::{delete, get, patch, post, put, web, HttpResponse, Responder};
+use serde::Deserialize;
+use serde_valid::{json::FromJsonValue, Validate};
 
-use crate::database::{entity::Entity, store::Store, virtual_machine::VirtualMachine};
+use super::{
+    actions, agent,
+    error::ApiError,
+    snapshots,
+    watch::{watch, WatchCache},
+    ListOptions,
+};
+use crate::database::{
+    admission::admit_virtual_machine,
+    entity::Entity,
+    ipam,
+    serde::merge_patch,
+    store::Store,
+    virtual_machine::{VirtualMachine, VirtualMachineStatus},
+};
 
 #[get("")]
-async fn list_vms(store: web::Data<Store>) -> Result<impl Responder, Box<dyn std::error::Error>> {
-    let vms = VirtualMachine::list(&store)?;
+async fn list_vms(
+    store: web::Data<Store>,
+    cache: web::Data<WatchCache>,
+    query: web::Query<ListOptions>,
+) -> Result<HttpResponse, ApiError> {
+    let selector = query.selector()?;
+    if query.watch {
+        return watch::<VirtualMachine>(&cache, selector, query.resource_version).await;
+    }
+    let vms = VirtualMachine::list_matching(&store, &selector)?;
 
-    Ok(web::Json(vms))
+    Ok(HttpResponse::Ok().json(vms))
 }
 
 #[get("{name}")]
 async fn get_vm(
     store: web::Data<Store>,
     path: web::Path<String>,
-) -> Result<impl Responder, Box<dyn std::error::Error>> {
+) -> Result<impl Responder, ApiError> {
     let vms = VirtualMachine::get(&store, path.into_inner())?;
 
     Ok(web::Json(vms))
@@ -24,21 +47,140 @@ async fn get_vm(
 async fn delete_vm(
     store: web::Data<Store>,
     path: web::Path<String>,
-) -> Result<impl Responder, Box<dyn std::error::Error>> {
-    VirtualMachine::delete(&store, path.into_inner())?;
+) -> Result<HttpResponse, ApiError> {
+    let name = path.into_inner();
+    let vm = VirtualMachine::get(&store, &name)?;
 
-    Ok(web::Bytes::from(""))
+    // the vms deleted through finalizers are released by the unit server
+    if VirtualMachine::delete(&store, &name)? {
+        ipam::release_all(&store, &vm)?;
+        Ok(HttpResponse::Ok().finish())
+    } else {
+        Ok(HttpResponse::Accepted().finish())
+    }
 }
 
 #[post("")]
 async fn create_vm(
     store: web::Data<Store>,
     vm: web::Json<serde_json::Value>,
-) -> Result<impl Responder, Box<dyn std::error::Error>> {
-    let vm = VirtualMachine::from_json_value(vm.0)?;
-    vm.create(&store)?;
+) -> Result<HttpResponse, ApiError> {
+    let mut vm = VirtualMachine::from_json_value(vm.0)?;
+    vm.status = Default::default();
+    let claimed = ipam::assign(&store, &mut vm)?;
+
+    match admit_virtual_machine(&store, &vm).and_then(|_| vm.create(&store)) {
+        Ok(vm) => Ok(HttpResponse::Ok().json(vm)),
+        Err(e) => {
+            for (bridge, address) in claimed {
+                ipam::release(&store, &bridge, &address, &vm.metadata.name)?;
+            }
+            Err(e.into())
+        }
+    }
+}
+
+fn update(store: &Store, name: &str, vm: serde_json::Value) -> Result<HttpResponse, ApiError> {
+    let mut vm = VirtualMachine::from_json_value(vm)?;
+    if vm.metadata.name != name {
+        return Err(ApiError::BadRequest(
+            "metadata.name doesn't match the request path".into(),
+        ));
+    }
+
+    // the status can only be changed through the status subresource
+    let current = VirtualMachine::get(store, name)?;
+    vm.status = current.status.clone();
+
+    // keep the allocated addresses of the interfaces that stay on their bridge
+    for (interface, previous) in vm.spec.interfaces.iter_mut().zip(&current.spec.interfaces) {
+        if interface.ip.is_none() && interface.bridge == previous.bridge {
+            interface.ip = previous.ip.clone();
+        }
+    }
+    let claimed = ipam::assign(store, &mut vm)?;
+
+    match admit_virtual_machine(store, &vm).and_then(|_| vm.update(store)) {
+        Ok(vm) => {
+            ipam::release_dropped(store, &current, &vm)?;
+            Ok(HttpResponse::Ok().json(vm))
+        }
+        Err(e) => {
+            for (bridge, address) in claimed {
+                ipam::release(store, &bridge, &address, name)?;
+            }
+            Err(e.into())
+        }
+    }
+}
+
+#[put("{name}")]
+async fn update_vm(
+    store: web::Data<Store>,
+    path: web::Path<String>,
+    vm: web::Json<serde_json::Value>,
+) -> Result<HttpResponse, ApiError> {
+    update(&store, &path.into_inner(), vm.0)
+}
+
+#[patch("{name}")]
+async fn patch_vm(
+    store: web::Data<Store>,
+    path: web::Path<String>,
+    patch: web::Bytes,
+) -> Result<HttpResponse, ApiError> {
+    let name = path.into_inner();
+    let patch: serde_json::Value = serde_json::from_slice(&patch)
+        .map_err(|e| ApiError::BadRequest(format!("invalid patch: {e}")))?;
+
+    let mut vm = serde_json::to_value(VirtualMachine::get(&store, &name)?)?;
+    merge_patch(&mut vm, &patch);
+
+    update(&store, &name, vm)
+}
+
+#[get("{name}/status")]
+async fn get_vm_status(
+    store: web::Data<Store>,
+    path: web::Path<String>,
+) -> Result<impl Responder, ApiError> {
+    let vm = VirtualMachine::get(&store, path.into_inner())?;
+
+    Ok(web::Json(vm.status))
+}
+
+/// The parts of the vm read by the status subresource, the spec it was
+/// written against is left out of the validation.
+#[derive(Deserialize, Validate)]
+struct StatusUpdate {
+    metadata: StatusMetadata,
+    #[validate]
+    #[serde(default)]
+    status: VirtualMachineStatus,
+}
+
+#[derive(Deserialize, Validate)]
+#[serde(rename_all = "camelCase")]
+struct StatusMetadata {
+    #[serde(default)]
+    resource_version: u64,
+}
+
+#[put("{name}/status")]
+async fn update_vm_status(
+    store: web::Data<Store>,
+    path: web::Path<String>,
+    vm: web::Json<serde_json::Value>,
+) -> Result<HttpResponse, ApiError> {
+    let name = path.into_inner();
+    let update = StatusUpdate::from_json_value(vm.0)?;
+
+    // only the status is taken from the request, everything else is kept
+    let mut current = VirtualMachine::get(&store, &name)?;
+    current.metadata.resource_version = update.metadata.resource_version;
+    current.status = update.status;
 
-    Ok(web::Json(vm))
+    Ok(HttpResponse::Ok().json(current.update(&store)?))
 }
 
 pub fn vms_apis(cfg: &mut web::ServiceConfig) {
@@ -47,6 +189,72 @@ pub fn vms_apis(cfg: &mut web::ServiceConfig) {
             .service(list_vms)
             .service(create_vm)
             .service(get_vm)
-            .service(delete_vm),
+            .service(get_vm_status)
+            .service(update_vm_status)
+            .service(update_vm)
+            .service(patch_vm)
+            .service(delete_vm)
+            .service(actions::vm_action)
+            .service(agent::guest_interfaces)
+            .service(agent::guest_shutdown)
+            .service(agent::guest_exec)
+            .service(agent::guest_read_file)
+            .service(agent::guest_write_file)
+            .service(snapshots::list_vm_snapshots),
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test, web, App};
+    use serde_json::{json, Value};
+
+    use super::vms_apis;
+    use crate::{
+        database::{entity::Entity, store::Store},
+        fixtures::{test_bridge, test_vm},
+    };
+
+    #[actix_web::test]
+    async fn claims_the_addresses_until_the_vm_is_deleted() {
+        let store = Store::in_memory();
+        test_bridge("br0", json!({})).create(&store).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(store.clone()))
+                .configure(vms_apis),
+        )
+        .await;
+
+        let request = test::TestRequest::post()
+            .uri("/api/v1/virtualmachines")
+            .set_json(test_vm("vm1", json!({})))
+            .to_request();
+        let created: Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(created["spec"]["interfaces"][0]["ip"], "10.0.0.2");
+
+        let request = test::TestRequest::post()
+            .uri("/api/v1/virtualmachines")
+            .set_json(test_vm("vm2", json!({})))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let error: Value = test::read_body_json(response).await;
+        assert_eq!(error["causes"][0]["field"], "spec.interfaces[0].mac");
+
+        let request = test::TestRequest::delete()
+            .uri("/api/v1/virtualmachines/vm1")
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, request).await.status(),
+            StatusCode::OK
+        );
+
+        let request = test::TestRequest::post()
+            .uri("/api/v1/virtualmachines")
+            .set_json(test_vm("vm2", json!({})))
+            .to_request();
+        let created: Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(created["spec"]["interfaces"][0]["ip"], "10.0.0.2");
+    }
+}
@@ -0,0 +1,129 @@
+use actix_web::{delete, get, patch, post, put, web, HttpResponse, Responder};
+use serde_valid::json::FromJsonValue;
+
+use super::{
+    error::ApiError,
+    watch::{watch, WatchCache},
+    ListOptions,
+};
+use crate::database::{
+    admission::admit_volume,
+    entity::Entity,
+    serde::merge_patch,
+    store::Store,
+    volume::{self, Volume},
+};
+
+#[get("")]
+async fn list_volumes(
+    store: web::Data<Store>,
+    cache: web::Data<WatchCache>,
+    query: web::Query<ListOptions>,
+) -> Result<HttpResponse, ApiError> {
+    let selector = query.selector()?;
+    if query.watch {
+        return watch::<Volume>(&cache, selector, query.resource_version).await;
+    }
+    let volumes = Volume::list_matching(&store, &selector)?;
+
+    Ok(HttpResponse::Ok().json(volumes))
+}
+
+#[get("{name}")]
+async fn get_volume(
+    store: web::Data<Store>,
+    path: web::Path<String>,
+) -> Result<impl Responder, ApiError> {
+    let volume = Volume::get(&store, path.into_inner())?;
+
+    Ok(web::Json(volume))
+}
+
+/// The image is removed by the unit server, once no vm uses the volume.
+#[delete("{name}")]
+async fn delete_volume(
+    store: web::Data<Store>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let name = path.into_inner();
+    Volume::get(&store, &name)?;
+
+    let users = volume::users(&store, &name)?;
+    if !users.is_empty() {
+        return Err(ApiError::InvalidState(format!(
+            "{name} is used by {}",
+            users.join(", ")
+        )));
+    }
+
+    if Volume::delete(&store, &name)? {
+        Ok(HttpResponse::Ok().finish())
+    } else {
+        Ok(HttpResponse::Accepted().finish())
+    }
+}
+
+#[post("")]
+async fn create_volume(
+    store: web::Data<Store>,
+    volume: web::Json<serde_json::Value>,
+) -> Result<HttpResponse, ApiError> {
+    let mut volume = Volume::from_json_value(volume.0)?;
+    volume.status = Default::default();
+    admit_volume(&store, &volume)?;
+    let volume = volume.create(&store)?;
+
+    Ok(HttpResponse::Ok().json(volume))
+}
+
+fn update(store: &Store, name: &str, volume: serde_json::Value) -> Result<HttpResponse, ApiError> {
+    let mut volume = Volume::from_json_value(volume)?;
+    if volume.metadata.name != name {
+        return Err(ApiError::BadRequest(
+            "metadata.name doesn't match the request path".into(),
+        ));
+    }
+
+    // the status is only maintained by the unit server
+    volume.status = Volume::get(store, name)?.status;
+    admit_volume(store, &volume)?;
+
+    Ok(HttpResponse::Ok().json(volume.update(store)?))
+}
+
+#[put("{name}")]
+async fn update_volume(
+    store: web::Data<Store>,
+    path: web::Path<String>,
+    volume: web::Json<serde_json::Value>,
+) -> Result<HttpResponse, ApiError> {
+    update(&store, &path.into_inner(), volume.0)
+}
+
+#[patch("{name}")]
+async fn patch_volume(
+    store: web::Data<Store>,
+    path: web::Path<String>,
+    patch: web::Bytes,
+) -> Result<HttpResponse, ApiError> {
+    let name = path.into_inner();
+    let patch: serde_json::Value = serde_json::from_slice(&patch)
+        .map_err(|e| ApiError::BadRequest(format!("invalid patch: {e}")))?;
+
+    let mut volume = serde_json::to_value(Volume::get(&store, &name)?)?;
+    merge_patch(&mut volume, &patch);
+
+    update(&store, &name, volume)
+}
+
+pub fn volumes_apis(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/api/v1/volumes")
+            .service(list_volumes)
+            .service(create_volume)
+            .service(get_volume)
+            .service(update_volume)
+            .service(patch_volume)
+            .service(delete_volume),
+    );
+}
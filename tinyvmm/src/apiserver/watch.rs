@@ -0,0 +1,460 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use actix_web::{web, HttpResponse};
+use futures::{stream, StreamExt};
+use log::{debug, warn};
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::sync::{broadcast, watch};
+
+use super::error::ApiError;
+use crate::database::{
+    backend::Event, entity::Entity, error::Error, metadata::Metadata, selector::LabelSelector,
+    serde::ValueGetter, store::Store,
+};
+
+/// How many past events are kept for the watches to resume from.
+const HISTORY_SIZE: usize = 1024;
+
+/// How long a watch from a version the cache hasn't seen yet waits for it.
+/// The writes reach the cache shortly after the store returns them.
+const CATCH_UP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the watchers are told the latest version, so that they can
+/// resume past the events of the other kinds.
+const BOOKMARK_PERIOD: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "UPPERCASE")]
+enum EventType {
+    Added,
+    Modified,
+    Deleted,
+    Bookmark,
+}
+
+#[derive(Debug, Clone)]
+struct WatchEvent {
+    event_type: EventType,
+    key: String,
+    resource_version: u64,
+    object: Value,
+}
+
+impl WatchEvent {
+    fn bookmark(resource_version: u64) -> Self {
+        WatchEvent {
+            event_type: EventType::Bookmark,
+            key: String::new(),
+            resource_version,
+            object: Value::Null,
+        }
+    }
+}
+
+/// A single line of the watch stream. The resource version is the one to
+/// resume the watch after.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WatchLine {
+    #[serde(rename = "type")]
+    event_type: EventType,
+    resource_version: u64,
+    object: Value,
+}
+
+struct History {
+    events: VecDeque<WatchEvent>,
+    objects: HashMap<String, Value>,
+    /// The oldest version the watches can be resumed from.
+    oldest: u64,
+    latest: u64,
+}
+
+fn object_version(object: &Value) -> u64 {
+    object
+        .get("metadata")
+        .and_then(|m| m.get("resourceVersion"))
+        .and_then(Value::as_u64)
+        .unwrap_or_default()
+}
+
+fn object_key(object: &Value) -> Result<String, Error> {
+    let kind = object.get_existing("kind")?.as_str().unwrap_or_default();
+    let name = object
+        .get_existing("metadata")?
+        .get_existing("name")?
+        .as_str()
+        .unwrap_or_default();
+
+    Ok(format!("/{kind}/{name}"))
+}
+
+/// Keeps the recent store changes so that the watches can be resumed from a
+/// given resource version. The events carry the versions the store gave to
+/// the changes, deletions included.
+#[derive(Clone)]
+pub struct WatchCache {
+    store: Store,
+    history: Arc<Mutex<History>>,
+    sender: broadcast::Sender<WatchEvent>,
+    latest: Arc<watch::Sender<u64>>,
+}
+
+impl WatchCache {
+    pub fn start(store: &Store) -> Result<Self, Error> {
+        // subscribe first so that nothing is missed between the two
+        let mut watcher = store.watch_entities("/");
+
+        let mut objects = HashMap::new();
+        for object in store.snapshot()? {
+            objects.insert(object_key(&object)?, object);
+        }
+        let latest = objects.values().map(object_version).max().unwrap_or(0);
+
+        let (sender, _) = broadcast::channel(HISTORY_SIZE);
+        let cache = WatchCache {
+            store: store.clone(),
+            history: Arc::new(Mutex::new(History {
+                events: VecDeque::new(),
+                objects,
+                oldest: latest,
+                latest,
+            })),
+            sender,
+            latest: Arc::new(watch::channel(latest).0),
+        };
+
+        let worker = cache.clone();
+        tokio::spawn(async move {
+            let mut bookmarks = tokio::time::interval(BOOKMARK_PERIOD);
+            loop {
+                tokio::select! {
+                    event = watcher.next() => match event {
+                        Some(Ok(event)) => worker.record(event),
+                        Some(Err(e)) => {
+                            warn!("resyncing the watch cache: {}", e);
+                            if let Err(e) = worker.resync() {
+                                warn!("failed to resync the watch cache: {}", e);
+                            }
+                        }
+                        None => break,
+                    },
+                    _ = bookmarks.tick() => worker.bookmark(),
+                }
+            }
+            debug!("the store watch has ended");
+        });
+
+        Ok(cache)
+    }
+
+    fn record(&self, event: Event) {
+        let mut history = self.history.lock().unwrap();
+
+        let (event_type, key, object) = match event {
+            Event::Insert { key, value } => {
+                let object: Value = match serde_json::from_slice(&value) {
+                    Ok(object) => object,
+                    Err(e) => {
+                        warn!("skipping the undecodable {}: {}", key, e);
+                        return;
+                    }
+                };
+                let event_type = match history.objects.insert(key.clone(), object.clone()) {
+                    None => EventType::Added,
+                    // already seen in the initial snapshot
+                    Some(previous) if object_version(&previous) == object_version(&object) => {
+                        return
+                    }
+                    Some(_) => EventType::Modified,
+                };
+                (event_type, key, object)
+            }
+            Event::Remove { key } => match history.objects.remove(&key) {
+                Some(object) => (EventType::Deleted, key, object),
+                None => return,
+            },
+        };
+
+        // only an entity written before versioning or deleted again since
+        // could be behind the latest version
+        let version = match event_type {
+            EventType::Deleted => match self.store.deletion_version(&key) {
+                Ok(version) => version.unwrap_or_default(),
+                Err(e) => {
+                    warn!("failed to get the deletion version of {}: {}", key, e);
+                    0
+                }
+            },
+            _ => object_version(&object),
+        };
+        let resource_version = version.max(history.latest + 1);
+        history.latest = resource_version;
+        self.latest.send_replace(resource_version);
+
+        let event = WatchEvent {
+            event_type,
+            key,
+            resource_version,
+            object,
+        };
+        history.events.push_back(event.clone());
+        if history.events.len() > HISTORY_SIZE {
+            if let Some(evicted) = history.events.pop_front() {
+                history.oldest = evicted.resource_version;
+            }
+        }
+
+        // there might be no watchers at all
+        let _ = self.sender.send(event);
+    }
+
+    /// Catches up with the store after missing some of its changes, the
+    /// differences are recorded as events.
+    fn resync(&self) -> Result<(), Error> {
+        let mut objects = self.store.snapshot()?;
+        objects.sort_by_key(object_version);
+        let mut keys = HashSet::new();
+        for object in &objects {
+            keys.insert(object_key(object)?);
+        }
+
+        let gone: Vec<String> = {
+            let history = self.history.lock().unwrap();
+            history
+                .objects
+                .keys()
+                .filter(|key| !keys.contains(*key))
+                .cloned()
+                .collect()
+        };
+        for key in gone {
+            self.record(Event::Remove { key });
+        }
+        for object in objects {
+            let key = object_key(&object)?;
+            let value = serde_json::to_vec(&object)?;
+            self.record(Event::Insert { key, value });
+        }
+
+        Ok(())
+    }
+
+    fn bookmark(&self) {
+        let history = self.history.lock().unwrap();
+        let _ = self.sender.send(WatchEvent::bookmark(history.latest));
+    }
+
+    /// Waits for the cache to record the version, for a little while.
+    async fn catch_up(&self, version: u64) {
+        let mut latest = self.latest.subscribe();
+        let caught_up = async {
+            while *latest.borrow_and_update() < version {
+                if latest.changed().await.is_err() {
+                    break;
+                }
+            }
+        };
+        let _ = tokio::time::timeout(CATCH_UP_TIMEOUT, caught_up).await;
+    }
+
+    /// Returns the events to replay for the new watch, the version they bring
+    /// the watcher to, and the receiver for the events that follow. Without
+    /// a version, every current object is replayed as added.
+    fn subscribe(
+        &self,
+        prefix: &str,
+        since: Option<u64>,
+    ) -> Result<(Vec<WatchEvent>, u64, broadcast::Receiver<WatchEvent>), ApiError> {
+        let history = self.history.lock().unwrap();
+        let receiver = self.sender.subscribe();
+
+        let replay = match since {
+            None | Some(0) => {
+                let mut events: Vec<_> = history
+                    .objects
+                    .iter()
+                    .filter(|(key, _)| key.starts_with(prefix))
+                    .map(|(key, object)| WatchEvent {
+                        event_type: EventType::Added,
+                        key: key.clone(),
+                        resource_version: object_version(object),
+                        object: object.clone(),
+                    })
+                    .collect();
+                events.sort_by_key(|e| e.resource_version);
+                events
+            }
+            Some(version) if version < history.oldest || version > history.latest => {
+                return Err(ApiError::Expired(version))
+            }
+            Some(version) => history
+                .events
+                .iter()
+                .filter(|e| e.resource_version > version && e.key.starts_with(prefix))
+                .cloned()
+                .collect(),
+        };
+
+        Ok((replay, history.latest, receiver))
+    }
+}
+
+/// Renders the event as a json line, skipping the objects not matching the
+/// selector.
+fn encode<E>(event: &WatchEvent, selector: &LabelSelector) -> Option<web::Bytes>
+where
+    E: Entity,
+    E::Type: Serialize,
+{
+    let object = match event.event_type {
+        EventType::Bookmark => json!({
+            "apiVersion": E::API_VERSION,
+            "kind": E::KIND,
+            "metadata": { "resourceVersion": event.resource_version },
+        }),
+        _ => {
+            let metadata: Metadata = event
+                .object
+                .get("metadata")
+                .and_then(|m| serde_json::from_value(m.clone()).ok())?;
+            if !selector.matches(&metadata.labels) {
+                return None;
+            }
+
+            let object = E::migrate_version(event.object.clone())
+                .and_then(|o| Ok(serde_json::from_value::<E::Type>(o)?))
+                .and_then(|o| Ok(serde_json::to_value(o)?));
+            match object {
+                Ok(object) => object,
+                Err(e) => {
+                    warn!("failed to migrate {} for a watch: {}", event.key, e);
+                    return None;
+                }
+            }
+        }
+    };
+
+    let mut line = serde_json::to_vec(&WatchLine {
+        event_type: event.event_type,
+        resource_version: event.resource_version,
+        object,
+    })
+    .ok()?;
+    line.push(b'\n');
+
+    Some(line.into())
+}
+
+/// Streams the changes of the entities of one kind as newline delimited json,
+/// resuming after the given version. The stream ends if the watcher falls
+/// too far behind, and should be resumed from the last seen version.
+pub async fn watch<E>(
+    cache: &WatchCache,
+    selector: LabelSelector,
+    since: Option<u64>,
+) -> Result<HttpResponse, ApiError>
+where
+    E: Entity + 'static,
+    E::Type: Serialize,
+{
+    let prefix = format!("/{}/", E::KIND);
+    if let Some(version) = since {
+        cache.catch_up(version).await;
+    }
+    let (replay, latest, receiver) = cache.subscribe(&prefix, since)?;
+
+    let live = stream::unfold(receiver, |mut receiver| async move {
+        match receiver.recv().await {
+            Ok(event) => Some((event, receiver)),
+            Err(_) => None,
+        }
+    })
+    .filter(move |e| {
+        futures::future::ready(e.event_type == EventType::Bookmark || e.key.starts_with(&prefix))
+    });
+
+    let events = stream::iter(replay)
+        .chain(stream::once(async move { WatchEvent::bookmark(latest) }))
+        .chain(live)
+        .filter_map(move |e| futures::future::ready(encode::<E>(&e, &selector)))
+        .map(Ok::<_, actix_web::Error>);
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(events))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::{EventType, WatchCache};
+    use crate::{database::store::Store, fixtures::test_bridge};
+
+    fn bridge(name: &str) -> Value {
+        json!(test_bridge(name, json!({})))
+    }
+
+    fn version(object: &Value) -> u64 {
+        object["metadata"]["resourceVersion"].as_u64().unwrap()
+    }
+
+    #[tokio::test]
+    async fn resumes_from_a_version_it_has_yet_to_see() {
+        let store = Store::in_memory();
+        let cache = WatchCache::start(&store).unwrap();
+
+        let created = store.create_entity(bridge("br0")).unwrap();
+        cache.catch_up(version(&created)).await;
+        let (replay, latest, _) = cache
+            .subscribe("/Bridge/", Some(version(&created)))
+            .unwrap();
+        assert!(replay.is_empty());
+        assert_eq!(latest, version(&created));
+    }
+
+    #[tokio::test]
+    async fn gives_the_deletions_their_store_version() {
+        let store = Store::in_memory();
+        let cache = WatchCache::start(&store).unwrap();
+
+        let created = store.create_entity(bridge("br0")).unwrap();
+        store.delete_entity("Bridge", "br0").unwrap();
+        let deleted = store.deletion_version("/Bridge/br0").unwrap().unwrap();
+        assert!(deleted > version(&created));
+
+        cache.catch_up(deleted).await;
+        let (replay, latest, _) = cache
+            .subscribe("/Bridge/", Some(version(&created)))
+            .unwrap();
+        assert_eq!(replay.len(), 1);
+        assert_eq!(replay[0].event_type, EventType::Deleted);
+        assert_eq!(replay[0].resource_version, deleted);
+        assert_eq!(latest, deleted);
+    }
+
+    #[tokio::test]
+    async fn resyncs_after_falling_behind() {
+        let store = Store::in_memory();
+        let cache = WatchCache::start(&store).unwrap();
+
+        // the cache doesn't run until the test yields
+        store.create_entity(bridge("gone")).unwrap();
+        store.delete_entity("Bridge", "gone").unwrap();
+        let mut last = 0;
+        for i in 0..2000 {
+            last = version(&store.create_entity(bridge(&format!("br{i}"))).unwrap());
+        }
+
+        cache.catch_up(last).await;
+        let (replay, latest, _) = cache.subscribe("/Bridge/", None).unwrap();
+        assert_eq!(replay.len(), 2000);
+        assert_eq!(latest, last);
+    }
+}
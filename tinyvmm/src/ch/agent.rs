@@ -0,0 +1,171 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use tinyvmm_agent::{
+    Exec, ExecResult, FileContent, Interface, ReadFile, Request, Response, WriteFile,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::UnixStream,
+    time::timeout,
+};
+
+use super::error::Error;
+use crate::systemd::{get_runtime_dir, get_systemd_unit_name};
+
+/// The cid of every guest, each vm has its own vsock device.
+pub const GUEST_CID: u64 = 3;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where cloud-hypervisor exposes the vsock device of the vm on the host.
+pub fn vsock_socket_path(name: &str) -> PathBuf {
+    get_runtime_dir(&get_systemd_unit_name(name)).join("vsock.sock")
+}
+
+enum Transport {
+    /// The host side of a cloud-hypervisor vsock device, connecting to a
+    /// guest port takes a `CONNECT` handshake first.
+    Vsock { socket: PathBuf, port: u32 },
+    /// An agent listening on a unix socket, for testing.
+    Unix(PathBuf),
+}
+
+/// A client of the agent running in a guest.
+pub struct Agent {
+    transport: Transport,
+    timeout: Duration,
+}
+
+impl Agent {
+    pub fn for_vm(name: &str) -> Self {
+        Self::vsock(&vsock_socket_path(name), tinyvmm_agent::PORT)
+    }
+
+    pub fn vsock(socket: &Path, port: u32) -> Self {
+        Self {
+            transport: Transport::Vsock {
+                socket: socket.to_path_buf(),
+                port,
+            },
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    pub fn unix(path: &Path) -> Self {
+        Self {
+            transport: Transport::Unix(path.to_path_buf()),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Bounds the whole call, connecting included.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    async fn connect(&self) -> Result<BufReader<UnixStream>, Error> {
+        match &self.transport {
+            Transport::Unix(path) => Ok(BufReader::new(UnixStream::connect(path).await?)),
+            Transport::Vsock { socket, port } => {
+                let mut stream = BufReader::new(UnixStream::connect(socket).await?);
+                stream
+                    .get_mut()
+                    .write_all(format!("CONNECT {port}\n").as_bytes())
+                    .await?;
+
+                let mut line = String::new();
+                stream.read_line(&mut line).await?;
+                if !line.starts_with("OK ") {
+                    return Err(Error::Agent(format!(
+                        "the guest refused the connection to port {port}: `{}`",
+                        line.trim_end()
+                    )));
+                }
+
+                Ok(stream)
+            }
+        }
+    }
+
+    async fn exchange(&self, request: &Request) -> Result<Response, Error> {
+        let mut stream = self.connect().await?;
+
+        let mut line = serde_json::to_vec(request)?;
+        line.push(b'\n');
+        stream.get_mut().write_all(&line).await?;
+
+        let mut line = String::new();
+        if stream.read_line(&mut line).await? == 0 {
+            return Err(Error::Agent("the agent closed the connection".into()));
+        }
+
+        Ok(serde_json::from_str(&line)?)
+    }
+
+    pub async fn call(&self, request: &Request) -> Result<Response, Error> {
+        let response = timeout(self.timeout, self.exchange(request))
+            .await
+            .map_err(|_| {
+                Error::IO(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "the agent didn't answer in time",
+                ))
+            })??;
+
+        match response {
+            Response::Error { message } => Err(Error::Agent(message)),
+            response => Ok(response),
+        }
+    }
+
+    pub async fn ping(&self) -> Result<String, Error> {
+        match self.call(&Request::Ping).await? {
+            Response::Pong { version } => Ok(version),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn interfaces(&self) -> Result<Vec<Interface>, Error> {
+        match self.call(&Request::Interfaces).await? {
+            Response::Interfaces { interfaces } => Ok(interfaces),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn shutdown(&self) -> Result<(), Error> {
+        match self.call(&Request::Shutdown).await? {
+            Response::ShuttingDown => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn exec(&self, exec: Exec) -> Result<ExecResult, Error> {
+        match self.call(&Request::Exec(exec)).await? {
+            Response::Exited(result) => Ok(result),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn read_file(&self, read: ReadFile) -> Result<FileContent, Error> {
+        match self.call(&Request::ReadFile(read)).await? {
+            Response::File(content) => Ok(content),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn write_file(&self, write: WriteFile) -> Result<(), Error> {
+        match self.call(&Request::WriteFile(write)).await? {
+            Response::Written => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+}
+
+fn unexpected(response: Response) -> Error {
+    Error::Agent(format!("unexpected response: {response:?}"))
+}
@@ -0,0 +1,239 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use hyper::{body::Bytes, client::Client as HyperClient, Body, Method, Request};
+use hyperlocal::{UnixClientExt, UnixConnector, Uri};
+use serde::{Deserialize, Serialize};
+use vmm::vm_config::{DiskConfig, VmConfig};
+
+use super::error::Error;
+use crate::systemd::{get_runtime_dir, get_systemd_unit_name};
+
+pub fn api_socket_path(name: &str) -> PathBuf {
+    get_runtime_dir(&get_systemd_unit_name(name)).join("api.sock")
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum VmState {
+    Created,
+    Running,
+    Shutdown,
+    Paused,
+    BreakPoint,
+}
+
+/// The answer to vm.info.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmInfo {
+    pub config: VmConfig,
+    pub state: VmState,
+    #[serde(default)]
+    pub memory_actual_size: u64,
+}
+
+/// The counters of every device, by device id and counter name.
+pub type VmCounters = BTreeMap<String, BTreeMap<String, u64>>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct VmResize {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub desired_vcpus: Option<u8>,
+    /// In bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub desired_ram: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub desired_balloon: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmRemoveDevice {
+    pub id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmSnapshot {
+    /// A `file://` url of the directory to write the snapshot to.
+    pub destination_url: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmRestore {
+    /// A `file://` url of the snapshot directory.
+    pub source_url: String,
+    #[serde(default)]
+    pub prefault: bool,
+}
+
+/// Where a hotplugged device ended up.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PciDeviceInfo {
+    pub id: String,
+    pub bdf: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmmPing {
+    pub version: String,
+}
+
+/// The calls tinyvmm makes to the api of a single cloud-hypervisor process.
+#[async_trait]
+pub trait VmmApi: Send + Sync {
+    async fn create(&self, config: &VmConfig) -> Result<(), Error>;
+    async fn boot(&self) -> Result<(), Error>;
+    async fn shutdown(&self) -> Result<(), Error>;
+    async fn power_button(&self) -> Result<(), Error>;
+    async fn reboot(&self) -> Result<(), Error>;
+    async fn pause(&self) -> Result<(), Error>;
+    async fn resume(&self) -> Result<(), Error>;
+    async fn info(&self) -> Result<VmInfo, Error>;
+    async fn counters(&self) -> Result<VmCounters, Error>;
+    async fn resize(&self, resize: &VmResize) -> Result<(), Error>;
+    async fn add_disk(&self, disk: &DiskConfig) -> Result<PciDeviceInfo, Error>;
+    async fn remove_device(&self, id: &str) -> Result<(), Error>;
+    async fn snapshot(&self, destination_url: &str) -> Result<(), Error>;
+    async fn restore(&self, restore: &VmRestore) -> Result<(), Error>;
+    async fn ping(&self) -> Result<VmmPing, Error>;
+}
+
+/// Talks to cloud-hypervisor over its api socket.
+pub struct Client {
+    socket: PathBuf,
+    client: HyperClient<UnixConnector>,
+}
+
+impl Client {
+    pub fn new(socket: impl Into<PathBuf>) -> Self {
+        Client {
+            socket: socket.into(),
+            client: HyperClient::unix(),
+        }
+    }
+
+    /// The client for the vmm of the vm, at its well known socket.
+    pub fn for_vm(name: &str) -> Self {
+        Self::new(api_socket_path(name))
+    }
+
+    pub fn socket(&self) -> &Path {
+        &self.socket
+    }
+
+    async fn request(&self, method: Method, endpoint: &str, body: Body) -> Result<Bytes, Error> {
+        let url = Uri::new(&self.socket, &format!("/api/v1/{endpoint}"));
+
+        let req = Request::builder()
+            .method(method)
+            .uri(url)
+            .header("host", "localhost")
+            .header("accept", "*/*")
+            .header("content-type", "application/json")
+            .body(body)?;
+
+        let response = self.client.request(req).await?;
+        let (parts, body) = response.into_parts();
+        let bytes = hyper::body::to_bytes(body).await?;
+
+        if !parts.status.is_success() {
+            let rep = (std::str::from_utf8(&bytes)?).to_string();
+            return Err(Error::HttpNoSuccess(parts.status.as_u16(), rep));
+        }
+
+        Ok(bytes)
+    }
+
+    async fn put(&self, endpoint: &str) -> Result<(), Error> {
+        self.request(Method::PUT, endpoint, Body::empty()).await?;
+        Ok(())
+    }
+
+    async fn put_json<T: Serialize + Sync>(
+        &self,
+        endpoint: &str,
+        body: &T,
+    ) -> Result<Bytes, Error> {
+        let body = Body::from(serde_json::to_vec(body)?);
+        self.request(Method::PUT, endpoint, body).await
+    }
+
+    async fn get<T: serde::de::DeserializeOwned>(&self, endpoint: &str) -> Result<T, Error> {
+        let bytes = self.request(Method::GET, endpoint, Body::empty()).await?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+}
+
+#[async_trait]
+impl VmmApi for Client {
+    async fn create(&self, config: &VmConfig) -> Result<(), Error> {
+        self.put_json("vm.create", config).await?;
+        Ok(())
+    }
+
+    async fn boot(&self) -> Result<(), Error> {
+        self.put("vm.boot").await
+    }
+
+    async fn shutdown(&self) -> Result<(), Error> {
+        self.put("vm.shutdown").await
+    }
+
+    async fn power_button(&self) -> Result<(), Error> {
+        self.put("vm.power-button").await
+    }
+
+    async fn reboot(&self) -> Result<(), Error> {
+        self.put("vm.reboot").await
+    }
+
+    async fn pause(&self) -> Result<(), Error> {
+        self.put("vm.pause").await
+    }
+
+    async fn resume(&self) -> Result<(), Error> {
+        self.put("vm.resume").await
+    }
+
+    async fn info(&self) -> Result<VmInfo, Error> {
+        self.get("vm.info").await
+    }
+
+    async fn counters(&self) -> Result<VmCounters, Error> {
+        self.get("vm.counters").await
+    }
+
+    async fn resize(&self, resize: &VmResize) -> Result<(), Error> {
+        self.put_json("vm.resize", resize).await?;
+        Ok(())
+    }
+
+    async fn add_disk(&self, disk: &DiskConfig) -> Result<PciDeviceInfo, Error> {
+        let bytes = self.put_json("vm.add-disk", disk).await?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    async fn remove_device(&self, id: &str) -> Result<(), Error> {
+        let body = VmRemoveDevice { id: id.into() };
+        self.put_json("vm.remove-device", &body).await?;
+        Ok(())
+    }
+
+    async fn snapshot(&self, destination_url: &str) -> Result<(), Error> {
+        let body = VmSnapshot {
+            destination_url: destination_url.into(),
+        };
+        self.put_json("vm.snapshot", &body).await?;
+        Ok(())
+    }
+
+    async fn restore(&self, restore: &VmRestore) -> Result<(), Error> {
+        self.put_json("vm.restore", restore).await?;
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<VmmPing, Error> {
+        self.get("vmm.ping").await
+    }
+}
@@ -1,24 +1,40 @@
-use std::{path::PathBuf, time::Duration};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use backoff::ExponentialBackoffBuilder;
-use byte_unit::Byte;
 use data_encoding::HEXUPPER;
-use hyper::{Body, Client, Method, Request};
-use hyperlocal::{UnixClientExt, Uri};
 use net_util::MacAddr;
 use vmm::vm_config::{
-    ConsoleConfig, ConsoleOutputMode, CpusConfig, DiskConfig, MemoryConfig, NetConfig,
-    PayloadConfig, RngConfig, VmConfig,
+    ConsoleConfig, ConsoleOutputMode, CpusConfig, DiskConfig, FsConfig, HotplugMethod,
+    MemoryConfig, NetConfig, PayloadConfig, RateLimiterConfig, RngConfig, TokenBucketConfig,
+    VmConfig, VsockConfig,
 };
 
-use crate::database::virtual_machine::VirtualMachine;
+use crate::{
+    database::{
+        virtual_machine::{memory_bytes, Boot, Disk, TokenBucket, VirtualMachine},
+        volume::{volume_path, Volume, VolumePhase},
+    },
+    systemd::{get_runtime_dir, get_systemd_unit_name},
+};
 
-use super::{error::Error, get_vm_tap_name};
+use super::{
+    agent::{vsock_socket_path, GUEST_CID},
+    api::VmmApi,
+    cloudinit,
+    error::Error,
+    get_vm_tap_name, virtiofsd_socket_path,
+};
 
-// TODO: make configurable
-const HYPERVISOR_FW: &str = "/var/lib/tinyvmm/hypervisor";
+/// The firmware booted by the vms without a boot section, unless the daemon
+/// is told otherwise.
+pub const DEFAULT_FIRMWARE: &str = "/var/lib/tinyvmm/hypervisor";
 
-fn digest(path: &str) -> String {
+/// The device id of a disk, stable across restarts.
+fn disk_id(path: &str) -> String {
     let mut context = ring::digest::Context::new(&ring::digest::SHA256);
     context.update(path.as_bytes());
     let digest = context.finish();
@@ -26,41 +42,150 @@ fn digest(path: &str) -> String {
     HEXUPPER.encode(digest.as_ref())
 }
 
-pub async fn bootstrap_vm(vm: &VirtualMachine, name: &str) -> Result<(), Error> {
-    let params = VmConfig {
+fn token_bucket(bucket: &TokenBucket) -> TokenBucketConfig {
+    TokenBucketConfig {
+        size: bucket.size,
+        one_time_burst: bucket.one_time_burst,
+        refill_time: bucket.refill_time,
+    }
+}
+
+fn disk_config(disk: &Disk, volumes: &[Volume]) -> Result<DiskConfig, Error> {
+    let defaults = DiskConfig::default();
+    // the id only names the device in cloud-hypervisor
+    let id = disk.id.clone().unwrap_or_else(|| {
+        disk_id(
+            disk.path
+                .as_deref()
+                .or(disk.volume.as_deref())
+                .or(disk.vhost_user_socket.as_deref())
+                .unwrap_or_default(),
+        )
+    });
+
+    let path = match &disk.volume {
+        Some(name) => match volumes.iter().find(|v| &v.metadata.name == name) {
+            Some(volume) if volume.status.phase == VolumePhase::Ready => Some(volume_path(volume)),
+            _ => return Err(Error::VolumeNotReady(name.clone())),
+        },
+        None => disk.path.as_ref().map(PathBuf::from),
+    };
+
+    Ok(DiskConfig {
+        path,
+        readonly: disk.readonly,
+        direct: disk.direct,
+        num_queues: disk.queues.unwrap_or(defaults.num_queues),
+        vhost_user: disk.vhost_user_socket.is_some(),
+        vhost_socket: disk.vhost_user_socket.clone(),
+        rate_limiter_config: disk.rate_limiter.as_ref().map(|limiter| RateLimiterConfig {
+            bandwidth: limiter.bandwidth.as_ref().map(token_bucket),
+            ops: limiter.ops.as_ref().map(token_bucket),
+        }),
+        id: Some(id),
+        ..defaults
+    })
+}
+
+fn payload(boot: Option<&Boot>, default_firmware: &Path) -> PayloadConfig {
+    // cloud-hypervisor loads the firmware like a kernel
+    let firmware = |path: &Path| PayloadConfig {
+        kernel: Some(path.to_path_buf()),
+        ..Default::default()
+    };
+
+    match boot {
+        Some(Boot {
+            kernel: Some(kernel),
+            initramfs,
+            cmdline,
+            ..
+        }) => PayloadConfig {
+            kernel: Some(PathBuf::from(kernel)),
+            initramfs: initramfs.as_ref().map(PathBuf::from),
+            cmdline: cmdline.clone(),
+            ..Default::default()
+        },
+        Some(Boot {
+            firmware: Some(path),
+            ..
+        }) => firmware(Path::new(path)),
+        _ => firmware(default_firmware),
+    }
+}
+
+/// The config the vm is created with, also used to tell which spec changes
+/// can be applied to the running vm. The volumes of its disks must be ready.
+pub fn vm_config(
+    vm: &VirtualMachine,
+    name: &str,
+    default_firmware: &Path,
+    volumes: &[Volume],
+) -> Result<VmConfig, Error> {
+    // TODO: fix the memory parsing in the deserializer so that the number is always correct in here
+    let memory = memory_bytes(&vm.spec.memory)?;
+    let max_memory = match &vm.spec.max_memory {
+        Some(max_memory) => memory_bytes(max_memory)?.max(memory),
+        None => memory,
+    };
+
+    Ok(VmConfig {
         cpus: CpusConfig {
             boot_vcpus: vm.spec.cpus,
-            max_vcpus: vm.spec.cpus,
+            max_vcpus: vm.spec.max_cpus.unwrap_or(vm.spec.cpus).max(vm.spec.cpus),
             ..Default::default()
         },
         memory: MemoryConfig {
-            // TODO: fix the memory parsing in the deserializer so that the number is always correct in here
-            size: Byte::from_str(format!("{}iB", vm.spec.memory))?.get_bytes() as u64,
+            size: memory,
+            // virtio-mem, unlike acpi, can also give the memory back
+            hotplug_method: HotplugMethod::VirtioMem,
+            hotplug_size: Some(max_memory - memory).filter(|size| *size > 0),
+            // virtiofsd maps the guest memory
+            shared: !vm.spec.filesystems.is_empty(),
             ..Default::default()
         },
-        payload: Some(PayloadConfig {
-            kernel: Some(PathBuf::from(HYPERVISOR_FW)),
-            ..Default::default()
-        }),
+        payload: Some(payload(vm.spec.boot.as_ref(), default_firmware)),
         disks: Some(
             vm.spec
                 .disks
                 .iter()
-                .map(|d| DiskConfig {
-                    path: Some(PathBuf::from(d)),
-                    id: Some(digest(d)),
-                    ..Default::default()
+                .map(|disk| disk_config(disk, volumes))
+                .chain(cloudinit::seed_disk(vm, name).map(Ok))
+                .collect::<Result<_, Error>>()?,
+        ),
+        net: Some(
+            vm.spec
+                .interfaces
+                .iter()
+                .enumerate()
+                .map(|(index, interface)| {
+                    Ok(NetConfig {
+                        tap: Some(get_vm_tap_name(name, index)),
+                        mac: MacAddr::parse_str(&interface.mac)?,
+                        mtu: interface.mtu,
+                        ..Default::default()
+                    })
                 })
-                .collect(),
+                .collect::<Result<_, Error>>()?,
         ),
-        net: Some(vec![NetConfig {
-            tap: Some(get_vm_tap_name(name)),
-            mac: MacAddr::parse_str(&vm.spec.mac)?,
-            ..Default::default()
-        }]),
         rng: RngConfig::default(),
         balloon: None,
-        fs: None,
+        fs: Some(
+            vm.spec
+                .filesystems
+                .iter()
+                .enumerate()
+                .map(|(index, filesystem)| FsConfig {
+                    tag: filesystem.tag.clone(),
+                    socket: virtiofsd_socket_path(name, index),
+                    num_queues: 1,
+                    queue_size: 1024,
+                    id: Some(format!("_fs{index}")),
+                    ..Default::default()
+                })
+                .collect::<Vec<_>>(),
+        )
+        .filter(|fs| !fs.is_empty()),
         pmem: None,
         serial: ConsoleConfig {
             file: None,
@@ -68,61 +193,110 @@ pub async fn bootstrap_vm(vm: &VirtualMachine, name: &str) -> Result<(), Error>
             iommu: false,
         },
         console: ConsoleConfig {
-            file: Some(
-                PathBuf::from("/run")
-                    .join(format!("tinyvmi-{}", name))
-                    .join("console"),
-            ),
+            file: Some(get_runtime_dir(&get_systemd_unit_name(name)).join("console")),
             mode: ConsoleOutputMode::File,
             iommu: false,
         },
         devices: None,
         user_devices: None,
         vdpa: None,
-        vsock: None,
+        vsock: Some(VsockConfig {
+            cid: GUEST_CID,
+            socket: vsock_socket_path(name),
+            id: Some("_vsock0".into()),
+            ..Default::default()
+        }),
         iommu: false,
         sgx_epc: None,
         numa: None,
         watchdog: false,
         platform: None, // TODO: uuid & serial
         tpm: None,
+    })
+}
+
+pub async fn bootstrap_vm(
+    api: &dyn VmmApi,
+    vm: &VirtualMachine,
+    name: &str,
+    default_firmware: &Path,
+    volumes: &[Volume],
+) -> Result<(), Error> {
+    let params = vm_config(vm, name, default_firmware, volumes)?;
+    cloudinit::write_seed(vm, name)?;
+
+    // cloud-hypervisor refuses the config until the virtiofsd sockets exist
+    let wait_op = || async {
+        match params.fs.iter().flatten().find(|fs| !fs.socket.exists()) {
+            Some(fs) => Err(backoff::Error::transient(Error::IO(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} doesn't exist yet", fs.socket.display()),
+            )))),
+            None => Ok(()),
+        }
     };
+    let backoff = ExponentialBackoffBuilder::new()
+        .with_max_elapsed_time(Some(Duration::from_secs(30)))
+        .build();
+    backoff::future::retry(backoff, wait_op).await?;
 
     let request_op = || async {
-        let url = Uri::new(
-            PathBuf::from("/run")
-                .join(format!("tinyvmi-{}", name))
-                .join("api.sock"),
-            "/api/v1/vm.create",
-        );
-
-        let req = Request::builder()
-            .method(Method::PUT)
-            .uri(url)
-            .header("host", "localhost")
-            .header("accept", "*/*")
-            .body(Body::from(
-                serde_json::to_string(&params).map_err(Error::Serialize)?,
-            ))
-            .map_err(Error::Http)?;
-
-        let client = Client::unix();
-
-        Ok(client.request(req).await.map_err(Error::Hyper)?)
+        api.create(&params).await.map_err(|e| match e {
+            // the vmm is up but refused the config
+            Error::HttpNoSuccess(..) => backoff::Error::permanent(e),
+            e => backoff::Error::transient(e),
+        })
     };
 
     let backoff = ExponentialBackoffBuilder::new()
         .with_max_elapsed_time(Some(Duration::from_secs(60)))
         .build();
-    let response = backoff::future::retry(backoff, request_op).await?;
+    backoff::future::retry(backoff, request_op).await?;
+
+    Ok(())
+}
 
-    let (parts, body) = response.into_parts();
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
 
-    if !parts.status.is_success() {
-        let bytes = &hyper::body::to_bytes(body).await?;
-        let rep = (std::str::from_utf8(bytes)?).to_string();
-        return Err(Error::HttpNoSuccess(parts.status.as_u16(), rep));
+    use super::*;
+    use crate::{ch::fake::FakeVmm, fixtures::test_vm};
+
+    #[tokio::test]
+    async fn creates_the_vm_once() {
+        let fake = FakeVmm::for_test("bootstrap");
+        let api = fake.client();
+        let vm = test_vm("vm1", json!({}));
+
+        bootstrap_vm(&api, &vm, "vm1", Path::new("/fw"), &[])
+            .await
+            .unwrap();
+        let info = api.info().await.unwrap();
+        assert_eq!(info.state, super::super::api::VmState::Created);
+        assert_eq!(info.config.cpus.boot_vcpus, 1);
+        assert_eq!(info.config.cpus.max_vcpus, 2);
+        assert_eq!(info.memory_actual_size, 512 << 20);
+        let disks = info.config.disks.unwrap_or_default();
+        assert_eq!(disks.len(), 1);
+        assert_eq!(disks[0].path, Some(PathBuf::from("/var/lib/vm1/root.raw")));
+        assert_eq!(disks[0].id, Some(disk_id("/var/lib/vm1/root.raw")));
+
+        // the refusal isn't retried
+        assert!(matches!(
+            bootstrap_vm(&api, &vm, "vm1", Path::new("/fw"), &[]).await,
+            Err(Error::HttpNoSuccess(..))
+        ));
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn waits_for_the_volumes() {
+        let fake = FakeVmm::for_test("bootstrap-volumes");
+        let vm = test_vm("vm1", json!({ "disks": [{ "volume": "data" }] }));
+
+        assert!(matches!(
+            bootstrap_vm(&fake.client(), &vm, "vm1", Path::new("/fw"), &[]).await,
+            Err(Error::VolumeNotReady(name)) if name == "data"
+        ));
+    }
 }
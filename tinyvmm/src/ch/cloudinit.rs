@@ -0,0 +1,172 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use fatfs::{FileSystem, FormatVolumeOptions, FsOptions};
+use serde_yaml::{Mapping, Value};
+use vmm::vm_config::DiskConfig;
+
+use crate::database::virtual_machine::{CloudInit, VirtualMachine};
+
+use super::{error::Error, vm_state_dir};
+
+/// The label cloud-init looks for to find the NoCloud seed.
+const SEED_LABEL: [u8; 11] = *b"cidata     ";
+
+const SEED_ID: &str = "cidata";
+
+fn seed_path(name: &str) -> PathBuf {
+    vm_state_dir(name).join("cidata.img")
+}
+
+/// The read-only seed disk of the vm, if it has a cloud-init section.
+pub fn seed_disk(vm: &VirtualMachine, name: &str) -> Option<DiskConfig> {
+    vm.spec.cloud_init.as_ref().map(|_| DiskConfig {
+        path: Some(seed_path(name)),
+        readonly: true,
+        id: Some(SEED_ID.into()),
+        ..Default::default()
+    })
+}
+
+fn meta_data(cloud_init: &CloudInit, name: &str) -> Result<String, Error> {
+    let mut meta_data: Mapping = match &cloud_init.meta_data {
+        Some(meta_data) => serde_yaml::from_str(meta_data)?,
+        None => Mapping::new(),
+    };
+    for key in ["instance-id", "local-hostname"] {
+        if meta_data.get(key).is_none() {
+            meta_data.insert(key.into(), Value::String(name.into()));
+        }
+    }
+
+    Ok(serde_yaml::to_string(&meta_data)?)
+}
+
+/// Writes the seed image of the vm in its state dir, replacing the previous
+/// one. Does nothing for the vms without a cloud-init section.
+pub fn write_seed(vm: &VirtualMachine, name: &str) -> Result<(), Error> {
+    let Some(cloud_init) = &vm.spec.cloud_init else {
+        return Ok(());
+    };
+
+    fs::create_dir_all(vm_state_dir(name))?;
+    write_seed_image(cloud_init, name, &seed_path(name))
+}
+
+fn write_seed_image(cloud_init: &CloudInit, name: &str, path: &Path) -> Result<(), Error> {
+    let mut files = vec![
+        ("meta-data", meta_data(cloud_init, name)?),
+        (
+            "user-data",
+            cloud_init.user_data.clone().unwrap_or_default(),
+        ),
+    ];
+    if let Some(network_config) = &cloud_init.network_config {
+        files.push(("network-config", network_config.clone()));
+    }
+
+    // leaves plenty of room for the filesystem itself
+    let data_size: usize = files.iter().map(|(_, contents)| contents.len()).sum();
+    let image_size = (data_size as u64 / (1 << 20) + 2) << 20;
+
+    let partial = path.with_extension("img.partial");
+    let mut image = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&partial)?;
+    image.set_len(image_size)?;
+
+    fatfs::format_volume(
+        &mut image,
+        FormatVolumeOptions::new().volume_label(SEED_LABEL),
+    )?;
+    let filesystem = FileSystem::new(&mut image, FsOptions::new())?;
+    for (file_name, contents) in &files {
+        let mut file = filesystem.root_dir().create_file(file_name)?;
+        file.truncate()?;
+        file.write_all(contents.as_bytes())?;
+    }
+    filesystem.unmount()?;
+    image.sync_all()?;
+
+    fs::rename(partial, path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use crate::fixtures::scratch_dir;
+
+    fn read_seed(path: &Path) -> (String, Vec<(String, String)>) {
+        let image = fs::File::open(path).unwrap();
+        let filesystem = FileSystem::new(image, FsOptions::new()).unwrap();
+        let mut files = vec![];
+        for entry in filesystem.root_dir().iter() {
+            let entry = entry.unwrap();
+            let mut contents = String::new();
+            entry.to_file().read_to_string(&mut contents).unwrap();
+            files.push((entry.file_name(), contents));
+        }
+        files.sort();
+
+        (filesystem.volume_label(), files)
+    }
+
+    #[test]
+    fn writes_the_nocloud_seed() {
+        let path = scratch_dir("seed").join("cidata.img");
+
+        let cloud_init = CloudInit {
+            user_data: Some("#cloud-config\npackages: [htop]\n".into()),
+            meta_data: Some("local-hostname: web\n".into()),
+            network_config: Some("version: 2\n".into()),
+        };
+        write_seed_image(&cloud_init, "vm1", &path).unwrap();
+
+        let (label, files) = read_seed(&path);
+        assert_eq!(label, "cidata");
+        assert_eq!(
+            files,
+            [
+                (
+                    "meta-data".into(),
+                    "local-hostname: web\ninstance-id: vm1\n".into()
+                ),
+                ("network-config".into(), "version: 2\n".into()),
+                (
+                    "user-data".into(),
+                    "#cloud-config\npackages: [htop]\n".into()
+                ),
+            ]
+        );
+        assert!(!path.with_extension("img.partial").exists());
+
+        // the defaults only fill in what's missing
+        let cloud_init = CloudInit {
+            user_data: None,
+            meta_data: None,
+            network_config: None,
+        };
+        write_seed_image(&cloud_init, "vm1", &path).unwrap();
+        let (_, files) = read_seed(&path);
+        assert_eq!(
+            files,
+            [
+                (
+                    "meta-data".into(),
+                    "instance-id: vm1\nlocal-hostname: vm1\n".into()
+                ),
+                ("user-data".into(), String::new()),
+            ]
+        );
+    }
+}
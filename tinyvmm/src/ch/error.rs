@@ -14,6 +14,9 @@ pub enum Error {
     #[error("serialize error")]
     Serialize(#[from] serde_json::Error),
 
+    #[error("yaml error")]
+    Yaml(#[from] serde_yaml::Error),
+
     #[error("http error")]
     Http(#[from] hyper::http::Error),
 
@@ -23,6 +26,15 @@ pub enum Error {
     #[error("http request failed: {0}: `{1}`")]
     HttpNoSuccess(u16, String),
 
+    #[error("volume `{0}` isn't ready")]
+    VolumeNotReady(String),
+
+    #[error("snapshot error: {0}")]
+    Snapshot(String),
+
+    #[error("agent error: {0}")]
+    Agent(String),
+
     #[error("utf8 error")]
     UTF8(#[from] std::str::Utf8Error),
 }
@@ -0,0 +1,277 @@
+use std::{
+    convert::Infallible,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use hyper::{server::conn::Http, service::service_fn, Body, Method, Request, Response, StatusCode};
+use log::{debug, warn};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use tokio::{net::UnixListener, task::JoinHandle};
+use vmm::vm_config::VmConfig;
+
+use super::{
+    api::{
+        Client, PciDeviceInfo, VmInfo, VmRemoveDevice, VmResize, VmRestore, VmSnapshot, VmState,
+    },
+    error::Error,
+};
+
+const FAKE_VERSION: &str = "fake-28.1";
+
+type Reply = Result<Option<Value>, (StatusCode, String)>;
+
+fn fail<T>(message: impl Into<String>) -> Result<T, (StatusCode, String)> {
+    Err((StatusCode::INTERNAL_SERVER_ERROR, message.into()))
+}
+
+fn parse<T: DeserializeOwned>(body: &[u8]) -> Result<T, (StatusCode, String)> {
+    serde_json::from_slice(body).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+fn snapshot_dir(url: &str) -> Result<PathBuf, (StatusCode, String)> {
+    match url.strip_prefix("file://") {
+        Some(path) => Ok(PathBuf::from(path)),
+        None => fail(format!("unsupported snapshot url `{url}`")),
+    }
+}
+
+#[derive(Default)]
+struct FakeState {
+    vm: Option<VmInfo>,
+    next_device: u32,
+}
+
+impl FakeState {
+    fn vm(&mut self) -> Result<&mut VmInfo, (StatusCode, String)> {
+        self.vm.as_mut().ok_or_else(|| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "VM is not created".to_string(),
+            )
+        })
+    }
+
+    /// Moves the vm from one of the given states to the next one.
+    fn transition(&mut self, from: &[VmState], to: VmState) -> Reply {
+        let vm = self.vm()?;
+        if !from.contains(&vm.state) {
+            return fail(format!(
+                "invalid transition from {:?} to {:?}",
+                vm.state, to
+            ));
+        }
+        vm.state = to;
+        Ok(None)
+    }
+
+    fn call(&mut self, method: &Method, endpoint: &str, body: &[u8]) -> Reply {
+        use VmState::*;
+
+        match (method, endpoint) {
+            (&Method::GET, "vmm.ping") => Ok(Some(json!({ "version": FAKE_VERSION }))),
+            (&Method::PUT, "vm.create") => {
+                if self.vm.is_some() {
+                    return fail("VM is already created");
+                }
+                let config: VmConfig = parse(body)?;
+                self.vm = Some(VmInfo {
+                    memory_actual_size: config.memory.size,
+                    config,
+                    state: Created,
+                });
+                Ok(None)
+            }
+            (&Method::PUT, "vm.boot") => self.transition(&[Created, Shutdown], Running),
+            (&Method::PUT, "vm.shutdown") => self.transition(&[Running, Paused], Shutdown),
+            (&Method::PUT, "vm.power-button") => self.transition(&[Running], Shutdown),
+            (&Method::PUT, "vm.reboot") => self.transition(&[Running], Running),
+            (&Method::PUT, "vm.pause") => self.transition(&[Running], Paused),
+            (&Method::PUT, "vm.resume") => self.transition(&[Paused], Running),
+            (&Method::GET, "vm.info") => Ok(Some(json!(self.vm()?))),
+            (&Method::GET, "vm.counters") => {
+                let vm = self.vm()?;
+                let counters: serde_json::Map<String, Value> = vm
+                    .config
+                    .disks
+                    .iter()
+                    .flatten()
+                    .filter_map(|d| d.id.clone())
+                    .map(|id| (id, json!({ "read_bytes": 0, "write_bytes": 0 })))
+                    .collect();
+                Ok(Some(Value::Object(counters)))
+            }
+            (&Method::PUT, "vm.resize") => {
+                let resize: VmResize = parse(body)?;
+                let vm = self.vm()?;
+                if let Some(vcpus) = resize.desired_vcpus {
+                    if vcpus > vm.config.cpus.max_vcpus {
+                        return fail(format!(
+                            "{vcpus} vcpus is over the maximum of {}",
+                            vm.config.cpus.max_vcpus
+                        ));
+                    }
+                    vm.config.cpus.boot_vcpus = vcpus;
+                }
+                if let Some(ram) = resize.desired_ram {
+                    let max = vm.config.memory.size + vm.config.memory.hotplug_size.unwrap_or(0);
+                    if ram < vm.config.memory.size || ram > max {
+                        return fail(format!("cannot resize the memory to {ram} bytes"));
+                    }
+                    vm.memory_actual_size = ram;
+                }
+                Ok(None)
+            }
+            (&Method::PUT, "vm.add-disk") => {
+                let mut disk: vmm::vm_config::DiskConfig = parse(body)?;
+                self.next_device += 1;
+                let slot = self.next_device;
+                let vm = self.vm()?;
+                let disks = vm.config.disks.get_or_insert_with(Vec::new);
+
+                let id = disk.id.clone().unwrap_or_else(|| format!("_disk{slot}"));
+                if disks.iter().any(|d| d.id.as_ref() == Some(&id)) {
+                    return fail(format!("device `{id}` already exists"));
+                }
+                disk.id = Some(id.clone());
+                disks.push(disk);
+
+                Ok(Some(json!(PciDeviceInfo {
+                    id,
+                    bdf: format!("0000:00:{:02x}.0", 0x10 + slot),
+                })))
+            }
+            (&Method::PUT, "vm.remove-device") => {
+                let device: VmRemoveDevice = parse(body)?;
+                let vm = self.vm()?;
+                let disks = vm.config.disks.get_or_insert_with(Vec::new);
+                let count = disks.len();
+                disks.retain(|d| d.id.as_ref() != Some(&device.id));
+                if disks.len() == count {
+                    return fail(format!("device `{}` not found", device.id));
+                }
+                Ok(None)
+            }
+            (&Method::PUT, "vm.snapshot") => {
+                let snapshot: VmSnapshot = parse(body)?;
+                let dir = snapshot_dir(&snapshot.destination_url)?;
+                let vm = self.vm()?;
+                if vm.state != Paused {
+                    return fail("VM is not paused");
+                }
+                let config = serde_json::to_vec(&vm.config).unwrap_or_default();
+                fs::write(dir.join("config.json"), config)
+                    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+                Ok(None)
+            }
+            (&Method::PUT, "vm.restore") => {
+                let restore: VmRestore = parse(body)?;
+                if self.vm.is_some() {
+                    return fail("VM is already created");
+                }
+                let dir = snapshot_dir(&restore.source_url)?;
+                let config = fs::read(dir.join("config.json"))
+                    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+                let config: VmConfig = parse(&config)?;
+                self.vm = Some(VmInfo {
+                    memory_actual_size: config.memory.size,
+                    config,
+                    state: Paused,
+                });
+                Ok(None)
+            }
+            _ => Err((
+                StatusCode::NOT_FOUND,
+                format!("unknown endpoint {method} {endpoint}"),
+            )),
+        }
+    }
+}
+
+async fn handle(
+    state: Arc<Mutex<FakeState>>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let (parts, body) = req.into_parts();
+    let body = hyper::body::to_bytes(body).await.unwrap_or_default();
+    let endpoint = parts.uri.path().trim_start_matches("/api/v1/");
+
+    debug!("fake vmm: {} {}", parts.method, endpoint);
+    let reply = state.lock().unwrap().call(&parts.method, endpoint, &body);
+
+    let response = match reply {
+        Ok(None) => Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty()),
+        Ok(Some(value)) => Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "application/json")
+            .body(Body::from(value.to_string())),
+        Err((status, message)) => Response::builder().status(status).body(Body::from(message)),
+    };
+
+    Ok(response.unwrap_or_default())
+}
+
+/// An in-process stand-in for the cloud-hypervisor api on a unix socket, so
+/// that the vm lifecycle can be exercised without kvm. It keeps track of the
+/// vm state and config, but runs nothing.
+pub struct FakeVmm {
+    socket: PathBuf,
+    server: JoinHandle<()>,
+}
+
+impl FakeVmm {
+    pub fn start(socket: impl AsRef<Path>) -> Result<Self, Error> {
+        let socket = socket.as_ref().to_path_buf();
+        let listener = UnixListener::bind(&socket)?;
+        let state = Arc::new(Mutex::new(FakeState::default()));
+
+        let server = tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        warn!("fake vmm: failed to accept: {}", e);
+                        continue;
+                    }
+                };
+                let state = state.clone();
+                tokio::spawn(async move {
+                    let service = service_fn(move |req| handle(state.clone(), req));
+                    if let Err(e) = Http::new()
+                        .http1_only(true)
+                        .serve_connection(stream, service)
+                        .await
+                    {
+                        debug!("fake vmm: connection failed: {}", e);
+                    }
+                });
+            }
+        });
+
+        Ok(FakeVmm { socket, server })
+    }
+
+    /// Starts the fake on a socket of its own in the temp dir.
+    pub fn for_test(test: &str) -> Self {
+        let socket =
+            std::env::temp_dir().join(format!("tinyvmm-{}-{}.sock", test, std::process::id()));
+        let _ = fs::remove_file(&socket);
+
+        Self::start(socket).unwrap()
+    }
+
+    pub fn client(&self) -> Client {
+        Client::new(&self.socket)
+    }
+}
+
+impl Drop for FakeVmm {
+    fn drop(&mut self) {
+        self.server.abort();
+        let _ = fs::remove_file(&self.socket);
+    }
+}
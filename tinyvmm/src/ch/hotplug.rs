@@ -0,0 +1,296 @@
+use std::fmt;
+
+use vmm::vm_config::{DiskConfig, VmConfig};
+
+use super::{
+    api::{VmInfo, VmResize, VmState, VmmApi},
+    error::Error,
+};
+
+/// An api call changing the running vm.
+#[derive(Debug)]
+enum Change {
+    Resize(VmResize),
+    AddDisk(DiskConfig),
+    RemoveDevice(String),
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Resize(resize) => write!(f, "resize to {resize:?}"),
+            Change::AddDisk(disk) => write!(f, "add disk {:?}", disk.path),
+            Change::RemoveDevice(id) => write!(f, "remove device {id}"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Unchanged,
+    /// The number of changes applied to the running vm.
+    Applied(usize),
+    /// The changes need a restart, for the given reason.
+    Restart(String),
+}
+
+fn disk_ids(config: &VmConfig) -> Vec<&str> {
+    config
+        .disks
+        .iter()
+        .flatten()
+        .filter_map(|d| d.id.as_deref())
+        .collect()
+}
+
+/// What can't change on an attached disk.
+fn disk_options(disk: &DiskConfig) -> serde_json::Value {
+    serde_json::json!({
+        "path": disk.path,
+        "readonly": disk.readonly,
+        "direct": disk.direct,
+        "num_queues": disk.num_queues,
+        "vhost_socket": disk.vhost_socket,
+        "rate_limiter": disk.rate_limiter_config,
+    })
+}
+
+/// Works out the calls bringing the running vm to the desired config, or
+/// why it has to be restarted instead. The boot sizes stay as they were,
+/// only the cpus and memory up to the limits set at boot can be changed.
+fn plan(live: &VmInfo, desired: &VmConfig) -> Result<Vec<Change>, String> {
+    let config = &live.config;
+
+    if config.cpus.max_vcpus != desired.cpus.max_vcpus {
+        return Err(format!(
+            "the maximum vcpus changed from {} to {}",
+            config.cpus.max_vcpus, desired.cpus.max_vcpus
+        ));
+    }
+    let max_memory = |c: &VmConfig| c.memory.size + c.memory.hotplug_size.unwrap_or(0);
+    if max_memory(config) != max_memory(desired) {
+        return Err(format!(
+            "the maximum memory changed from {} to {} bytes",
+            max_memory(config),
+            max_memory(desired)
+        ));
+    }
+    if desired.memory.size < config.memory.size {
+        return Err(format!(
+            "the memory can't go below the {} bytes booted with",
+            config.memory.size
+        ));
+    }
+    let macs = |c: &VmConfig| c.net.iter().flatten().map(|n| n.mac).collect::<Vec<_>>();
+    if macs(config) != macs(desired) {
+        return Err("the network interfaces changed".into());
+    }
+
+    for disk in desired.disks.iter().flatten() {
+        let live = config
+            .disks
+            .iter()
+            .flatten()
+            .find(|live| live.id.is_some() && live.id == disk.id);
+        if let Some(live) = live.filter(|live| disk_options(live) != disk_options(disk)) {
+            return Err(format!(
+                "the options of the disk {} changed",
+                live.id.as_deref().unwrap_or_default()
+            ));
+        }
+    }
+
+    let mut changes = vec![];
+
+    let mut resize = VmResize::default();
+    if config.cpus.boot_vcpus != desired.cpus.boot_vcpus {
+        resize.desired_vcpus = Some(desired.cpus.boot_vcpus);
+    }
+    if live.memory_actual_size != desired.memory.size {
+        resize.desired_ram = Some(desired.memory.size);
+    }
+    if resize != VmResize::default() {
+        changes.push(Change::Resize(resize));
+    }
+
+    let live_disks = disk_ids(config);
+    let desired_disks = disk_ids(desired);
+    for id in &live_disks {
+        if !desired_disks.contains(id) {
+            changes.push(Change::RemoveDevice(id.to_string()));
+        }
+    }
+    for disk in desired.disks.iter().flatten() {
+        if !disk
+            .id
+            .as_deref()
+            .is_some_and(|id| live_disks.contains(&id))
+        {
+            changes.push(Change::AddDisk(disk.clone()));
+        }
+    }
+
+    Ok(changes)
+}
+
+/// Brings the running vm to the desired config through the api. When that
+/// isn't possible, or cloud-hypervisor refuses a change, the outcome asks for
+/// a restart. The calls that fail otherwise are errors, the next reconcile
+/// plans again from what was applied.
+pub async fn apply(api: &dyn VmmApi, desired: &VmConfig) -> Result<Outcome, Error> {
+    let live = api.info().await?;
+    if !matches!(live.state, VmState::Running | VmState::Paused) {
+        return Ok(Outcome::Unchanged);
+    }
+
+    let changes = match plan(&live, desired) {
+        Ok(changes) if changes.is_empty() => return Ok(Outcome::Unchanged),
+        Ok(changes) => changes,
+        Err(reason) => return Ok(Outcome::Restart(reason)),
+    };
+
+    for change in &changes {
+        let result = match change {
+            Change::Resize(resize) => api.resize(resize).await,
+            Change::AddDisk(disk) => api.add_disk(disk).await.map(|_| ()),
+            Change::RemoveDevice(id) => api.remove_device(id).await,
+        };
+        match result {
+            Err(e @ Error::HttpNoSuccess(..)) => {
+                return Ok(Outcome::Restart(format!("failed to {change}: {e}")))
+            }
+            result => result?,
+        }
+    }
+
+    Ok(Outcome::Applied(changes.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        ch::{
+            bootstrap::{bootstrap_vm, vm_config},
+            fake::FakeVmm,
+            runtime::start_vm,
+        },
+        fixtures::test_vm,
+    };
+
+    async fn running(fake: &FakeVmm) -> impl VmmApi {
+        let api = fake.client();
+        bootstrap_vm(
+            &api,
+            &test_vm("vm1", json!({})),
+            "vm1",
+            Path::new("/fw"),
+            &[],
+        )
+        .await
+        .unwrap();
+        start_vm(&api).await.unwrap();
+
+        api
+    }
+
+    fn desired(spec: serde_json::Value) -> VmConfig {
+        vm_config(&test_vm("vm1", spec), "vm1", Path::new("/fw"), &[]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn resizes_and_adds_the_disks() {
+        let fake = FakeVmm::for_test("hotplug");
+        let api = running(&fake).await;
+
+        let desired = desired(json!({
+            "cpus": 2,
+            "memory": "1G",
+            "disks": [
+                { "path": "/var/lib/vm1/root.raw" },
+                { "path": "/var/lib/vm1/data.raw" },
+            ],
+        }));
+        assert_eq!(apply(&api, &desired).await.unwrap(), Outcome::Applied(2));
+
+        let info = api.info().await.unwrap();
+        assert_eq!(info.config.cpus.boot_vcpus, 2);
+        assert_eq!(info.memory_actual_size, 1 << 30);
+        assert_eq!(disk_ids(&info.config), disk_ids(&desired));
+        assert_eq!(apply(&api, &desired).await.unwrap(), Outcome::Unchanged);
+    }
+
+    #[tokio::test]
+    async fn removes_the_disks() {
+        let fake = FakeVmm::for_test("hotplug-remove");
+        let api = running(&fake).await;
+
+        let desired = desired(json!({ "disks": [] }));
+        assert_eq!(apply(&api, &desired).await.unwrap(), Outcome::Applied(1));
+        assert!(disk_ids(&api.info().await.unwrap().config).is_empty());
+    }
+
+    #[tokio::test]
+    async fn restarts_for_the_boot_limits() {
+        let fake = FakeVmm::for_test("hotplug-restart");
+        let api = running(&fake).await;
+
+        assert_eq!(
+            apply(&api, &desired(json!({ "maxCpus": 4 })))
+                .await
+                .unwrap(),
+            Outcome::Restart("the maximum vcpus changed from 2 to 4".into())
+        );
+        let readonly = desired(json!({
+            "disks": [{ "path": "/var/lib/vm1/root.raw", "readonly": true }],
+        }));
+        assert_eq!(
+            apply(&api, &readonly).await.unwrap(),
+            Outcome::Restart(format!(
+                "the options of the disk {} changed",
+                disk_ids(&readonly)[0]
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn restarts_for_the_refused_changes() {
+        let fake = FakeVmm::for_test("hotplug-refused");
+        let api = running(&fake).await;
+
+        // past the limits the plan checks, cloud-hypervisor has the last word
+        let mut over = desired(json!({}));
+        over.cpus.boot_vcpus = 3;
+        match apply(&api, &over).await.unwrap() {
+            Outcome::Restart(reason) => assert!(
+                reason.contains("3 vcpus is over the maximum of 2"),
+                "{reason}"
+            ),
+            outcome => panic!("unexpected {outcome:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn leaves_a_stopped_vm_alone() {
+        let fake = FakeVmm::for_test("hotplug-stopped");
+        let api = fake.client();
+        bootstrap_vm(
+            &api,
+            &test_vm("vm1", json!({})),
+            "vm1",
+            Path::new("/fw"),
+            &[],
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            apply(&api, &desired(json!({ "cpus": 2 }))).await.unwrap(),
+            Outcome::Unchanged
+        );
+    }
+}
@@ -1,14 +1,51 @@
+pub mod agent;
+pub mod api;
 pub mod bootstrap;
+pub mod cloudinit;
 pub mod error;
+#[cfg(test)]
+pub mod fake;
+pub mod hotplug;
 pub mod runtime;
+pub mod snapshot;
 
-pub fn get_vm_tap_name(name: &str) -> String {
+use std::path::PathBuf;
+
+use crate::systemd::{get_runtime_dir, get_systemd_fs_unit_name};
+
+/// Where the files tinyvmm keeps for the vms live, one dir per vm.
+const STATE_DIR: &str = "/var/lib/tinyvmm/vms";
+
+pub fn vm_state_dir(name: &str) -> PathBuf {
+    PathBuf::from(STATE_DIR).join(name)
+}
+
+/// Removes the files kept for the vm.
+pub fn remove_vm_state(name: &str) -> std::io::Result<()> {
+    match std::fs::remove_dir_all(vm_state_dir(name)) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Where the virtiofsd of the filesystem listens, in the runtime directory of
+/// its unit.
+pub fn virtiofsd_socket_path(name: &str, filesystem: usize) -> PathBuf {
+    get_runtime_dir(&get_systemd_fs_unit_name(name, filesystem)).join("virtiofsd.sock")
+}
+
+/// The tap of the interface, the first one keeps the name from before the vms
+/// had several interfaces.
+pub fn get_vm_tap_name(name: &str, interface: usize) -> String {
     use data_encoding::HEXLOWER;
     use ring::digest::{Context, SHA256};
 
     const PREFIX: &str = "vmi";
     let mut context = Context::new(&SHA256);
     context.update(name.as_bytes());
+    if interface > 0 {
+        context.update(format!("/{interface}").as_bytes());
+    }
     let digest = context.finish();
     let hash = HEXLOWER.encode(digest.as_ref());
 
@@ -1,68 +1,110 @@
-use std::path::PathBuf;
-
-use hyper::{Body, Client, Method, Request};
-use hyperlocal::{UnixClientExt, Uri};
-
-use super::error::Error;
-
-pub async fn start_vm(name: &str) -> Result<(), Error> {
-    let url = Uri::new(
-        PathBuf::from("/run")
-            .join(format!("tinyvmi-{}", name))
-            .join("api.sock"),
-        "/api/v1/vm.boot",
-    );
-    let client = Client::unix();
-
-    let req = Request::builder()
-        .method(Method::PUT)
-        .uri(url)
-        .header("host", "localhost")
-        .header("accept", "*/*")
-        .body(Body::empty())?;
-
-    let response = client.request(req).await?;
-    let (parts, body) = response.into_parts();
-
-    if !parts.status.is_success() {
-        let bytes = &hyper::body::to_bytes(body).await?;
-        let rep = (std::str::from_utf8(bytes)?).to_string();
-        return Err(Error::HttpNoSuccess(parts.status.as_u16(), rep));
+use super::{
+    api::{VmState, VmmApi},
+    error::Error,
+};
+
+/// Boots the vm, or resumes it when it was restored from a snapshot.
+pub async fn start_vm(api: &dyn VmmApi) -> Result<(), Error> {
+    match api.info().await?.state {
+        VmState::Paused => api.resume().await,
+        _ => api.boot().await,
+    }
+}
+
+/// Presses the power button and waits for the guest to shut down, which
+/// either takes the vmm down with it or leaves the vm in the shutdown state.
+pub async fn shutdown_vm(api: &dyn VmmApi) -> Result<(), Error> {
+    api.power_button().await?;
+
+    for _ in 0..240 {
+        match api.info().await {
+            Ok(info) if info.state != VmState::Shutdown => {}
+            _ => return Ok(()),
+        }
+        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
     }
 
     Ok(())
 }
 
-pub async fn shutdown_vm(name: &str) -> Result<(), Error> {
-    let api_path = PathBuf::from("/run")
-        .join(format!("tinyvmi-{}", name))
-        .join("api.sock");
+pub async fn reboot_vm(api: &dyn VmmApi) -> Result<(), Error> {
+    api.reboot().await
+}
+
+pub async fn pause_vm(api: &dyn VmmApi) -> Result<(), Error> {
+    api.pause().await
+}
+
+pub async fn resume_vm(api: &dyn VmmApi) -> Result<(), Error> {
+    api.resume().await
+}
 
-    let url = Uri::new(&api_path, "/api/v1/vm.power-button");
-    let client = Client::unix();
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
 
-    let req = Request::builder()
-        .method(Method::PUT)
-        .uri(url)
-        .header("host", "localhost")
-        .header("accept", "*/*")
-        .body(Body::empty())?;
+    use serde_json::json;
 
-    let response = client.request(req).await?;
-    let (parts, body) = response.into_parts();
+    use super::*;
+    use crate::{
+        ch::{bootstrap::bootstrap_vm, fake::FakeVmm},
+        fixtures::test_vm,
+    };
 
-    if !parts.status.is_success() {
-        let bytes = &hyper::body::to_bytes(body).await?;
-        let rep = (std::str::from_utf8(bytes)?).to_string();
-        return Err(Error::HttpNoSuccess(parts.status.as_u16(), rep));
+    async fn state(api: &dyn VmmApi) -> VmState {
+        api.info().await.unwrap().state
     }
 
-    for _ in 0..240 {
-        if !api_path.exists() {
-            return Ok(());
-        }
-        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+    #[tokio::test]
+    async fn runs_the_vm_through_its_lifecycle() {
+        let fake = FakeVmm::for_test("runtime");
+        let api = fake.client();
+        bootstrap_vm(
+            &api,
+            &test_vm("vm1", json!({})),
+            "vm1",
+            Path::new("/fw"),
+            &[],
+        )
+        .await
+        .unwrap();
+        assert_eq!(state(&api).await, VmState::Created);
+
+        start_vm(&api).await.unwrap();
+        assert_eq!(state(&api).await, VmState::Running);
+        reboot_vm(&api).await.unwrap();
+        assert_eq!(state(&api).await, VmState::Running);
+
+        pause_vm(&api).await.unwrap();
+        assert_eq!(state(&api).await, VmState::Paused);
+        // a paused vm, like a restored one, is resumed rather than booted
+        start_vm(&api).await.unwrap();
+        assert_eq!(state(&api).await, VmState::Running);
+
+        pause_vm(&api).await.unwrap();
+        resume_vm(&api).await.unwrap();
+        shutdown_vm(&api).await.unwrap();
+        assert_eq!(state(&api).await, VmState::Shutdown);
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn refuses_the_invalid_transitions() {
+        let fake = FakeVmm::for_test("runtime-invalid");
+        let api = fake.client();
+        assert!(start_vm(&api).await.is_err());
+
+        bootstrap_vm(
+            &api,
+            &test_vm("vm1", json!({})),
+            "vm1",
+            Path::new("/fw"),
+            &[],
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            resume_vm(&api).await,
+            Err(Error::HttpNoSuccess(..))
+        ));
+    }
 }
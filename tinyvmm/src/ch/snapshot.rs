@@ -0,0 +1,252 @@
+use std::{
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use backoff::ExponentialBackoffBuilder;
+use log::warn;
+use tokio::process::Command;
+
+use super::{
+    api::{VmState, VmmApi},
+    error::Error,
+    vm_state_dir,
+};
+use crate::database::snapshot::SnapshotDisk;
+
+/// Read by the vm unit, it names the snapshot the next start restores
+/// instead of creating the vm.
+pub fn restore_env_path(name: &str) -> PathBuf {
+    vm_state_dir(name).join("restore.env")
+}
+
+fn file_url(path: &Path) -> String {
+    format!("file://{}", path.display())
+}
+
+/// Copies the file, sharing its blocks when the filesystem can. The copy is
+/// only moved in place once complete.
+async fn copy(source: &Path, destination: &Path) -> Result<(), Error> {
+    let mut partial = OsString::from(destination);
+    partial.push(".partial");
+
+    let output = Command::new("cp")
+        .arg("--reflink=auto")
+        .arg("--sparse=always")
+        .arg(source)
+        .arg(&partial)
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(Error::Snapshot(format!(
+            "failed to copy {}: {}",
+            source.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(fs::rename(partial, destination)?)
+}
+
+async fn write_snapshot(
+    api: &dyn VmmApi,
+    dir: &Path,
+    disks: &[PathBuf],
+) -> Result<Vec<SnapshotDisk>, Error> {
+    api.snapshot(&file_url(&dir.join("vm"))).await?;
+
+    let mut copies = vec![];
+    for (index, path) in disks.iter().enumerate() {
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let copy_path = dir.join("disks").join(format!("{index}-{file_name}"));
+        copy(path, &copy_path).await?;
+        copies.push(SnapshotDisk {
+            path: path.to_string_lossy().into(),
+            copy: copy_path.to_string_lossy().into(),
+        });
+    }
+
+    Ok(copies)
+}
+
+/// Pauses the vm, writes its state and copies of its disks to the dir and
+/// resumes it, a vm that was already paused stays so. The vm is resumed even
+/// if the snapshot failed, and the snapshot error is the one returned.
+pub async fn take_snapshot(api: &dyn VmmApi, dir: &Path) -> Result<Vec<SnapshotDisk>, Error> {
+    let info = api.info().await?;
+    let disks: Vec<PathBuf> = info
+        .config
+        .disks
+        .iter()
+        .flatten()
+        .filter_map(|disk| disk.path.clone())
+        .collect();
+
+    fs::create_dir_all(dir.join("vm"))?;
+    fs::create_dir_all(dir.join("disks"))?;
+
+    let running = info.state == VmState::Running;
+    if running {
+        api.pause().await?;
+    }
+    let result = write_snapshot(api, dir, &disks).await;
+    if running {
+        match (api.resume().await, &result) {
+            (Err(e), Ok(_)) => return Err(e),
+            (Err(e), Err(_)) => warn!("failed to resume the vm after the snapshot: {}", e),
+            (Ok(()), _) => {}
+        }
+    }
+
+    result
+}
+
+/// Makes the next start of the vm unit restore the snapshot.
+pub fn request_restore(name: &str, snapshot: &str, dir: &Path) -> Result<(), Error> {
+    fs::create_dir_all(vm_state_dir(name))?;
+    fs::write(
+        restore_env_path(name),
+        format!(
+            "SNAPSHOT={}\nRESTORE=--restore source_url={}\n",
+            snapshot,
+            file_url(&dir.join("vm"))
+        ),
+    )?;
+
+    Ok(())
+}
+
+/// Makes the next start of the vm unit create the vm again.
+pub fn cancel_restore(name: &str) -> Result<(), Error> {
+    match fs::remove_file(restore_env_path(name)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Puts the disks back as they were in the snapshot, while the vmm is down.
+pub async fn restore_disks(disks: &[SnapshotDisk]) -> Result<(), Error> {
+    for disk in disks {
+        copy(Path::new(&disk.copy), Path::new(&disk.path)).await?;
+    }
+
+    Ok(())
+}
+
+/// Waits for cloud-hypervisor to be done restoring, the next starts of the
+/// unit create the vm again.
+pub async fn wait_restored(api: &dyn VmmApi, name: &str) -> Result<(), Error> {
+    fs::remove_file(restore_env_path(name))?;
+
+    let request_op = || async {
+        api.info()
+            .await
+            .map(|_| ())
+            .map_err(backoff::Error::transient)
+    };
+    let backoff = ExponentialBackoffBuilder::new()
+        .with_max_elapsed_time(Some(Duration::from_secs(60)))
+        .build();
+    backoff::future::retry(backoff, request_op).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        ch::{
+            api::VmRestore, bootstrap::bootstrap_vm, fake::FakeVmm, remove_vm_state,
+            runtime::start_vm,
+        },
+        fixtures::{scratch_dir, test_vm},
+    };
+
+    /// Boots a vm on the fake with its root disk in the dir.
+    async fn running_vm(api: &dyn VmmApi, dir: &Path) -> PathBuf {
+        let disk = dir.join("root.raw");
+        fs::write(&disk, "before").unwrap();
+        let vm = test_vm("vm1", json!({ "disks": [{ "path": disk }] }));
+        bootstrap_vm(api, &vm, "vm1", Path::new("/fw"), &[])
+            .await
+            .unwrap();
+        start_vm(api).await.unwrap();
+
+        disk
+    }
+
+    #[tokio::test]
+    async fn takes_a_snapshot_and_restores_the_disks() {
+        let fake = FakeVmm::for_test("snapshot");
+        let api = fake.client();
+        let dir = scratch_dir("snapshot");
+        let disk = running_vm(&api, &dir).await;
+
+        let disks = take_snapshot(&api, &dir.join("snap1")).await.unwrap();
+        assert_eq!(api.info().await.unwrap().state, VmState::Running);
+        assert!(dir.join("snap1/vm/config.json").exists());
+        assert_eq!(disks.len(), 1);
+        assert_eq!(disks[0].path, disk.to_string_lossy());
+        assert_eq!(fs::read(&disks[0].copy).unwrap(), b"before");
+
+        fs::write(&disk, "after").unwrap();
+        restore_disks(&disks).await.unwrap();
+        assert_eq!(fs::read(&disk).unwrap(), b"before");
+    }
+
+    #[tokio::test]
+    async fn resumes_the_vm_when_the_snapshot_fails() {
+        let fake = FakeVmm::for_test("snapshot-failed");
+        let api = fake.client();
+        let dir = scratch_dir("snapshot-failed");
+        let disk = running_vm(&api, &dir).await;
+        fs::remove_file(disk).unwrap();
+
+        let result = take_snapshot(&api, &dir.join("snap1")).await;
+        assert!(matches!(result, Err(Error::Snapshot(_))), "{result:?}");
+        assert_eq!(api.info().await.unwrap().state, VmState::Running);
+    }
+
+    #[tokio::test]
+    async fn restores_the_snapshot_on_the_next_start() {
+        let name = "tinyvmm-test-restore";
+        let dir = scratch_dir("snapshot-restore");
+        let snapshot_dir = dir.join("snap1");
+        {
+            let fake = FakeVmm::for_test("snapshot-taken");
+            let api = fake.client();
+            running_vm(&api, &dir).await;
+            take_snapshot(&api, &snapshot_dir).await.unwrap();
+        }
+
+        request_restore(name, "snap1", &snapshot_dir).unwrap();
+        let env = fs::read_to_string(restore_env_path(name)).unwrap();
+        let source_url = format!("file://{}", snapshot_dir.join("vm").display());
+        assert_eq!(
+            env,
+            format!("SNAPSHOT=snap1\nRESTORE=--restore source_url={source_url}\n")
+        );
+
+        // the unit starts cloud-hypervisor with the restore arguments
+        let fake = FakeVmm::for_test("snapshot-restored");
+        let api = fake.client();
+        api.restore(&VmRestore {
+            source_url,
+            prefault: false,
+        })
+        .await
+        .unwrap();
+        wait_restored(&api, name).await.unwrap();
+        assert!(!restore_env_path(name).exists());
+        assert_eq!(api.info().await.unwrap().state, VmState::Paused);
+        start_vm(&api).await.unwrap();
+        assert_eq!(api.info().await.unwrap().state, VmState::Running);
+
+        remove_vm_state(name).unwrap();
+    }
+}
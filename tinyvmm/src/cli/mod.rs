@@ -1,8 +1,19 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+};
+
 use crate::{
     self as tvm,
-    database::{entity::Entity, store::Store, virtual_machine::VirtualMachine},
+    database::{
+        backup::{ConflictMode, Format},
+        entity::Entity,
+        store::Store,
+        virtual_machine::VirtualMachine,
+    },
 };
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use log::{debug, LevelFilter};
 use tokio::{
     signal::{self, unix::SignalKind},
@@ -15,6 +26,7 @@ struct Cli {
     #[clap(flatten)]
     verbose: clap_verbosity_flag::Verbosity,
 
+    /// Path to the store database
     #[clap(long, default_value = "/var/lib/tinyvmm/store.db")]
     store: String,
 
@@ -32,6 +44,10 @@ enum Commands {
         #[clap(long)]
         api_server: String,
 
+        /// The firmware booted by the vms without a boot section
+        #[clap(long, default_value = tvm::ch::bootstrap::DEFAULT_FIRMWARE)]
+        default_firmware: PathBuf,
+
         #[command(subcommand)]
         command: SystemdCommands,
     },
@@ -51,6 +67,9 @@ enum Commands {
 
         #[clap(long)]
         api_server: String,
+
+        #[clap(flatten)]
+        options: UnitServerOptions,
     },
     DnsServer {
         #[clap(long)]
@@ -62,14 +81,113 @@ enum Commands {
 
         #[clap(long)]
         listen_dns: String,
+
+        #[clap(flatten)]
+        options: UnitServerOptions,
+    },
+    Store {
+        #[command(subcommand)]
+        command: StoreCommands,
+    },
+    /// Runs a command in the vm through its agent
+    Exec {
+        #[clap(long, default_value = DEFAULT_API_SERVER)]
+        api_server: String,
+
+        name: String,
+
+        #[clap(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+    /// Copies a file from or to the vm through its agent, the vm side is
+    /// written as `name:/path`
+    Cp {
+        #[clap(long, default_value = DEFAULT_API_SERVER)]
+        api_server: String,
+
+        source: String,
+
+        destination: String,
+    },
+}
+
+const DEFAULT_API_SERVER: &str = "/run/tinyvmm/sock";
+
+/// How many times the status of the vm is written again when it changed under
+/// the command, the conflict is returned after that.
+const UPDATE_ATTEMPTS: usize = 5;
+
+#[derive(Debug, Args)]
+struct UnitServerOptions {
+    /// How many vms can be restarting at once to apply their spec changes
+    #[clap(long, default_value_t = 1)]
+    max_concurrent_restarts: usize,
+
+    /// The firmware booted by the vms without a boot section
+    #[clap(long, default_value = tvm::ch::bootstrap::DEFAULT_FIRMWARE)]
+    default_firmware: String,
+
+    /// The virtiofsd serving the shared directories of the vms
+    #[clap(long, default_value = tvm::systemd::DEFAULT_VIRTIOFSD)]
+    virtiofsd: String,
+}
+
+#[derive(Debug, Subcommand)]
+enum StoreCommands {
+    /// Rewrites all the stored entities at their latest api version
+    Migrate {
+        /// Only print the changes without writing them
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Writes all the stored entities out, one document per entity
+    Export {
+        /// json-lines or yaml
+        #[clap(long, default_value = "json-lines")]
+        format: Format,
+
+        /// The output file, stdout if not set
+        #[clap(long)]
+        output: Option<PathBuf>,
+    },
+    /// Reads the entities from an export and creates them in the store
+    Import {
+        /// The input file, stdin if not set
+        input: Option<PathBuf>,
+
+        /// json-lines or yaml
+        #[clap(long, default_value = "json-lines")]
+        format: Format,
+
+        /// What to do with the existing entities: fail, skip or overwrite, the vms
+        /// are never overwritten
+        #[clap(long, default_value = "fail")]
+        on_conflict: ConflictMode,
     },
 }
 
 #[derive(Debug, Subcommand)]
 enum SystemdCommands {
-    BootstrapPre { name: String },
-    BootstrapPost { name: String },
-    Teardown { name: String },
+    BootstrapPre {
+        name: String,
+        /// The position of the interface in the vm spec
+        #[clap(default_value_t = 0)]
+        interface: usize,
+    },
+    BootstrapPost {
+        name: String,
+    },
+    /// Puts the disks of the vm back as they were in the snapshot, nothing
+    /// to do without one
+    RestoreDisks {
+        name: String,
+        snapshot: Option<String>,
+    },
+    Teardown {
+        name: String,
+        #[clap(default_value_t = 0)]
+        interface: usize,
+    },
 }
 
 #[derive(Debug, Subcommand)]
@@ -98,6 +216,10 @@ enum TapCommands {
         mac: String,
         #[clap(long)]
         bridge: String,
+        #[clap(long, default_value_t = 0)]
+        interface: usize,
+        #[clap(long)]
+        mtu: Option<u16>,
     },
     Destroy {
         name: String,
@@ -118,6 +240,70 @@ enum InternalCommands {
         #[command(subcommand)]
         command: NetworkdCommand,
     },
+    /// Calls the cloud-hypervisor api directly
+    Vmm {
+        /// A vm name, or the path of an api socket
+        target: String,
+
+        #[command(subcommand)]
+        command: VmmCommands,
+    },
+    /// Calls the guest agent directly
+    Agent {
+        /// A vm name, or the path of an agent listening on a unix socket
+        target: String,
+
+        #[command(subcommand)]
+        command: AgentCommands,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum VmmCommands {
+    Ping,
+    Info,
+    Counters,
+    Boot,
+    Shutdown,
+    PowerButton,
+    Reboot,
+    Pause,
+    Resume,
+    Resize {
+        #[clap(long)]
+        cpus: Option<u8>,
+        /// The total memory, like 4GiB
+        #[clap(long)]
+        memory: Option<String>,
+    },
+    AddDisk {
+        path: PathBuf,
+        #[clap(long)]
+        id: Option<String>,
+        #[clap(long)]
+        readonly: bool,
+    },
+    RemoveDevice {
+        id: String,
+    },
+    /// Snapshots the paused vm into an existing directory
+    Snapshot {
+        destination: PathBuf,
+    },
+    Restore {
+        source: PathBuf,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum AgentCommands {
+    Ping,
+    Interfaces,
+    Shutdown,
+    Exec {
+        #[clap(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
 }
 
 #[derive(Debug, Subcommand)]
@@ -144,10 +330,13 @@ async fn internal_command(cmd: &InternalCommands, store: Store) -> eyre::Result<
         } => {
             let vms = VirtualMachine::list(&store)?;
             let mut leases = vec![];
-            for vm in vms {
+            for interface in vms.into_iter().flat_map(|vm| vm.spec.interfaces) {
+                let Some(ip) = interface.ip.filter(|_| &interface.bridge == name) else {
+                    continue;
+                };
                 leases.push(tvm::systemd::bridge::Lease {
-                    mac: vm.spec.mac,
-                    ip: vm.spec.ip,
+                    mac: interface.mac,
+                    ip,
                 });
             }
             tvm::systemd::bridge::create_bridge(name).await?;
@@ -167,11 +356,18 @@ async fn internal_command(cmd: &InternalCommands, store: Store) -> eyre::Result<
             tvm::systemd::destroy_netdev(name).await?;
         }
         Tap {
-            command: tap::Create { name, mac, bridge },
+            command:
+                tap::Create {
+                    name,
+                    mac,
+                    bridge,
+                    interface,
+                    mtu,
+                },
         } => {
-            let tap_name = tvm::ch::get_vm_tap_name(name);
+            let tap_name = tvm::ch::get_vm_tap_name(name, *interface);
             tvm::systemd::tap::create_tap(&tap_name, mac).await?;
-            tvm::systemd::tap::create_tap_network(&tap_name, bridge, mac).await?;
+            tvm::systemd::tap::create_tap_network(&tap_name, bridge, mac, *mtu).await?;
         }
         Tap {
             command: tap::Destroy { name },
@@ -179,30 +375,244 @@ async fn internal_command(cmd: &InternalCommands, store: Store) -> eyre::Result<
             tvm::systemd::destroy_netdev(name).await?;
         }
         Networkd { command } => networkd_command(command).await?,
+        Vmm { target, command } => vmm_command(target, command).await?,
+        Agent { target, command } => agent_command(target, command).await?,
     }
     Ok(())
 }
 
-async fn systemd_command(cmd: &SystemdCommands, api_server: &str) -> eyre::Result<()> {
+async fn vmm_command(target: &str, cmd: &VmmCommands) -> eyre::Result<()> {
+    use tvm::ch::api::{Client, VmResize, VmRestore, VmmApi};
+    use VmmCommands::*;
+
+    let api = match target.contains('/') {
+        true => Client::new(target),
+        false => Client::for_vm(target),
+    };
+    debug!("calling the vmm at {}", api.socket().display());
+
+    let file_url = |path: &PathBuf| -> eyre::Result<String> {
+        Ok(format!("file://{}", std::fs::canonicalize(path)?.display()))
+    };
+
+    match cmd {
+        Ping => println!("{}", serde_json::to_string_pretty(&api.ping().await?)?),
+        Info => println!("{}", serde_json::to_string_pretty(&api.info().await?)?),
+        Counters => println!("{}", serde_json::to_string_pretty(&api.counters().await?)?),
+        Boot => api.boot().await?,
+        Shutdown => api.shutdown().await?,
+        PowerButton => api.power_button().await?,
+        Reboot => api.reboot().await?,
+        Pause => api.pause().await?,
+        Resume => api.resume().await?,
+        Resize { cpus, memory } => {
+            let desired_ram = match memory {
+                Some(memory) => Some(byte_unit::Byte::from_str(memory)?.get_bytes() as u64),
+                None => None,
+            };
+            api.resize(&VmResize {
+                desired_vcpus: *cpus,
+                desired_ram,
+                desired_balloon: None,
+            })
+            .await?
+        }
+        AddDisk { path, id, readonly } => {
+            let disk = vmm::vm_config::DiskConfig {
+                path: Some(path.clone()),
+                id: id.clone(),
+                readonly: *readonly,
+                ..Default::default()
+            };
+            let device = api.add_disk(&disk).await?;
+            println!("{}", serde_json::to_string_pretty(&device)?);
+        }
+        RemoveDevice { id } => api.remove_device(id).await?,
+        Snapshot { destination } => api.snapshot(&file_url(destination)?).await?,
+        Restore { source } => {
+            api.restore(&VmRestore {
+                source_url: file_url(source)?,
+                prefault: false,
+            })
+            .await?
+        }
+    }
+    Ok(())
+}
+
+async fn agent_command(target: &str, cmd: &AgentCommands) -> eyre::Result<()> {
+    use tvm::ch::agent::Agent;
+    use AgentCommands::*;
+
+    let agent = match target.contains('/') {
+        true => Agent::unix(Path::new(target)),
+        false => Agent::for_vm(target),
+    };
+
+    match cmd {
+        Ping => println!("{}", agent.ping().await?),
+        Interfaces => println!(
+            "{}",
+            serde_json::to_string_pretty(&agent.interfaces().await?)?
+        ),
+        Shutdown => agent.shutdown().await?,
+        Exec { command } => {
+            let result = agent
+                .exec(tinyvmm_agent::Exec {
+                    command: command.clone(),
+                })
+                .await?;
+            print_exec_result(&result)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes out the outputs of the command and exits with its exit code.
+fn print_exec_result(result: &tinyvmm_agent::ExecResult) -> eyre::Result<()> {
+    use std::io::Write;
+
+    io::stdout().write_all(&tinyvmm_agent::decode(&result.stdout)?)?;
+    io::stderr().write_all(&tinyvmm_agent::decode(&result.stderr)?)?;
+
+    match result.exit_code {
+        Some(0) => Ok(()),
+        Some(code) => std::process::exit(code),
+        None => eyre::bail!("the command was killed by a signal"),
+    }
+}
+
+/// Splits `name:/path` into the vm name and the path in the guest.
+fn guest_path(location: &str) -> Option<(&str, &str)> {
+    location
+        .split_once(':')
+        .filter(|(name, path)| !name.is_empty() && !name.contains('/') && path.starts_with('/'))
+}
+
+async fn exec(api_server: &str, name: &str, command: &[String]) -> eyre::Result<()> {
+    let client = crate::client::Client::new(api_server.into());
+    let result = client
+        .virtualmachines()
+        .exec(
+            name,
+            &tinyvmm_agent::Exec {
+                command: command.to_vec(),
+            },
+        )
+        .await?;
+
+    print_exec_result(&result)
+}
+
+async fn copy(api_server: &str, source: &str, destination: &str) -> eyre::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let client = crate::client::Client::new(api_server.into());
+    let vms = client.virtualmachines();
+
+    match (guest_path(source), guest_path(destination)) {
+        (Some((name, path)), None) => {
+            let file = vms
+                .read_file(name, &tinyvmm_agent::ReadFile { path: path.into() })
+                .await?;
+            std::fs::write(destination, tinyvmm_agent::decode(&file.content)?)?;
+            std::fs::set_permissions(destination, std::fs::Permissions::from_mode(file.mode))?;
+        }
+        (None, Some((name, path))) => {
+            let content = std::fs::read(source)?;
+            let mode = std::fs::metadata(source)?.permissions().mode() & 0o7777;
+            vms.write_file(
+                name,
+                &tinyvmm_agent::WriteFile {
+                    path: path.into(),
+                    content: tinyvmm_agent::encode(&content),
+                    mode: Some(mode),
+                },
+            )
+            .await?;
+        }
+        _ => eyre::bail!("exactly one of the source and the destination must be `name:/path`"),
+    }
+    Ok(())
+}
+
+async fn systemd_command(
+    cmd: &SystemdCommands,
+    api_server: &str,
+    default_firmware: &Path,
+) -> eyre::Result<()> {
     use SystemdCommands::*;
 
     let client = crate::client::Client::new(api_server.into());
 
     match cmd {
-        BootstrapPre { name } => {
-            let tap_name = tvm::ch::get_vm_tap_name(name);
+        BootstrapPre { name, interface } => {
+            let tap_name = tvm::ch::get_vm_tap_name(name, *interface);
 
             let vm = client.virtualmachines().get(name).await?;
+            let Some(spec) = vm.spec.interfaces.get(*interface) else {
+                eyre::bail!("{} has no interface {}", name, interface);
+            };
 
-            tvm::systemd::tap::create_tap(&tap_name, &vm.spec.mac).await?;
-            tvm::systemd::tap::create_tap_network(&tap_name, &vm.spec.bridge, &vm.spec.mac).await?;
+            tvm::systemd::tap::create_tap(&tap_name, &spec.mac).await?;
+            tvm::systemd::tap::create_tap_network(&tap_name, &spec.bridge, &spec.mac, spec.mtu)
+                .await?;
         }
         BootstrapPost { name } => {
             let vm = client.virtualmachines().get(name).await?;
 
-            tvm::ch::bootstrap::bootstrap_vm(&vm, name).await?
+            let api = tvm::ch::api::Client::for_vm(name);
+            if tvm::ch::snapshot::restore_env_path(name).exists() {
+                // cloud-hypervisor was started with the snapshot to restore
+                tvm::ch::snapshot::wait_restored(&api, name).await?;
+            } else {
+                let volumes = client.volumes().list().await?;
+                tvm::ch::bootstrap::bootstrap_vm(&api, &vm, name, default_firmware, &volumes)
+                    .await?;
+            }
+
+            // tells the unit server which spec the vm runs with
+            let hash = tvm::database::virtual_machine::spec_hash(&vm)?;
+            let mut attempts = 0;
+            loop {
+                let mut current = client.virtualmachines().get(name).await?;
+                current.status.spec_hash = Some(hash.clone());
+                match client.virtualmachines().update_status(&current).await {
+                    Err(tvm::client::error::Error::Conflict(_))
+                        if attempts + 1 < UPDATE_ATTEMPTS =>
+                    {
+                        attempts += 1
+                    }
+                    result => break result.map(|_| ())?,
+                }
+            }
+        }
+        RestoreDisks {
+            name,
+            snapshot: Some(snapshot),
+        } => {
+            let restored = async {
+                let snapshot = client.snapshots().get(snapshot).await?;
+                if snapshot.spec.vm != *name {
+                    eyre::bail!(
+                        "{} is a snapshot of {}",
+                        snapshot.metadata.name,
+                        snapshot.spec.vm
+                    );
+                }
+                Ok(tvm::ch::snapshot::restore_disks(&snapshot.status.disks).await?)
+            }
+            .await;
+            if restored.is_err() {
+                // the next start boots the vm afresh instead of failing the same way
+                let _ = tvm::ch::snapshot::cancel_restore(name);
+            }
+            restored?;
+        }
+        RestoreDisks { snapshot: None, .. } => {}
+        Teardown { name, interface } => {
+            tvm::systemd::destroy_netdev(&tvm::ch::get_vm_tap_name(name, *interface)).await?
         }
-        Teardown { name } => tvm::systemd::destroy_netdev(&tvm::ch::get_vm_tap_name(name)).await?,
     }
     Ok(())
 }
@@ -232,13 +642,77 @@ async fn networkd_command(cmd: &NetworkdCommand) -> eyre::Result<()> {
     }
 }
 
+fn store_command(cmd: &StoreCommands, store: Store) -> eyre::Result<()> {
+    use tvm::database::{backup, migration};
+    use StoreCommands::*;
+
+    match cmd {
+        Migrate { dry_run } => {
+            let migrations = migration::plan(&store)?;
+
+            let mut failed = 0;
+            for m in migrations.iter() {
+                match &m.result {
+                    Err(e) => {
+                        failed += 1;
+                        println!("{}/{}: failed: {}", m.kind, m.name, e);
+                    }
+                    Ok(_) if !m.is_changed() => println!("{}/{}: up to date", m.kind, m.name),
+                    Ok(_) => {
+                        println!("{}/{}:", m.kind, m.name);
+                        for change in m.diff.iter() {
+                            println!("  {}", change);
+                        }
+                    }
+                }
+            }
+
+            if failed > 0 {
+                eyre::bail!("{} entities failed to migrate, nothing was written", failed);
+            }
+            if !dry_run {
+                let count = migration::apply(&store, migrations)?;
+                println!("rewrote {} entities", count);
+            }
+
+            Ok(())
+        }
+        Export { format, output } => {
+            let entities = store.snapshot()?;
+            match output {
+                Some(path) => backup::write_entities(&entities, *format, File::create(path)?)?,
+                None => backup::write_entities(&entities, *format, io::stdout().lock())?,
+            }
+
+            Ok(())
+        }
+        Import {
+            input,
+            format,
+            on_conflict,
+        } => {
+            let entities = match input {
+                Some(path) => backup::read_entities(*format, BufReader::new(File::open(path)?))?,
+                None => backup::read_entities(*format, io::stdin().lock())?,
+            };
+            let summary = backup::import(&store, entities, *on_conflict)?;
+            println!(
+                "created {}, overwritten {}, skipped {}",
+                summary.created, summary.overwritten, summary.skipped
+            );
+
+            Ok(())
+        }
+    }
+}
+
 async fn start_vm(name: &str) -> eyre::Result<()> {
-    tvm::ch::runtime::start_vm(name).await?;
+    tvm::ch::runtime::start_vm(&tvm::ch::api::Client::for_vm(name)).await?;
     Ok(())
 }
 
 async fn stop_vm(name: &str) -> eyre::Result<()> {
-    tvm::ch::runtime::shutdown_vm(name).await?;
+    tvm::ch::runtime::shutdown_vm(&tvm::ch::api::Client::for_vm(name)).await?;
     Ok(())
 }
 
@@ -247,7 +721,12 @@ async fn run_apiserver(store: Store, listen: &str) -> eyre::Result<()> {
     Ok(())
 }
 
-async fn run_unitserver(store: Store, dns_listener: &str, api_server: &str) -> eyre::Result<()> {
+async fn run_unitserver(
+    store: Store,
+    dns_listener: &str,
+    api_server: &str,
+    options: &UnitServerOptions,
+) -> eyre::Result<()> {
     let (shutdown_send, shutdown_recv) = mpsc::channel(1);
     let (terminated_send, mut terminated_recv) = mpsc::channel(1);
 
@@ -256,6 +735,9 @@ async fn run_unitserver(store: Store, dns_listener: &str, api_server: &str) -> e
         store,
         dns_listener: dns_listener.into(),
         api_server: api_server.into(),
+        max_concurrent_restarts: options.max_concurrent_restarts,
+        default_firmware: options.default_firmware.clone(),
+        virtiofsd: options.virtiofsd.clone(),
     };
 
     let worker = tvm::unitserver::main(config, terminated_send);
@@ -315,11 +797,16 @@ async fn run_dnsserver(store: Store, listen: &str) -> eyre::Result<()> {
     handle.await.unwrap()
 }
 
-async fn run_all(store: Store, listen: &str, listen_dns: &str) -> eyre::Result<()> {
+async fn run_all(
+    store: Store,
+    listen: &str,
+    listen_dns: &str,
+    options: &UnitServerOptions,
+) -> eyre::Result<()> {
     let dns_listener = listen_dns.split(':').next().unwrap();
     let res = tokio::join!(
         run_apiserver(store.clone(), listen),
-        run_unitserver(store.clone(), dns_listener, listen),
+        run_unitserver(store.clone(), dns_listener, listen, options),
         run_dnsserver(store, listen_dns),
     );
     if res.0.is_err() {
@@ -346,13 +833,24 @@ pub async fn main() -> eyre::Result<()> {
     match &cli.command {
         Commands::Systemd {
             api_server,
+            default_firmware,
             command,
-        } => systemd_command(command, api_server).await,
+        } => systemd_command(command, api_server, default_firmware).await,
         Commands::Start { name } => start_vm(name).await,
         Commands::Stop { name } => stop_vm(name).await,
+        Commands::Exec {
+            api_server,
+            name,
+            command,
+        } => exec(api_server, name, command).await,
+        Commands::Cp {
+            api_server,
+            source,
+            destination,
+        } => copy(api_server, source, destination).await,
 
         cmd => {
-            let store = Store::new(cli.store.clone())?;
+            let store = Store::new(&cli.store)?;
 
             match cmd {
                 Commands::Internal { command } => internal_command(command, store).await,
@@ -361,8 +859,14 @@ pub async fn main() -> eyre::Result<()> {
                 Commands::UnitServer {
                     dns_listener,
                     api_server,
-                } => run_unitserver(store, dns_listener, api_server).await,
-                Commands::Serve { listen, listen_dns } => run_all(store, listen, listen_dns).await,
+                    options,
+                } => run_unitserver(store, dns_listener, api_server, options).await,
+                Commands::Serve {
+                    listen,
+                    listen_dns,
+                    options,
+                } => run_all(store, listen, listen_dns, options).await,
+                Commands::Store { command } => store_command(command, store),
                 _ => todo!(),
             }
         }
@@ -1,5 +1,7 @@
 use thiserror::Error;
 
+use crate::{apiserver::error::Status, database::error::Cause};
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("http error")]
@@ -11,9 +13,49 @@ pub enum Error {
     #[error("http request failed: {0}: `{1}`")]
     HttpNoSuccess(u16, String),
 
+    #[error("bad request: {0}")]
+    BadRequest(String),
+
+    #[error("not found: {0}")]
+    NotFound(String),
+
+    #[error("already exists: {0}")]
+    AlreadyExists(String),
+
+    #[error("conflict: {0}")]
+    Conflict(String),
+
+    #[error("invalid: {message}")]
+    Invalid { message: String, causes: Vec<Cause> },
+
+    #[error("expired: {0}")]
+    Expired(String),
+
+    #[error("api server error: {0}")]
+    Internal(String),
+
     #[error("utf8 error")]
     UTF8(#[from] std::str::Utf8Error),
 
     #[error("serialize error")]
     Serialize(#[from] serde_json::Error),
 }
+
+impl From<Status> for Error {
+    fn from(status: Status) -> Self {
+        use crate::apiserver::error::Reason::*;
+
+        match status.reason {
+            BadRequest => Error::BadRequest(status.message),
+            NotFound => Error::NotFound(status.message),
+            AlreadyExists => Error::AlreadyExists(status.message),
+            Conflict => Error::Conflict(status.message),
+            Invalid => Error::Invalid {
+                message: status.message,
+                causes: status.causes,
+            },
+            Expired => Error::Expired(status.message),
+            InternalError => Error::Internal(status.message),
+        }
+    }
+}
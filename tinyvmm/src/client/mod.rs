@@ -2,12 +2,16 @@ use std::path::PathBuf;
 
 use hyper::{Body, Method, Request};
 use hyperlocal::{UnixClientExt, Uri};
+use tinyvmm_agent::{Exec, ExecResult, FileContent, ReadFile, WriteFile};
 
-use crate::database::virtual_machine::VirtualMachine;
+use crate::{
+    apiserver::error::Status,
+    database::{snapshot::Snapshot, virtual_machine::VirtualMachine, volume::Volume},
+};
 
 use self::error::Error;
 
-mod error;
+pub mod error;
 
 pub struct Client {
     api_server: String,
@@ -23,6 +27,18 @@ impl Client {
             api_server: self.api_server.clone(),
         }
     }
+
+    pub fn volumes(&self) -> VolumeClient {
+        VolumeClient {
+            api_server: self.api_server.clone(),
+        }
+    }
+
+    pub fn snapshots(&self) -> SnapshotClient {
+        SnapshotClient {
+            api_server: self.api_server.clone(),
+        }
+    }
 }
 
 pub struct VirtualMachineClient {
@@ -31,12 +47,17 @@ pub struct VirtualMachineClient {
 
 impl VirtualMachineClient {
     async fn http_get(url: Uri) -> Result<String, Error> {
+        Self::http_request(Method::GET, url, Body::empty()).await
+    }
+
+    async fn http_request(method: Method, url: Uri, body: Body) -> Result<String, Error> {
         let req = Request::builder()
-            .method(Method::GET)
+            .method(method)
             .uri(url)
             .header("host", "localhost")
             .header("accept", "application/json")
-            .body(Body::empty())?;
+            .header("content-type", "application/json")
+            .body(body)?;
 
         let client = hyper::Client::unix();
 
@@ -48,13 +69,16 @@ impl VirtualMachineClient {
         let rep = (std::str::from_utf8(bytes)?).to_string();
 
         if !parts.status.is_success() {
-            return Err(Error::HttpNoSuccess(parts.status.as_u16(), rep));
+            return Err(match serde_json::from_str::<Status>(&rep) {
+                Ok(status) => status.into(),
+                Err(_) => Error::HttpNoSuccess(parts.status.as_u16(), rep),
+            });
         }
 
         Ok(rep)
     }
 
-    pub async fn list(&self) -> Result<VirtualMachine, Error> {
+    pub async fn list(&self) -> Result<Vec<VirtualMachine>, Error> {
         let url = Uri::new(
             PathBuf::from(self.api_server.clone()),
             "/api/v1/virtualmachines",
@@ -71,4 +95,83 @@ impl VirtualMachineClient {
 
         Ok(serde_json::from_str(&Self::http_get(url).await?)?)
     }
+
+    /// Replaces the status of the vm, failing with a conflict if the vm was
+    /// changed since it was read.
+    pub async fn update_status(&self, vm: &VirtualMachine) -> Result<VirtualMachine, Error> {
+        let url = Uri::new(
+            PathBuf::from(self.api_server.clone()),
+            &format!("/api/v1/virtualmachines/{}/status", vm.metadata.name),
+        );
+        let body = Body::from(serde_json::to_vec(vm)?);
+
+        Ok(serde_json::from_str(
+            &Self::http_request(Method::PUT, url, body).await?,
+        )?)
+    }
+
+    async fn agent_request<T: serde::Serialize>(
+        &self,
+        name: &str,
+        request: &str,
+        body: &T,
+    ) -> Result<String, Error> {
+        let url = Uri::new(
+            PathBuf::from(self.api_server.clone()),
+            &format!("/api/v1/virtualmachines/{name}/agent/{request}"),
+        );
+        let body = Body::from(serde_json::to_vec(body)?);
+
+        Self::http_request(Method::POST, url, body).await
+    }
+
+    /// Runs the command in the guest through its agent.
+    pub async fn exec(&self, name: &str, exec: &Exec) -> Result<ExecResult, Error> {
+        Ok(serde_json::from_str(
+            &self.agent_request(name, "exec", exec).await?,
+        )?)
+    }
+
+    pub async fn read_file(&self, name: &str, read: &ReadFile) -> Result<FileContent, Error> {
+        Ok(serde_json::from_str(
+            &self.agent_request(name, "read", read).await?,
+        )?)
+    }
+
+    pub async fn write_file(&self, name: &str, write: &WriteFile) -> Result<(), Error> {
+        self.agent_request(name, "write", write).await?;
+
+        Ok(())
+    }
+}
+
+pub struct VolumeClient {
+    api_server: String,
+}
+
+impl VolumeClient {
+    pub async fn list(&self) -> Result<Vec<Volume>, Error> {
+        let url = Uri::new(PathBuf::from(self.api_server.clone()), "/api/v1/volumes");
+
+        Ok(serde_json::from_str(
+            &VirtualMachineClient::http_get(url).await?,
+        )?)
+    }
+}
+
+pub struct SnapshotClient {
+    api_server: String,
+}
+
+impl SnapshotClient {
+    pub async fn get(&self, name: &str) -> Result<Snapshot, Error> {
+        let url = Uri::new(
+            PathBuf::from(self.api_server.clone()),
+            &format!("/api/v1/snapshots/{name}"),
+        );
+
+        Ok(serde_json::from_str(
+            &VirtualMachineClient::http_get(url).await?,
+        )?)
+    }
 }
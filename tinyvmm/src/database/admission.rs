@@ -0,0 +1,455 @@
+use std::net::Ipv4Addr;
+
+use ipnet::Ipv4Net;
+
+use super::{
+    bridge::Bridge,
+    entity::Entity,
+    error::{Cause, Error},
+    image::Image,
+    snapshot::Snapshot,
+    store::Store,
+    virtual_machine::{memory_bytes, VirtualMachine},
+    volume::{size_bytes, CloneStrategy, Volume, VolumeFormat},
+};
+
+fn cause(field: &str, message: String) -> Cause {
+    Cause {
+        field: field.into(),
+        message,
+    }
+}
+
+fn check(kind: &'static str, name: &str, causes: Vec<Cause>) -> Result<(), Error> {
+    if causes.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Invalid {
+            kind,
+            name: name.into(),
+            causes,
+        })
+    }
+}
+
+/// Returns the problems with the vm address in the given bridge network.
+fn address_causes(field: &str, ip: &str, net: &Ipv4Net) -> Vec<Cause> {
+    let ip: Ipv4Addr = match ip.parse() {
+        Ok(ip) => ip,
+        Err(e) => return vec![cause(field, format!("`{ip}` is not an address: {e}"))],
+    };
+
+    if !net.contains(&ip) {
+        vec![cause(
+            field,
+            format!("{ip} is outside of the bridge network {}", net.trunc()),
+        )]
+    } else if ip == net.addr() {
+        vec![cause(field, format!("{ip} is the bridge router address"))]
+    } else if ip == net.network() || ip == net.broadcast() {
+        vec![cause(
+            field,
+            format!("{ip} is not a usable host address in {}", net.trunc()),
+        )]
+    } else {
+        vec![]
+    }
+}
+
+/// Checks the vm against the other entities in the store:
+///
+/// - the bridge of each interface exists
+/// - the addresses are host addresses of the bridge network
+/// - the addresses and the macs are unique on their bridge
+/// - the resize limits aren't below the vm size
+/// - the boot section names one thing to boot
+/// - each disk has one of a path, an existing volume or a vhost-user socket
+/// - the disk ids and the filesystem tags are unique
+pub fn admit_virtual_machine(store: &Store, vm: &VirtualMachine) -> Result<(), Error> {
+    let name = &vm.metadata.name;
+    let mut causes = vec![];
+
+    for (index, interface) in vm.spec.interfaces.iter().enumerate() {
+        let field = |key: &str| format!("spec.interfaces[{index}].{key}");
+        let bridge_name = &interface.bridge;
+
+        match Bridge::get(store, bridge_name) {
+            Ok(bridge) if bridge.metadata.deletion_timestamp.is_some() => causes.push(cause(
+                &field("bridge"),
+                format!("bridge `{bridge_name}` is being deleted"),
+            )),
+            Ok(bridge) => match bridge.spec.address.parse::<Ipv4Net>() {
+                Ok(net) => {
+                    if let Some(ip) = &interface.ip {
+                        causes.extend(address_causes(&field("ip"), ip, &net))
+                    }
+                }
+                Err(e) => causes.push(cause(
+                    &field("bridge"),
+                    format!("bridge `{bridge_name}` has an invalid address: {e}"),
+                )),
+            },
+            Err(Error::NotFound) => causes.push(cause(
+                &field("bridge"),
+                format!("bridge `{bridge_name}` doesn't exist"),
+            )),
+            Err(e) => return Err(e),
+        }
+
+        for other in &vm.spec.interfaces[..index] {
+            if other.mac == interface.mac {
+                causes.push(cause(
+                    &field("mac"),
+                    format!("{} is used by another interface", interface.mac),
+                ));
+            }
+            if let Some(ip) = interface
+                .ip
+                .as_ref()
+                .filter(|ip| other.bridge == *bridge_name && other.ip.as_ref() == Some(ip))
+            {
+                causes.push(cause(
+                    &field("ip"),
+                    format!("{ip} is used by another interface on {bridge_name}"),
+                ));
+            }
+        }
+    }
+
+    if vm.spec.max_cpus.filter(|max| *max < vm.spec.cpus).is_some() {
+        causes.push(cause(
+            "spec.maxCpus",
+            format!("must be at least the {} cpus", vm.spec.cpus),
+        ));
+    }
+    if let Some(max_memory) = &vm.spec.max_memory {
+        if let (Ok(max), Ok(memory)) = (memory_bytes(max_memory), memory_bytes(&vm.spec.memory)) {
+            if max < memory {
+                causes.push(cause(
+                    "spec.maxMemory",
+                    format!("must be at least the {} of memory", vm.spec.memory),
+                ));
+            }
+        }
+    }
+
+    if let Some(boot) = &vm.spec.boot {
+        if boot.firmware.is_some() && boot.kernel.is_some() {
+            causes.push(cause(
+                "spec.boot",
+                "either a firmware or a kernel can be booted, not both".into(),
+            ));
+        }
+        if boot.kernel.is_none() && (boot.initramfs.is_some() || boot.cmdline.is_some()) {
+            causes.push(cause(
+                "spec.boot.kernel",
+                "the initramfs and the command line need a kernel".into(),
+            ));
+        }
+    }
+
+    for (index, disk) in vm.spec.disks.iter().enumerate() {
+        let field = |key: &str| format!("spec.disks[{index}].{key}");
+        let sources = [&disk.path, &disk.volume, &disk.vhost_user_socket];
+        if sources.iter().filter(|source| source.is_some()).count() != 1 {
+            causes.push(cause(
+                &field("path"),
+                "a disk needs exactly one of a path, a volume or a vhost-user socket".into(),
+            ));
+        }
+        if let Some(volume_name) = &disk.volume {
+            match Volume::get(store, volume_name) {
+                Ok(volume) if volume.metadata.deletion_timestamp.is_some() => causes.push(cause(
+                    &field("volume"),
+                    format!("volume `{volume_name}` is being deleted"),
+                )),
+                Ok(_) => {}
+                Err(Error::NotFound) => causes.push(cause(
+                    &field("volume"),
+                    format!("volume `{volume_name}` doesn't exist"),
+                )),
+                Err(e) => return Err(e),
+            }
+            if vm.spec.disks[..index]
+                .iter()
+                .any(|other| other.volume.as_ref() == Some(volume_name))
+            {
+                causes.push(cause(
+                    &field("volume"),
+                    format!("`{volume_name}` is used by another disk"),
+                ));
+            }
+        }
+        if let Some(id) = &disk.id {
+            // the id of the cloud-init seed disk
+            if id == "cidata" {
+                causes.push(cause(&field("id"), format!("`{id}` is reserved")));
+            } else if vm.spec.disks[..index]
+                .iter()
+                .any(|other| other.id.as_ref() == Some(id))
+            {
+                causes.push(cause(
+                    &field("id"),
+                    format!("`{id}` is used by another disk"),
+                ));
+            }
+        }
+    }
+
+    for (index, filesystem) in vm.spec.filesystems.iter().enumerate() {
+        if vm.spec.filesystems[..index]
+            .iter()
+            .any(|other| other.tag == filesystem.tag)
+        {
+            causes.push(cause(
+                &format!("spec.filesystems[{index}].tag"),
+                format!("`{}` is used by another filesystem", filesystem.tag),
+            ));
+        }
+    }
+
+    for other in VirtualMachine::list(store)? {
+        if &other.metadata.name == name {
+            continue;
+        }
+        for (index, interface) in vm.spec.interfaces.iter().enumerate() {
+            let field = |key: &str| format!("spec.interfaces[{index}].{key}");
+            for theirs in &other.spec.interfaces {
+                if theirs.bridge != interface.bridge {
+                    continue;
+                }
+                if let Some(ip) = interface
+                    .ip
+                    .as_ref()
+                    .filter(|ip| theirs.ip.as_ref() == Some(ip))
+                {
+                    causes.push(cause(
+                        &field("ip"),
+                        format!("{} is already used by `{}`", ip, other.metadata.name),
+                    ));
+                }
+                if theirs.mac == interface.mac {
+                    causes.push(cause(
+                        &field("mac"),
+                        format!(
+                            "{} is already used by `{}`",
+                            interface.mac, other.metadata.name
+                        ),
+                    ));
+                }
+            }
+        }
+        for (index, disk) in vm.spec.disks.iter().enumerate() {
+            let Some(volume) = &disk.volume else {
+                continue;
+            };
+            if other
+                .spec
+                .disks
+                .iter()
+                .any(|d| d.volume.as_ref() == Some(volume))
+            {
+                causes.push(cause(
+                    &format!("spec.disks[{index}].volume"),
+                    format!("`{}` is already used by `{}`", volume, other.metadata.name),
+                ));
+            }
+        }
+    }
+
+    check(VirtualMachine::KIND, name, causes)
+}
+
+/// Checks that the size is valid and that an existing volume only grows,
+/// everything else about its image is fixed once it's created.
+pub fn admit_volume(store: &Store, volume: &Volume) -> Result<(), Error> {
+    let name = &volume.metadata.name;
+    let mut causes = vec![];
+
+    let size = match size_bytes(&volume.spec.size) {
+        Ok(size) => Some(size),
+        Err(e) => {
+            causes.push(cause("spec.size", format!("invalid size: {e}")));
+            None
+        }
+    };
+
+    if let Some(image_name) = &volume.spec.image {
+        if volume.spec.source.is_some() {
+            causes.push(cause(
+                "spec.image",
+                "a volume is created from either a source or an image".into(),
+            ));
+        }
+        match Image::get(store, image_name) {
+            Ok(image) if image.metadata.deletion_timestamp.is_some() => causes.push(cause(
+                "spec.image",
+                format!("image `{image_name}` is being deleted"),
+            )),
+            Ok(image) => match volume.spec.clone {
+                CloneStrategy::Reflink if image.spec.format != volume.spec.format => {
+                    causes.push(cause(
+                        "spec.format",
+                        "a reflink clone has the format of its image".into(),
+                    ))
+                }
+                CloneStrategy::Overlay if volume.spec.format != VolumeFormat::Qcow2 => causes.push(
+                    cause("spec.format", "an overlay needs the qcow2 format".into()),
+                ),
+                _ => {}
+            },
+            Err(Error::NotFound) => causes.push(cause(
+                "spec.image",
+                format!("image `{image_name}` doesn't exist"),
+            )),
+            Err(e) => return Err(e),
+        }
+    }
+
+    let current = match Volume::get(store, name) {
+        Ok(current) => Some(current),
+        Err(Error::NotFound) => None,
+        Err(e) => return Err(e),
+    };
+    if let Some(current) = current {
+        if let (Some(size), Ok(current_size)) = (size, size_bytes(&current.spec.size)) {
+            if size < current_size {
+                causes.push(cause(
+                    "spec.size",
+                    format!("can't shrink below the {} of the volume", current.spec.size),
+                ));
+            }
+        }
+        if volume.spec.format != current.spec.format {
+            causes.push(cause("spec.format", "can't be changed".into()));
+        }
+        if volume.spec.storage_dir != current.spec.storage_dir {
+            causes.push(cause("spec.storageDir", "can't be changed".into()));
+        }
+        if volume.spec.source != current.spec.source {
+            causes.push(cause("spec.source", "can't be changed".into()));
+        }
+        if volume.spec.image != current.spec.image {
+            causes.push(cause("spec.image", "can't be changed".into()));
+        }
+        if volume.spec.clone != current.spec.clone {
+            causes.push(cause("spec.clone", "can't be changed".into()));
+        }
+    }
+
+    check(Volume::KIND, name, causes)
+}
+
+/// Checks that the image comes from exactly one place, with a checksum for
+/// the urls, the cached copy can't change under its volumes afterwards.
+pub fn admit_image(store: &Store, image: &Image) -> Result<(), Error> {
+    let name = &image.metadata.name;
+    let mut causes = vec![];
+
+    if image.spec.path.is_some() == image.spec.url.is_some() {
+        causes.push(cause(
+            "spec",
+            "an image needs exactly one of a path or an url".into(),
+        ));
+    }
+    if image.spec.url.is_some() && image.spec.sha256.is_none() {
+        causes.push(cause(
+            "spec.sha256",
+            "an image fetched from an url needs its checksum".into(),
+        ));
+    }
+
+    match Image::get(store, name) {
+        Ok(current) if current.spec != image.spec => {
+            causes.push(cause("spec", "can't be changed".into()))
+        }
+        Ok(_) | Err(Error::NotFound) => {}
+        Err(e) => return Err(e),
+    }
+
+    check(Image::KIND, name, causes)
+}
+
+/// Checks that the bridge network doesn't overlap with the other bridges and
+/// still fits all the vms attached to it.
+pub fn admit_bridge(store: &Store, bridge: &Bridge) -> Result<(), Error> {
+    let name = &bridge.metadata.name;
+    let net: Ipv4Net = match bridge.spec.address.parse() {
+        Ok(net) => net,
+        Err(e) => {
+            return check(
+                Bridge::KIND,
+                name,
+                vec![cause("spec.address", format!("invalid address: {e}"))],
+            )
+        }
+    };
+    let mut causes = vec![];
+
+    for other in Bridge::list(store)? {
+        if &other.metadata.name == name {
+            continue;
+        }
+        let Ok(other_net) = other.spec.address.parse::<Ipv4Net>() else {
+            continue;
+        };
+        if net.contains(&other_net.network()) || other_net.contains(&net.network()) {
+            causes.push(cause(
+                "spec.address",
+                format!(
+                    "{} overlaps with {} of bridge `{}`",
+                    net.trunc(),
+                    other_net.trunc(),
+                    other.metadata.name
+                ),
+            ));
+        }
+    }
+
+    for vm in VirtualMachine::list(store)? {
+        let ips = vm
+            .spec
+            .interfaces
+            .iter()
+            .filter(|interface| &interface.bridge == name)
+            .filter_map(|interface| interface.ip.as_ref());
+        for c in ips.flat_map(|ip| address_causes("spec.address", ip, &net)) {
+            causes.push(cause(
+                "spec.address",
+                format!("doesn't fit vm `{}`: {}", vm.metadata.name, c.message),
+            ));
+        }
+    }
+
+    check(Bridge::KIND, name, causes)
+}
+
+/// Checks that the snapshot is of an existing vm, only its retention can
+/// change afterwards.
+pub fn admit_snapshot(store: &Store, snapshot: &Snapshot) -> Result<(), Error> {
+    let name = &snapshot.metadata.name;
+    let mut causes = vec![];
+
+    match Snapshot::get(store, name) {
+        Ok(current) => {
+            if snapshot.spec.vm != current.spec.vm {
+                causes.push(cause("spec.vm", "can't be changed".into()));
+            }
+        }
+        Err(Error::NotFound) => match VirtualMachine::get(store, &snapshot.spec.vm) {
+            Ok(vm) if vm.metadata.deletion_timestamp.is_some() => causes.push(cause(
+                "spec.vm",
+                format!("vm `{}` is being deleted", snapshot.spec.vm),
+            )),
+            Ok(_) => {}
+            Err(Error::NotFound) => causes.push(cause(
+                "spec.vm",
+                format!("vm `{}` doesn't exist", snapshot.spec.vm),
+            )),
+            Err(e) => return Err(e),
+        },
+        Err(e) => return Err(e),
+    }
+
+    check(Snapshot::KIND, name, causes)
+}
@@ -0,0 +1,140 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use futures::StreamExt;
+use tokio::sync::broadcast;
+
+use super::{Event, StorageBackend, Watcher};
+use crate::database::error::Error;
+
+const WATCH_CAPACITY: usize = 1024;
+
+/// A volatile backend that keeps everything in memory.
+pub struct MemoryBackend {
+    entries: Mutex<BTreeMap<String, Vec<u8>>>,
+    next_id: AtomicU64,
+    events: broadcast::Sender<Event>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        let (events, _) = broadcast::channel(WATCH_CAPACITY);
+
+        MemoryBackend {
+            entries: Mutex::new(BTreeMap::new()),
+            next_id: AtomicU64::new(0),
+            events,
+        }
+    }
+
+    fn notify(&self, event: Event) {
+        // there might be no watchers at all
+        let _ = self.events.send(event);
+    }
+}
+
+impl Default for MemoryBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.entries.lock().unwrap().get(key).cloned())
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &str,
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool, Error> {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.get(key).map(Vec::as_slice) != old {
+            return Ok(false);
+        }
+
+        let event = match new {
+            Some(value) => {
+                entries.insert(key.into(), value.to_vec());
+                Event::Insert {
+                    key: key.into(),
+                    value: value.to_vec(),
+                }
+            }
+            None => {
+                entries.remove(key);
+                Event::Remove { key: key.into() }
+            }
+        };
+        drop(entries);
+        self.notify(event);
+
+        Ok(true)
+    }
+
+    fn delete(&self, key: &str) -> Result<bool, Error> {
+        let removed = self.entries.lock().unwrap().remove(key).is_some();
+        if removed {
+            self.notify(Event::Remove { key: key.into() });
+        }
+
+        Ok(removed)
+    }
+
+    fn range(&self, start: &str, end: &str) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        Ok(self
+            .entries
+            .lock()
+            .unwrap()
+            .range(start.to_string()..end.to_string())
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect())
+    }
+
+    fn apply_batch(&self, batch: Vec<(String, Vec<u8>)>) -> Result<(), Error> {
+        let mut entries = self.entries.lock().unwrap();
+        for (key, value) in batch.iter() {
+            entries.insert(key.clone(), value.clone());
+        }
+        drop(entries);
+
+        for (key, value) in batch {
+            self.notify(Event::Insert { key, value });
+        }
+
+        Ok(())
+    }
+
+    fn generate_id(&self) -> Result<u64, Error> {
+        Ok(self.next_id.fetch_add(1, Ordering::SeqCst))
+    }
+
+    fn watch(&self, prefix: &str) -> Watcher {
+        let prefix = prefix.to_string();
+        let receiver = self.events.subscribe();
+
+        futures::stream::unfold(receiver, |mut receiver| async move {
+            match receiver.recv().await {
+                Ok(event) => Some((Ok(event), receiver)),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    Some((Err(Error::WatchLagged(missed)), receiver))
+                }
+                Err(broadcast::error::RecvError::Closed) => None,
+            }
+        })
+        .filter(move |event| {
+            futures::future::ready(match event {
+                Ok(event) => event.key().starts_with(&prefix),
+                Err(_) => true,
+            })
+        })
+        .boxed()
+    }
+}
@@ -0,0 +1,65 @@
+use futures::stream::BoxStream;
+
+use super::error::Error;
+
+#[cfg(test)]
+mod memory;
+mod sled;
+
+#[cfg(test)]
+pub use self::memory::MemoryBackend;
+pub use self::sled::SledBackend;
+
+/// A change to a stored key, as seen by the watchers.
+#[derive(Debug, Clone)]
+pub enum Event {
+    Insert { key: String, value: Vec<u8> },
+    Remove { key: String },
+}
+
+impl Event {
+    pub fn key(&self) -> &str {
+        match self {
+            Event::Insert { key, .. } => key,
+            Event::Remove { key } => key,
+        }
+    }
+}
+
+/// The changes under a prefix, in the order they were made. A watcher that
+/// falls behind gets an error in place of the events it missed, and should
+/// read the entries again.
+pub type Watcher = BoxStream<'static, Result<Event, Error>>;
+
+/// An ordered key-value storage for the serialized entities.
+pub trait StorageBackend: Send + Sync {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Stores the value unless the key already exists. Returns false if it did.
+    fn put_if_absent(&self, key: &str, value: &[u8]) -> Result<bool, Error> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    /// Atomically replaces the value if the current one matches `old`, where
+    /// `None` stands for a missing key. Returns false if it didn't match.
+    fn compare_and_swap(
+        &self,
+        key: &str,
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool, Error>;
+
+    /// Removes the key. Returns false if it didn't exist.
+    fn delete(&self, key: &str) -> Result<bool, Error>;
+
+    /// Returns all the entries with the keys in `start..end`, in key order.
+    fn range(&self, start: &str, end: &str) -> Result<Vec<(String, Vec<u8>)>, Error>;
+
+    /// Atomically stores all the entries.
+    fn apply_batch(&self, entries: Vec<(String, Vec<u8>)>) -> Result<(), Error>;
+
+    /// Returns a new id, greater than all the previously generated ones.
+    fn generate_id(&self) -> Result<u64, Error>;
+
+    fn watch(&self, prefix: &str) -> Watcher;
+}
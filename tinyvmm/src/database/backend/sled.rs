@@ -0,0 +1,84 @@
+use futures::StreamExt;
+use sled::{Db, Tree};
+
+use super::{Event, StorageBackend, Watcher};
+use crate::database::error::Error;
+
+pub struct SledBackend {
+    db: Db,
+    tree: Tree,
+}
+
+impl SledBackend {
+    pub fn new<P: AsRef<std::path::Path>>(path: P, tree: &str) -> Result<Self, Error> {
+        let db = sled::open(path)?;
+        let tree = db.open_tree(tree)?;
+
+        Ok(SledBackend { db, tree })
+    }
+}
+
+fn key_to_string(key: &[u8]) -> String {
+    String::from_utf8_lossy(key).into_owned()
+}
+
+impl StorageBackend for SledBackend {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.tree.get(key)?.map(|v| v.to_vec()))
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &str,
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool, Error> {
+        Ok(self.tree.compare_and_swap(key, old, new)?.is_ok())
+    }
+
+    fn delete(&self, key: &str) -> Result<bool, Error> {
+        Ok(self.tree.remove(key)?.is_some())
+    }
+
+    fn range(&self, start: &str, end: &str) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        self.tree
+            .range(start..end)
+            .map(|e| {
+                e.map(|(k, v)| (key_to_string(&k), v.to_vec()))
+                    .map_err(Error::SledError)
+            })
+            .collect()
+    }
+
+    fn apply_batch(&self, entries: Vec<(String, Vec<u8>)>) -> Result<(), Error> {
+        let mut batch = sled::Batch::default();
+        for (key, value) in entries {
+            batch.insert(key.as_bytes(), value);
+        }
+        self.tree.apply_batch(batch)?;
+
+        Ok(())
+    }
+
+    fn generate_id(&self) -> Result<u64, Error> {
+        Ok(self.db.generate_id()?)
+    }
+
+    fn watch(&self, prefix: &str) -> Watcher {
+        let subscriber = self.tree.watch_prefix(prefix);
+
+        futures::stream::unfold(subscriber, |mut subscriber| async move {
+            let event = match (&mut subscriber).await? {
+                sled::Event::Insert { key, value } => Event::Insert {
+                    key: key_to_string(&key),
+                    value: value.to_vec(),
+                },
+                sled::Event::Remove { key } => Event::Remove {
+                    key: key_to_string(&key),
+                },
+            };
+            Some((Ok(event), subscriber))
+        })
+        .boxed()
+    }
+}
@@ -0,0 +1,311 @@
+use std::{
+    io::{BufRead, Write},
+    str::FromStr,
+};
+
+use serde::Deserialize;
+use serde_json::value::Value;
+use serde_valid::json::FromJsonValue;
+
+use super::{
+    entity::Entity,
+    error::{Cause, Error},
+    ipam,
+    migration::migrate_kind,
+    serde::{EntityObject, ValueGetter},
+    store::Store,
+    virtual_machine::VirtualMachine,
+};
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Format {
+    #[default]
+    JsonLines,
+    Yaml,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json-lines" => Ok(Format::JsonLines),
+            "yaml" => Ok(Format::Yaml),
+            _ => Err(format!("unknown format `{s}`, expected json-lines or yaml")),
+        }
+    }
+}
+
+/// What to do when an imported entity already exists in the store.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum ConflictMode {
+    #[default]
+    Fail,
+    Skip,
+    Overwrite,
+}
+
+impl FromStr for ConflictMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(ConflictMode::Fail),
+            "skip" => Ok(ConflictMode::Skip),
+            "overwrite" => Ok(ConflictMode::Overwrite),
+            _ => Err(format!(
+                "unknown conflict mode `{s}`, expected fail, skip or overwrite"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    pub created: usize,
+    pub skipped: usize,
+    pub overwritten: usize,
+}
+
+/// Serializes a single entity as one document of the given format.
+pub fn encode_entity(entity: &Value, format: Format) -> Result<Vec<u8>, Error> {
+    match format {
+        Format::JsonLines => {
+            let mut data = serde_json::to_vec(entity)?;
+            data.push(b'\n');
+            Ok(data)
+        }
+        Format::Yaml => {
+            let mut data = b"---\n".to_vec();
+            data.extend(serde_yaml::to_string(entity)?.into_bytes());
+            Ok(data)
+        }
+    }
+}
+
+pub fn write_entities<W: Write>(
+    entities: &[Value],
+    format: Format,
+    mut writer: W,
+) -> Result<(), Error> {
+    for entity in entities {
+        writer.write_all(&encode_entity(entity, format)?)?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+pub fn read_entities<R: BufRead>(format: Format, mut reader: R) -> Result<Vec<Value>, Error> {
+    let mut entities = vec![];
+
+    match format {
+        Format::JsonLines => {
+            for line in reader.lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                entities.push(serde_json::from_str(&line)?);
+            }
+        }
+        Format::Yaml => {
+            let mut data = String::new();
+            reader.read_to_string(&mut data)?;
+            for document in serde_yaml::Deserializer::from_str(&data) {
+                let entity = Value::deserialize(document)?;
+                if !entity.is_null() {
+                    entities.push(entity);
+                }
+            }
+        }
+    }
+
+    Ok(entities)
+}
+
+fn entity_id(entity: &Value) -> Result<(&str, &str), Error> {
+    let kind = entity
+        .get_existing("kind")?
+        .as_str()
+        .ok_or(Error::MissingKey("kind"))?;
+    let name = entity
+        .get_existing("metadata")?
+        .as_map()?
+        .get_existing("name")?
+        .as_str()
+        .ok_or(Error::MissingKey("name"))?;
+
+    Ok((kind, name))
+}
+
+/// Imports the entities into the store, migrating and validating them first.
+/// The vms claim their addresses and macs like the created ones, and the
+/// existing ones are never overwritten since they own claims and units.
+/// The entities are written in a single atomic batch, so nothing is written
+/// if any of them is invalid, changed meanwhile or, in the fail mode,
+/// already exists.
+pub fn import(
+    store: &Store,
+    entities: Vec<Value>,
+    mode: ConflictMode,
+) -> Result<ImportSummary, Error> {
+    let mut claimed = vec![];
+    let result = prepare(store, entities, mode, &mut claimed).and_then(|(summary, batch)| {
+        store.replace_entities(batch)?;
+        Ok(summary)
+    });
+    if result.is_err() {
+        for (bridge, address, owner) in claimed {
+            ipam::release(store, &bridge, &address, &owner)?;
+        }
+    }
+
+    result
+}
+
+/// The entities to write, with the versions they replace.
+type Batch = Vec<(Value, Option<u64>)>;
+
+/// Returns the entities to write. The claims made for the vms are added to
+/// `claimed` as they're made.
+fn prepare(
+    store: &Store,
+    entities: Vec<Value>,
+    mode: ConflictMode,
+    claimed: &mut Vec<(String, String, String)>,
+) -> Result<(ImportSummary, Batch), Error> {
+    let mut summary = ImportSummary::default();
+    let mut batch = vec![];
+    for entity in entities {
+        let kind = entity_id(&entity)?.0.to_string();
+        let mut entity = migrate_kind(&kind, entity)?;
+
+        let (kind, name) = entity_id(&entity)?;
+        let (kind, name) = (kind.to_string(), name.to_string());
+        let read_at = match (store.get_entity(&kind, &name)?, mode) {
+            (None, _) => {
+                summary.created += 1;
+                None
+            }
+            (Some(_), ConflictMode::Fail) => return Err(Error::KeyExists { kind, name }),
+            (Some(_), ConflictMode::Skip) => {
+                summary.skipped += 1;
+                continue;
+            }
+            (Some(_), ConflictMode::Overwrite) if kind == VirtualMachine::KIND => {
+                return Err(Error::Invalid {
+                    kind: VirtualMachine::KIND,
+                    name,
+                    causes: vec![Cause {
+                        field: "metadata.name".into(),
+                        message: "an existing vm can't be overwritten, delete it first".into(),
+                    }],
+                })
+            }
+            (Some(current), ConflictMode::Overwrite) => {
+                summary.overwritten += 1;
+                Some(
+                    current["metadata"]["resourceVersion"]
+                        .as_u64()
+                        .unwrap_or_default(),
+                )
+            }
+        };
+
+        // the imported entities start over, like the created ones
+        entity
+            .as_map_mut()?
+            .get_mut("metadata")
+            .ok_or(Error::MissingKey("metadata"))?
+            .as_map_mut()?
+            .remove("deletionTimestamp");
+
+        if kind == VirtualMachine::KIND {
+            let mut vm = VirtualMachine::from_json_value(entity)?;
+            for (bridge, address) in ipam::assign(store, &mut vm)? {
+                claimed.push((bridge, address, name.clone()));
+            }
+            entity = serde_json::to_value(vm)?;
+        }
+        batch.push((entity, read_at));
+    }
+
+    Ok((summary, batch))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{import, ConflictMode};
+    use crate::{
+        database::{entity::Entity, error::Error, store::Store, virtual_machine::VirtualMachine},
+        fixtures::test_vm,
+    };
+
+    #[test]
+    fn claims_the_addresses_of_the_imported_vms() {
+        let store = Store::in_memory();
+
+        // the second vm has the same mac on the same bridge
+        let e = import(
+            &store,
+            vec![
+                json!(test_vm("vm1", json!({}))),
+                json!(test_vm("vm2", json!({}))),
+            ],
+            ConflictMode::Fail,
+        )
+        .unwrap_err();
+        assert!(
+            matches!(e, Error::Invalid { ref name, .. } if name == "vm2"),
+            "{e}"
+        );
+        assert!(VirtualMachine::list(&store).unwrap().is_empty());
+        assert!(store.get_address_claims().unwrap().is_empty());
+
+        let summary = import(
+            &store,
+            vec![json!(test_vm("vm1", json!({})))],
+            ConflictMode::Fail,
+        )
+        .unwrap();
+        assert_eq!(summary.created, 1);
+        assert_eq!(store.get_address_claims().unwrap().len(), 1);
+        assert!(import(
+            &store,
+            vec![json!(test_vm("vm2", json!({})))],
+            ConflictMode::Fail
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn never_overwrites_the_vms() {
+        let store = Store::in_memory();
+        import(
+            &store,
+            vec![json!(test_vm("vm1", json!({})))],
+            ConflictMode::Fail,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            import(
+                &store,
+                vec![json!(test_vm("vm1", json!({})))],
+                ConflictMode::Overwrite
+            ),
+            Err(Error::Invalid { .. })
+        ));
+        let summary = import(
+            &store,
+            vec![json!(test_vm("vm1", json!({})))],
+            ConflictMode::Skip,
+        )
+        .unwrap();
+        assert_eq!(summary.skipped, 1);
+    }
+}
@@ -1,7 +1,7 @@
 use serde_json::value::Value;
 use vmm_entity::{vmm_entity, vmm_entity_struct};
 
-use super::{entity::MigratableEntity, error::Error};
+use super::{entity::MigratableEntity, error::Error, ipam::AddressRange};
 
 pub fn get_migrator(version: &str) -> Option<fn(Value) -> Result<Value, Error>> {
     match version {
@@ -25,4 +25,18 @@ pub struct BridgeSpec {
     pub dns_zone: String,
     #[validate(pattern = r"^\d+\.\d+\.\d+\.\d+$")]
     pub dns_server: String,
+    /// Addresses that are never allocated to vms: single addresses, ranges
+    /// like `10.0.0.2-10.0.0.9` or subnets.
+    #[validate(custom(reserved_validation))]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reserved: Vec<String>,
+}
+
+fn reserved_validation(ranges: &Vec<String>) -> Result<(), serde_valid::validation::Error> {
+    for range in ranges {
+        if let Err(e) = range.parse::<AddressRange>() {
+            return Err(serde_valid::validation::Error::Custom(e));
+        }
+    }
+    Ok(())
 }
@@ -1,7 +1,9 @@
 use serde::{de::DeserializeOwned, Serialize};
 use serde_json::value::Value;
 
-use super::{error::Error, serde::ValueGetter, store::Store};
+use super::{
+    error::Error, metadata::Metadata, selector::LabelSelector, serde::ValueGetter, store::Store,
+};
 
 pub trait Entity {
     const KIND: &'static str;
@@ -42,20 +44,32 @@ pub trait Entity {
         Ok(unwrapped)
     }
 
-    fn delete<T>(store: &Store, name: T) -> Result<(), super::error::Error>
+    /// Requests the deletion of the entity. Returns false if the entity has
+    /// finalizers and will only be removed once those are cleared.
+    fn delete<T>(store: &Store, name: T) -> Result<bool, super::error::Error>
     where
         T: AsRef<str>,
     {
-        store.delete_entity(Self::KIND, name.as_ref())?;
-
-        Ok(())
+        store.delete_entity(Self::KIND, name.as_ref())
     }
 
     fn list(store: &Store) -> Result<Vec<Self::Type>, super::error::Error> {
+        Self::list_matching(store, &LabelSelector::default())
+    }
+
+    fn list_matching(
+        store: &Store,
+        selector: &LabelSelector,
+    ) -> Result<Vec<Self::Type>, super::error::Error> {
         let entities = store.get_kind(Self::KIND)?;
 
         let mut vms = vec![];
         for entity in entities {
+            let metadata: Metadata =
+                serde_json::value::from_value(entity.get_existing("metadata")?.clone())?;
+            if !selector.matches(&metadata.labels) {
+                continue;
+            }
             let entity = Self::migrate_version(entity)?;
             let vm = serde_json::value::from_value(entity)?;
             vms.push(vm);
@@ -64,13 +78,24 @@ pub trait Entity {
         Ok(vms)
     }
 
-    fn create(&self, store: &Store) -> Result<(), super::error::Error>
+    fn create(&self, store: &Store) -> Result<Self::Type, super::error::Error>
+    where
+        Self: Serialize,
+    {
+        let val = serde_json::value::to_value(self)?;
+        let val = store.create_entity(val)?;
+
+        Ok(serde_json::value::from_value(val)?)
+    }
+
+    fn update(&self, store: &Store) -> Result<Self::Type, super::error::Error>
     where
         Self: Serialize,
     {
         let val = serde_json::value::to_value(self)?;
-        store.create_entity(val)?;
-        Ok(())
+        let val = store.update_entity(val)?;
+
+        Ok(serde_json::value::from_value(val)?)
     }
 }
 
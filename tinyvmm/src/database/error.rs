@@ -1,5 +1,21 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+/// A single field-level reason for rejecting an entity.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Cause {
+    pub field: String,
+    pub message: String,
+}
+
+impl fmt::Display for Cause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("serialize error: {0}")]
@@ -33,6 +49,34 @@ pub enum Error {
     #[error("an entity already exists for {kind}/{name}")]
     KeyExists { kind: String, name: String },
 
+    #[error("the entity {kind}/{name} was modified concurrently")]
+    Conflict { kind: String, name: String },
+
+    #[error("invalid label selector term `{0}`")]
+    InvalidSelector(String),
+
+    /// Only the in-memory backend drops the changes for the watchers that
+    /// fall behind, sled holds up the writers instead.
+    #[cfg(test)]
+    #[error("the watch fell behind and missed {0} changes")]
+    WatchLagged(u64),
+
+    #[error("unknown entity kind {0}")]
+    UnknownKind(String),
+
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("yaml error: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+
+    #[error("{kind}/{name} is invalid: {}", causes.iter().map(Cause::to_string).collect::<Vec<_>>().join(", "))]
+    Invalid {
+        kind: &'static str,
+        name: String,
+        causes: Vec<Cause>,
+    },
+
     #[error("entity not found")]
     NotFound,
 }
@@ -0,0 +1,111 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use serde_json::value::Value;
+use vmm_entity::{vmm_entity, vmm_entity_struct};
+
+use super::{
+    entity::{Entity, MigratableEntity},
+    error::Error,
+    store::Store,
+    volume::{Volume, VolumeFormat},
+};
+
+/// Where the images are cached, one file per image.
+const IMAGES_DIR: &str = "/var/lib/tinyvmm/images";
+
+pub fn get_migrator(version: &str) -> Option<fn(Value) -> Result<Value, Error>> {
+    match version {
+        "v1alpha1" => Some(Image::migrate),
+        _ => None,
+    }
+}
+
+/// A base image the volumes are created from, cached once.
+#[vmm_entity("v1alpha1", "get_migrator")]
+pub struct Image {
+    #[validate]
+    pub spec: ImageSpec,
+    #[serde(default)]
+    pub status: ImageStatus,
+}
+impl MigratableEntity for Image {}
+
+/// Either a local file or an http url, the url needs the checksum since
+/// it's fetched without tls.
+#[vmm_entity_struct]
+#[derive(PartialEq)]
+pub struct ImageSpec {
+    #[validate(custom(path_validation))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[validate(pattern = r"^http://[^\s]+$")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// The hex sha256 of the image, checked once it's cached.
+    #[validate(pattern = r"^[0-9a-f]{64}$")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(default)]
+    pub format: VolumeFormat,
+}
+
+/// The observed state of an image, maintained by the unit server.
+#[vmm_entity_struct]
+#[derive(Clone, PartialEq)]
+pub struct ImageStatus {
+    pub phase: ImagePhase,
+    /// The cached copy, once it's complete.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// In bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// The volumes created from the image.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub volumes: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum ImagePhase {
+    #[default]
+    Pending,
+    Ready,
+    Failed,
+}
+
+/// Where the image is cached, whether it's fetched yet or not.
+pub fn image_path(image: &Image) -> PathBuf {
+    let extension = match image.spec.format {
+        VolumeFormat::Raw => "raw",
+        VolumeFormat::Qcow2 => "qcow2",
+    };
+
+    PathBuf::from(IMAGES_DIR).join(format!("{}.{}", image.metadata.name, extension))
+}
+
+/// The names of the volumes created from the image, the deleted ones
+/// included until their images are removed.
+pub fn dependents(store: &Store, name: &str) -> Result<Vec<String>, Error> {
+    let mut volumes: Vec<_> = Volume::list(store)?
+        .into_iter()
+        .filter(|volume| volume.spec.image.as_deref() == Some(name))
+        .map(|volume| volume.metadata.name)
+        .collect();
+    volumes.sort();
+
+    Ok(volumes)
+}
+
+fn path_validation(path: &Option<String>) -> Result<(), serde_valid::validation::Error> {
+    match path {
+        Some(path) if !std::path::Path::new(path).is_file() => Err(
+            serde_valid::validation::Error::Custom(format!("image `{}` doesn't exist", path)),
+        ),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,381 @@
+use std::{
+    collections::HashSet,
+    net::Ipv4Addr,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use ipnet::Ipv4Net;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    bridge::Bridge,
+    entity::Entity,
+    error::{Cause, Error},
+    store::Store,
+    virtual_machine::VirtualMachine,
+};
+
+/// How long a claim is kept for a vm that doesn't exist yet, so that the
+/// claims made right before creating the vm aren't collected.
+const CLAIM_GRACE_PERIOD: Duration = Duration::from_secs(60);
+
+/// An inclusive range of addresses.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AddressRange {
+    start: Ipv4Addr,
+    end: Ipv4Addr,
+}
+
+impl AddressRange {
+    pub fn contains(&self, ip: &Ipv4Addr) -> bool {
+        &self.start <= ip && ip <= &self.end
+    }
+}
+
+impl FromStr for AddressRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |e: &dyn std::fmt::Display| format!("invalid address range `{s}`: {e}");
+
+        if let Some((start, end)) = s.split_once('-') {
+            let start = start.trim().parse().map_err(|e| invalid(&e))?;
+            let end = end.trim().parse().map_err(|e| invalid(&e))?;
+            if start > end {
+                return Err(invalid(&"the range is reversed"));
+            }
+            return Ok(AddressRange { start, end });
+        }
+        if s.contains('/') {
+            let net: Ipv4Net = s.parse().map_err(|e| invalid(&e))?;
+            return Ok(AddressRange {
+                start: net.network(),
+                end: net.broadcast(),
+            });
+        }
+
+        let ip = s.parse().map_err(|e| invalid(&e))?;
+        Ok(AddressRange { start: ip, end: ip })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Claim {
+    owner: String,
+    /// Seconds since the unix epoch.
+    claimed_at: u64,
+}
+
+impl Claim {
+    fn new(owner: &str) -> Self {
+        Claim {
+            owner: owner.into(),
+            claimed_at: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        let claimed_at = SystemTime::UNIX_EPOCH + Duration::from_secs(self.claimed_at);
+        claimed_at + CLAIM_GRACE_PERIOD < SystemTime::now()
+    }
+}
+
+/// The macs are claimed on their bridge like the ips, lowercased so that
+/// they are unique whatever their case.
+fn mac_address(mac: &str) -> String {
+    mac.to_ascii_lowercase()
+}
+
+/// A claim is stale once its owner is gone or has moved to another address.
+fn is_stale(store: &Store, bridge: &str, address: &str, claim: &Claim) -> Result<bool, Error> {
+    if !claim.is_expired() {
+        return Ok(false);
+    }
+
+    match VirtualMachine::get(store, &claim.owner) {
+        Ok(vm) => Ok(!vm.spec.interfaces.iter().any(|i| {
+            i.bridge == bridge
+                && (i.ip.as_deref() == Some(address) || mac_address(&i.mac) == address)
+        })),
+        Err(Error::NotFound) => Ok(true),
+        Err(e) => Err(e),
+    }
+}
+
+/// Claims the ip or mac address for the vm. Returns true if the claim is new
+/// and false if the vm already had it.
+fn claim(
+    store: &Store,
+    bridge: &str,
+    address: &str,
+    owner: &str,
+    field: &str,
+) -> Result<bool, Error> {
+    let data = serde_json::to_vec(&Claim::new(owner))?;
+
+    loop {
+        let current = store.get_address_claim(bridge, address)?;
+        if let Some(current) = &current {
+            let claim: Claim = serde_json::from_slice(current)?;
+            if claim.owner == owner {
+                return Ok(false);
+            }
+            if !is_stale(store, bridge, address, &claim)? {
+                return Err(Error::Invalid {
+                    kind: VirtualMachine::KIND,
+                    name: owner.into(),
+                    causes: vec![Cause {
+                        field: field.into(),
+                        message: format!("{address} is already allocated to `{}`", claim.owner),
+                    }],
+                });
+            }
+        }
+
+        if store.swap_address_claim(bridge, address, current.as_deref(), Some(&data))? {
+            return Ok(true);
+        }
+    }
+}
+
+/// Drops the vm claim on the address, if it still holds it.
+pub fn release(store: &Store, bridge: &str, address: &str, owner: &str) -> Result<(), Error> {
+    let Some(current) = store.get_address_claim(bridge, address)? else {
+        return Ok(());
+    };
+    let claim: Claim = serde_json::from_slice(&current)?;
+    if claim.owner == owner {
+        store.swap_address_claim(bridge, address, Some(&current), None)?;
+    }
+
+    Ok(())
+}
+
+/// Drops the vm claims on the ips and macs of its interfaces.
+pub fn release_all(store: &Store, vm: &VirtualMachine) -> Result<(), Error> {
+    for interface in &vm.spec.interfaces {
+        let name = &vm.metadata.name;
+        if let Some(ip) = &interface.ip {
+            release(store, &interface.bridge, ip, name)?;
+        }
+        release(store, &interface.bridge, &mac_address(&interface.mac), name)?;
+    }
+
+    Ok(())
+}
+
+/// Drops the claims of the previous spec of the vm that the new one doesn't
+/// hold anymore.
+pub fn release_dropped(
+    store: &Store,
+    previous: &VirtualMachine,
+    vm: &VirtualMachine,
+) -> Result<(), Error> {
+    for interface in &previous.spec.interfaces {
+        let kept = |address: &str| {
+            vm.spec.interfaces.iter().any(|i| {
+                i.bridge == interface.bridge
+                    && (i.ip.as_deref() == Some(address) || mac_address(&i.mac) == address)
+            })
+        };
+        let name = &vm.metadata.name;
+        if let Some(ip) = interface.ip.as_deref().filter(|ip| !kept(ip)) {
+            release(store, &interface.bridge, ip, name)?;
+        }
+        let mac = mac_address(&interface.mac);
+        if !kept(&mac) {
+            release(store, &interface.bridge, &mac, name)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Makes sure the vm holds a claim on the address and mac of each of its
+/// interfaces, allocating the next free address of the bridge network to the
+/// interfaces that have none. Returns the newly claimed bridges and
+/// addresses, so that they can be released if the vm isn't saved.
+///
+/// Interfaces on unknown bridges are left alone for the admission to reject.
+pub fn assign(store: &Store, vm: &mut VirtualMachine) -> Result<Vec<(String, String)>, Error> {
+    let mut claimed = vec![];
+
+    for index in 0..vm.spec.interfaces.len() {
+        let bridge = vm.spec.interfaces[index].bridge.clone();
+        let result = assign_interface(store, vm, index).and_then(|ip| {
+            claimed.extend(ip.map(|ip| (bridge.clone(), ip)));
+            claim_mac(store, vm, index)
+        });
+        match result {
+            Ok(mac) => claimed.extend(mac.map(|mac| (bridge, mac))),
+            Err(e) => {
+                for (bridge, address) in &claimed {
+                    release(store, bridge, address, &vm.metadata.name)?;
+                }
+                return Err(e);
+            }
+        }
+    }
+
+    Ok(claimed)
+}
+
+/// Claims the mac of the interface, returning it if the claim is new.
+fn claim_mac(store: &Store, vm: &VirtualMachine, index: usize) -> Result<Option<String>, Error> {
+    let interface = &vm.spec.interfaces[index];
+    let mac = mac_address(&interface.mac);
+    let field = format!("spec.interfaces[{index}].mac");
+
+    Ok(claim(store, &interface.bridge, &mac, &vm.metadata.name, &field)?.then_some(mac))
+}
+
+fn assign_interface(
+    store: &Store,
+    vm: &mut VirtualMachine,
+    index: usize,
+) -> Result<Option<String>, Error> {
+    let name = &vm.metadata.name;
+    let field = format!("spec.interfaces[{index}].ip");
+    let interface = &vm.spec.interfaces[index];
+    let bridge_name = &interface.bridge;
+
+    if let Some(ip) = &interface.ip {
+        if ip.parse::<Ipv4Addr>().is_err() {
+            return Ok(None);
+        }
+        return Ok(claim(store, bridge_name, ip, name, &field)?.then(|| ip.clone()));
+    }
+
+    let bridge = match Bridge::get(store, bridge_name) {
+        Ok(bridge) => bridge,
+        Err(Error::NotFound) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let Ok(net) = bridge.spec.address.parse::<Ipv4Net>() else {
+        return Ok(None);
+    };
+    let reserved: Vec<AddressRange> = bridge
+        .spec
+        .reserved
+        .iter()
+        .filter_map(|r| r.parse().ok())
+        .collect();
+
+    // the vms created before the allocation was introduced have no claims,
+    // and the other interfaces of the vm on the same bridge share its claims
+    let mut used: HashSet<String> = VirtualMachine::list(store)?
+        .into_iter()
+        .filter(|other| &other.metadata.name != name)
+        .flat_map(|other| other.spec.interfaces)
+        .filter(|other| &other.bridge == bridge_name)
+        .filter_map(|other| other.ip)
+        .collect();
+    used.extend(
+        vm.spec
+            .interfaces
+            .iter()
+            .filter(|other| &other.bridge == bridge_name)
+            .filter_map(|other| other.ip.clone()),
+    );
+
+    for ip in net.hosts() {
+        if ip == net.addr() || reserved.iter().any(|r| r.contains(&ip)) {
+            continue;
+        }
+        let ip = ip.to_string();
+        if used.contains(&ip) {
+            continue;
+        }
+
+        match claim(store, bridge_name, &ip, name, &field) {
+            Ok(new) => {
+                vm.spec.interfaces[index].ip = Some(ip.clone());
+                return Ok(new.then_some(ip));
+            }
+            Err(Error::Invalid { .. }) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(Error::Invalid {
+        kind: VirtualMachine::KIND,
+        name: name.clone(),
+        causes: vec![Cause {
+            field,
+            message: format!("no free addresses left in {}", net.trunc()),
+        }],
+    })
+}
+
+/// Releases the claims of the vms that were deleted or moved to another
+/// address. Returns the number of released claims.
+pub fn release_stale(store: &Store) -> Result<usize, Error> {
+    let mut released = 0;
+
+    for (bridge, address, data) in store.get_address_claims()? {
+        let claim: Claim = serde_json::from_slice(&data)?;
+        if is_stale(store, &bridge, &address, &claim)?
+            && store.swap_address_claim(&bridge, &address, Some(&data), None)?
+        {
+            released += 1;
+        }
+    }
+
+    Ok(released)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{assign, release_all};
+    use crate::{
+        database::{error::Error, store::Store},
+        fixtures::test_vm,
+    };
+
+    #[test]
+    fn claims_the_macs_on_their_bridge() {
+        let store = Store::in_memory();
+
+        let mut vm1 = test_vm("vm1", json!({}));
+        let claimed = assign(&store, &mut vm1).unwrap();
+        assert_eq!(claimed, [("br0".into(), "02:00:00:00:00:01".into())]);
+        assert!(assign(&store, &mut vm1).unwrap().is_empty());
+
+        let mut vm2 = test_vm(
+            "vm2",
+            json!({ "interfaces": [{ "bridge": "br0", "mac": "02:00:00:00:00:0A" }] }),
+        );
+        let mut same_mac = test_vm(
+            "vm2",
+            json!({
+                "interfaces": [
+                    { "bridge": "br0", "mac": "02:00:00:00:00:0a" },
+                    { "bridge": "br0", "mac": "02:00:00:00:00:01" },
+                ],
+            }),
+        );
+        match assign(&store, &mut same_mac) {
+            Err(Error::Invalid { causes, .. }) => {
+                assert_eq!(causes[0].field, "spec.interfaces[1].mac");
+                assert_eq!(
+                    causes[0].message,
+                    "02:00:00:00:00:01 is already allocated to `vm1`"
+                );
+            }
+            result => panic!("unexpected {result:?}"),
+        }
+        // the claims of the rejected spec are released
+        assert_eq!(assign(&store, &mut vm2).unwrap().len(), 1);
+
+        release_all(&store, &vm1).unwrap();
+        let mut vm3 = test_vm("vm3", json!({}));
+        assert_eq!(assign(&store, &mut vm3).unwrap().len(), 1);
+    }
+}
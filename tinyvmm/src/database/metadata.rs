@@ -1,8 +1,114 @@
+use std::collections::BTreeMap;
+
+use once_cell::sync::Lazy;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use serde_valid::Validate;
 
+static NAME_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^([A-Za-z0-9][-A-Za-z0-9_.]*)?[A-Za-z0-9]$").unwrap());
+static PREFIX_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^[a-z0-9]([-a-z0-9]*[a-z0-9])?(\.[a-z0-9]([-a-z0-9]*[a-z0-9])?)*$").unwrap()
+});
+
+const MAX_ANNOTATIONS_SIZE: usize = 256 * 1024;
+
 #[derive(Serialize, Deserialize, Debug, Validate, Default)]
+#[serde(rename_all = "camelCase")]
 pub struct Metadata {
     #[validate(pattern = r"^[a-z0-9]([-a-z0-9]*[a-z0-9])?(\.[a-z0-9]([-a-z0-9]*[a-z0-9])?)*$")]
     pub name: String,
+    #[serde(default)]
+    pub resource_version: u64,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    #[validate(custom(labels_validation))]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    #[validate(custom(annotations_validation))]
+    pub annotations: BTreeMap<String, String>,
+    /// Set by the store once the deletion was requested but there are
+    /// finalizers left. Can't be changed by the clients.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deletion_timestamp: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub finalizers: Vec<String>,
+}
+
+impl Metadata {
+    pub fn has_finalizer(&self, finalizer: &str) -> bool {
+        self.finalizers.iter().any(|f| f == finalizer)
+    }
+
+    pub fn add_finalizer(&mut self, finalizer: &str) {
+        if !self.has_finalizer(finalizer) {
+            self.finalizers.push(finalizer.into());
+        }
+    }
+
+    pub fn remove_finalizer(&mut self, finalizer: &str) {
+        self.finalizers.retain(|f| f != finalizer);
+    }
+}
+
+/// Checks a label or annotation key: an optional DNS subdomain prefix and a
+/// slash, followed by a name of up to 63 alphanumerics, `-`, `_` or `.`.
+pub fn is_valid_key(key: &str) -> bool {
+    let (prefix, name) = match key.split_once('/') {
+        Some((prefix, name)) => (Some(prefix), name),
+        None => (None, key),
+    };
+
+    if let Some(prefix) = prefix {
+        if prefix.len() > 253 || !PREFIX_REGEX.is_match(prefix) {
+            return false;
+        }
+    }
+
+    name.len() <= 63 && NAME_REGEX.is_match(name)
+}
+
+pub fn is_valid_label_value(value: &str) -> bool {
+    value.is_empty() || (value.len() <= 63 && NAME_REGEX.is_match(value))
+}
+
+fn labels_validation(
+    labels: &BTreeMap<String, String>,
+) -> Result<(), serde_valid::validation::Error> {
+    for (key, value) in labels {
+        if !is_valid_key(key) {
+            return Err(serde_valid::validation::Error::Custom(format!(
+                "label key `{}` is invalid",
+                key
+            )));
+        }
+        if !is_valid_label_value(value) {
+            return Err(serde_valid::validation::Error::Custom(format!(
+                "label value `{}` for `{}` is invalid",
+                value, key
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn annotations_validation(
+    annotations: &BTreeMap<String, String>,
+) -> Result<(), serde_valid::validation::Error> {
+    let mut size = 0;
+    for (key, value) in annotations {
+        if !is_valid_key(key) {
+            return Err(serde_valid::validation::Error::Custom(format!(
+                "annotation key `{}` is invalid",
+                key
+            )));
+        }
+        size += key.len() + value.len();
+    }
+    if size > MAX_ANNOTATIONS_SIZE {
+        return Err(serde_valid::validation::Error::Custom(format!(
+            "annotations are too long: {} bytes, must be at most {}",
+            size, MAX_ANNOTATIONS_SIZE
+        )));
+    }
+    Ok(())
 }
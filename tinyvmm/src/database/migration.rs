@@ -0,0 +1,207 @@
+use serde::Serialize;
+use serde_json::value::Value;
+use serde_valid::{json::FromJsonValue, Validate};
+
+use super::{
+    bridge::Bridge,
+    entity::Entity,
+    error::Error,
+    image::Image,
+    serde::{EntityObject, ValueGetter},
+    snapshot::Snapshot,
+    store::Store,
+    virtual_machine::VirtualMachine,
+    volume::Volume,
+};
+
+/// The outcome of migrating a single stored entity to its latest version.
+pub struct Migration {
+    pub kind: String,
+    pub name: String,
+    /// The migrated entity, or the reason it couldn't be migrated.
+    pub result: Result<Value, Error>,
+    /// Human-readable changes between the stored and the migrated entity.
+    pub diff: Vec<String>,
+}
+
+impl Migration {
+    pub fn is_changed(&self) -> bool {
+        !self.diff.is_empty()
+    }
+}
+
+fn migrate<E>(entity: Value) -> Result<Value, Error>
+where
+    E: Entity,
+    E::Type: Validate + Serialize,
+{
+    let entity = E::migrate_version(entity)?;
+    let entity = E::Type::from_json_value(entity)?;
+
+    Ok(serde_json::value::to_value(entity)?)
+}
+
+/// Migrates the entity of the given kind to its latest version and validates it.
+pub fn migrate_kind(kind: &str, entity: Value) -> Result<Value, Error> {
+    if kind == VirtualMachine::KIND {
+        migrate::<VirtualMachine>(entity)
+    } else if kind == Bridge::KIND {
+        migrate::<Bridge>(entity)
+    } else if kind == Volume::KIND {
+        migrate::<Volume>(entity)
+    } else if kind == Image::KIND {
+        migrate::<Image>(entity)
+    } else if kind == Snapshot::KIND {
+        migrate::<Snapshot>(entity)
+    } else {
+        Err(Error::UnknownKind(kind.into()))
+    }
+}
+
+fn diff(path: &str, before: &Value, after: &Value, out: &mut Vec<String>) {
+    match (before, after) {
+        (Value::Object(before), Value::Object(after)) => {
+            for (key, value) in before {
+                let path = format!("{path}/{key}");
+                match after.get(key) {
+                    Some(new_value) => diff(&path, value, new_value, out),
+                    None => out.push(format!("- {path}: {value}")),
+                }
+            }
+            for (key, value) in after {
+                if !before.contains_key(key) {
+                    out.push(format!("+ {path}/{key}: {value}"));
+                }
+            }
+        }
+        (before, after) if before != after => out.push(format!("~ {path}: {before} -> {after}")),
+        _ => {}
+    }
+}
+
+/// Runs the migrator chain and the validation for every stored entity.
+pub fn plan(store: &Store) -> Result<Vec<Migration>, Error> {
+    let mut migrations = vec![];
+
+    for entity in store.get_all()? {
+        let kind = entity
+            .get_existing("kind")?
+            .as_str()
+            .ok_or(Error::MissingKey("kind"))?
+            .to_string();
+        let name = entity
+            .get_existing("metadata")?
+            .as_map()?
+            .get_existing("name")?
+            .as_str()
+            .ok_or(Error::MissingKey("name"))?
+            .to_string();
+
+        let result = migrate_kind(&kind, entity.clone());
+        let mut changes = vec![];
+        if let Ok(migrated) = &result {
+            diff("", &entity, migrated, &mut changes);
+        }
+
+        migrations.push(Migration {
+            kind,
+            name,
+            result,
+            diff: changes,
+        });
+    }
+
+    Ok(migrations)
+}
+
+/// Rewrites all the changed entities at once. Nothing is written if any of
+/// the entities failed to migrate or changed since they were read.
+pub fn apply(store: &Store, migrations: Vec<Migration>) -> Result<usize, Error> {
+    let mut entities = vec![];
+    for migration in migrations {
+        if migration.is_changed() {
+            let migrated = migration.result?;
+            // the migrations keep the metadata, and so the version read
+            let read_at = migrated["metadata"]["resourceVersion"]
+                .as_u64()
+                .unwrap_or_default();
+            entities.push((migrated, Some(read_at)));
+        } else {
+            migration.result?;
+        }
+    }
+
+    let count = entities.len();
+    store.replace_entities(entities)?;
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::{apply, plan};
+    use crate::database::{error::Error, store::Store};
+
+    fn v1alpha3_vm(name: &str) -> Value {
+        let disk = std::env::current_exe().unwrap();
+        json!({
+            "apiVersion": "v1alpha3",
+            "kind": "VirtualMachine",
+            "metadata": { "name": name },
+            "spec": {
+                "cpus": 1,
+                "memory": "512M",
+                "disks": [disk],
+                "mac": "02:00:00:00:00:01",
+                "bridge": "br0",
+            },
+        })
+    }
+
+    fn api_version(store: &Store, name: &str) -> Value {
+        store.get_entity("VirtualMachine", name).unwrap().unwrap()["apiVersion"].clone()
+    }
+
+    #[test]
+    fn only_rewrites_the_entities_once_applied() {
+        let store = Store::in_memory();
+        store.create_entity(v1alpha3_vm("vm1")).unwrap();
+
+        // planning is the dry run
+        let migrations = plan(&store).unwrap();
+        assert_eq!(migrations.len(), 1);
+        assert!(migrations[0].is_changed());
+        assert!(migrations[0]
+            .diff
+            .contains(&"~ /apiVersion: \"v1alpha3\" -> \"v1alpha5\"".to_string()));
+        assert_eq!(api_version(&store, "vm1"), "v1alpha3");
+
+        assert_eq!(apply(&store, migrations).unwrap(), 1);
+        assert_eq!(api_version(&store, "vm1"), "v1alpha5");
+        assert!(!plan(&store).unwrap()[0].is_changed());
+    }
+
+    #[test]
+    fn rewrites_nothing_if_an_entity_fails_or_changed() {
+        let store = Store::in_memory();
+        store.create_entity(v1alpha3_vm("vm1")).unwrap();
+        let mut broken = v1alpha3_vm("vm2");
+        broken["apiVersion"] = "v1alpha0".into();
+        store.create_entity(broken).unwrap();
+
+        assert!(apply(&store, plan(&store).unwrap()).is_err());
+        assert_eq!(api_version(&store, "vm1"), "v1alpha3");
+
+        store.delete_entity("VirtualMachine", "vm2").unwrap();
+        let migrations = plan(&store).unwrap();
+        let vm1 = store.get_entity("VirtualMachine", "vm1").unwrap().unwrap();
+        store.update_entity(vm1).unwrap();
+        assert!(matches!(
+            apply(&store, migrations),
+            Err(Error::Conflict { .. })
+        ));
+        assert_eq!(api_version(&store, "vm1"), "v1alpha3");
+    }
+}
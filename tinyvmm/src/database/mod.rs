@@ -1,7 +1,16 @@
+pub mod admission;
+pub mod backend;
+pub mod backup;
 pub mod bridge;
 pub mod entity;
 pub mod error;
+pub mod image;
+pub mod ipam;
 pub mod metadata;
+pub mod migration;
+pub mod selector;
 pub mod serde;
+pub mod snapshot;
 pub mod store;
 pub mod virtual_machine;
+pub mod volume;
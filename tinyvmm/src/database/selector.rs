@@ -0,0 +1,206 @@
+use std::{collections::BTreeMap, str::FromStr};
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use super::{
+    error::Error,
+    metadata::{is_valid_key, is_valid_label_value},
+};
+
+static SET_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^(\S+)\s+(in|notin)\s*\((.*)\)$").unwrap());
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Requirement {
+    Equals(String, String),
+    NotEquals(String, String),
+    In(String, Vec<String>),
+    NotIn(String, Vec<String>),
+    Exists(String),
+    DoesNotExist(String),
+}
+
+impl Requirement {
+    fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        use Requirement::*;
+
+        match self {
+            Equals(k, v) => labels.get(k) == Some(v),
+            NotEquals(k, v) => labels.get(k) != Some(v),
+            In(k, vs) => labels.get(k).map(|v| vs.contains(v)).unwrap_or(false),
+            NotIn(k, vs) => labels.get(k).map(|v| !vs.contains(v)).unwrap_or(true),
+            Exists(k) => labels.contains_key(k),
+            DoesNotExist(k) => !labels.contains_key(k),
+        }
+    }
+}
+
+/// A kubernetes-style equality and set based label selector, e.g.
+/// `env=prod,tier!=db,zone in (a,b),!legacy`. An empty selector matches
+/// everything.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LabelSelector {
+    requirements: Vec<Requirement>,
+}
+
+impl LabelSelector {
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        self.requirements.iter().all(|r| r.matches(labels))
+    }
+}
+
+fn split_terms(selector: &str) -> Vec<&str> {
+    let mut terms = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+
+    for (i, c) in selector.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                terms.push(&selector[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    terms.push(&selector[start..]);
+
+    terms
+        .into_iter()
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .collect()
+}
+
+fn parse_requirement(term: &str) -> Result<Requirement, Error> {
+    let invalid = || Error::InvalidSelector(term.into());
+    let key = |k: &str| {
+        let k = k.trim();
+        if is_valid_key(k) {
+            Ok(k.to_string())
+        } else {
+            Err(invalid())
+        }
+    };
+    let value = |v: &str| {
+        let v = v.trim();
+        if is_valid_label_value(v) {
+            Ok(v.to_string())
+        } else {
+            Err(invalid())
+        }
+    };
+
+    if let Some(captures) = SET_REGEX.captures(term) {
+        let values = captures[3]
+            .split(',')
+            .map(value)
+            .collect::<Result<Vec<_>, _>>()?;
+        return match &captures[2] {
+            "in" => Ok(Requirement::In(key(&captures[1])?, values)),
+            _ => Ok(Requirement::NotIn(key(&captures[1])?, values)),
+        };
+    }
+    if let Some(k) = term.strip_prefix('!') {
+        return Ok(Requirement::DoesNotExist(key(k)?));
+    }
+    if let Some((k, v)) = term.split_once("!=") {
+        return Ok(Requirement::NotEquals(key(k)?, value(v)?));
+    }
+    if let Some((k, v)) = term.split_once("==").or_else(|| term.split_once('=')) {
+        return Ok(Requirement::Equals(key(k)?, value(v)?));
+    }
+
+    Ok(Requirement::Exists(key(term)?))
+}
+
+impl FromStr for LabelSelector {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let requirements = split_terms(s)
+            .into_iter()
+            .map(parse_requirement)
+            .collect::<Result<_, _>>()?;
+
+        Ok(LabelSelector { requirements })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde_json::json;
+
+    use super::{LabelSelector, Requirement};
+    use crate::{
+        database::{entity::Entity, error::Error, store::Store, virtual_machine::VirtualMachine},
+        fixtures::test_vm,
+    };
+
+    fn labels(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn parses_the_equality_and_set_requirements() {
+        let selector: LabelSelector =
+            "env=prod, tier!=db,zone in (a, b),app notin (x),team,!legacy"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            selector.requirements,
+            [
+                Requirement::Equals("env".into(), "prod".into()),
+                Requirement::NotEquals("tier".into(), "db".into()),
+                Requirement::In("zone".into(), vec!["a".into(), "b".into()]),
+                Requirement::NotIn("app".into(), vec!["x".into()]),
+                Requirement::Exists("team".into()),
+                Requirement::DoesNotExist("legacy".into()),
+            ]
+        );
+
+        let matching = labels(&[("env", "prod"), ("zone", "a"), ("team", "web")]);
+        assert!(selector.matches(&matching));
+        assert!(!selector.matches(&labels(&[("env", "prod"), ("zone", "a")])));
+        assert!(!selector.matches(&labels(&[
+            ("env", "prod"),
+            ("zone", "a"),
+            ("team", "web"),
+            ("legacy", ""),
+        ])));
+        assert!(LabelSelector::default().matches(&BTreeMap::new()));
+
+        for invalid in ["env=prod=1", "-env", "zone in (a,-b)"] {
+            assert!(
+                matches!(
+                    invalid.parse::<LabelSelector>(),
+                    Err(Error::InvalidSelector(_))
+                ),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn lists_the_matching_entities() {
+        let store = Store::in_memory();
+        for (name, env) in [("vm1", "prod"), ("vm2", "dev")] {
+            let mut vm = test_vm(name, json!({}));
+            vm.metadata.labels = labels(&[("env", env)]);
+            vm.create(&store).unwrap();
+        }
+
+        let selector = "env in (prod,staging)".parse().unwrap();
+        let vms = VirtualMachine::list_matching(&store, &selector).unwrap();
+        let names: Vec<_> = vms.iter().map(|vm| vm.metadata.name.as_str()).collect();
+        assert_eq!(names, ["vm1"]);
+    }
+}
@@ -3,6 +3,7 @@ use serde_json::value::Value;
 
 pub trait EntityObject {
     fn as_map(&self) -> Result<&serde_json::map::Map<String, Value>, Error>;
+    fn as_map_mut(&mut self) -> Result<&mut serde_json::map::Map<String, Value>, Error>;
     fn is_version(&self, version: &str) -> Result<bool, Error> {
         Ok(self.as_map()?.get_existing("apiVersion")? == version)
     }
@@ -16,6 +17,10 @@ impl EntityObject for Value {
     fn as_map(&self) -> Result<&serde_json::map::Map<String, Value>, Error> {
         self.as_object().ok_or(Error::NotAnObject)
     }
+
+    fn as_map_mut(&mut self) -> Result<&mut serde_json::map::Map<String, Value>, Error> {
+        self.as_object_mut().ok_or(Error::NotAnObject)
+    }
 }
 
 impl ValueGetter for Value {
@@ -29,3 +34,24 @@ impl ValueGetter for serde_json::map::Map<String, Value> {
         self.get(index).ok_or(Error::MissingKey(index))
     }
 }
+
+/// Applies a JSON merge patch (RFC 7386) to the target value.
+pub fn merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+
+    if !target.is_object() {
+        *target = Value::Object(serde_json::map::Map::new());
+    }
+    let target = target.as_object_mut().unwrap();
+
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use serde_json::value::Value;
+use vmm_entity::{vmm_entity, vmm_entity_struct};
+
+use super::{
+    entity::{Entity, MigratableEntity},
+    error::Error,
+    store::Store,
+};
+
+/// Where the snapshots are kept, one dir per snapshot.
+const SNAPSHOTS_DIR: &str = "/var/lib/tinyvmm/snapshots";
+
+pub fn get_migrator(version: &str) -> Option<fn(Value) -> Result<Value, Error>> {
+    match version {
+        "v1alpha1" => Some(Snapshot::migrate),
+        _ => None,
+    }
+}
+
+/// The memory, device state and disks of a running vm, taken once by the
+/// unit server.
+#[vmm_entity("v1alpha1", "get_migrator")]
+pub struct Snapshot {
+    #[validate]
+    pub spec: SnapshotSpec,
+    #[serde(default)]
+    pub status: SnapshotStatus,
+}
+impl MigratableEntity for Snapshot {}
+
+#[vmm_entity_struct]
+pub struct SnapshotSpec {
+    pub vm: String,
+    /// How long the snapshot is kept once it's taken, like `7d`, forever if
+    /// not set.
+    #[validate(custom(retention_validation))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention: Option<String>,
+}
+
+/// The observed state of a snapshot, maintained by the unit server.
+#[vmm_entity_struct]
+#[derive(Clone, PartialEq)]
+pub struct SnapshotStatus {
+    pub phase: SnapshotPhase,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub taken_at: Option<String>,
+    /// The full hash of the spec the vm ran with, it's only restored onto
+    /// the same one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spec_hash: Option<String>,
+    /// The copies of the disks, the vhost-user ones aren't part of it.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub disks: Vec<SnapshotDisk>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum SnapshotPhase {
+    #[default]
+    Pending,
+    Ready,
+    Failed,
+}
+
+#[vmm_entity_struct]
+#[derive(Clone, PartialEq)]
+pub struct SnapshotDisk {
+    /// The disk of the vm.
+    pub path: String,
+    /// Its copy in the snapshot.
+    pub copy: String,
+}
+
+/// Where the snapshot is written, whether it's taken yet or not.
+pub fn snapshot_dir(snapshot: &Snapshot) -> PathBuf {
+    PathBuf::from(SNAPSHOTS_DIR).join(&snapshot.metadata.name)
+}
+
+/// The snapshots of the vm in the order they were taken, the pending ones
+/// last.
+pub fn of_vm(store: &Store, vm: &str) -> Result<Vec<Snapshot>, Error> {
+    let mut snapshots: Vec<_> = Snapshot::list(store)?
+        .into_iter()
+        .filter(|snapshot| snapshot.spec.vm == vm)
+        .collect();
+    snapshots.sort_by_key(|snapshot| {
+        (
+            snapshot.status.taken_at.is_none(),
+            snapshot.status.taken_at.clone(),
+            snapshot.metadata.name.clone(),
+        )
+    });
+
+    Ok(snapshots)
+}
+
+fn retention_validation(retention: &Option<String>) -> Result<(), serde_valid::validation::Error> {
+    match retention.as_deref().map(humantime::parse_duration) {
+        Some(Err(e)) => Err(serde_valid::validation::Error::Custom(format!(
+            "invalid retention: {e}"
+        ))),
+        _ => Ok(()),
+    }
+}
@@ -1,93 +1,442 @@
-use std::path::PathBuf;
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
 
 use serde_json::Value;
-use sled::Tree;
 
 use super::{
+    backend::{SledBackend, StorageBackend, Watcher},
     error::Error,
     serde::{EntityObject, ValueGetter},
 };
 
 #[derive(Clone)]
 pub struct Store {
-    entity_tree: Tree,
+    backend: Arc<dyn StorageBackend>,
+    /// Writers share the lock, while snapshots take it exclusively so that
+    /// they see a consistent view of all the entities.
+    snapshot_lock: Arc<RwLock<()>>,
     pub store_path: PathBuf,
 }
 
 impl Store {
     pub fn new<P: AsRef<std::path::Path>>(path: P) -> Result<Self, Error> {
         let path = path.as_ref();
-        let db = sled::open(path)?;
+        let backend = SledBackend::new(path, "entities")?;
 
-        let entity_tree = db.open_tree("entities")?;
+        Ok(Self::with_backend(Arc::new(backend), path.into()))
+    }
 
-        Ok(Store {
-            entity_tree,
-            store_path: path.into(),
-        })
+    /// Creates a store that doesn't persist anything, for the tests.
+    #[cfg(test)]
+    pub fn in_memory() -> Self {
+        Self::with_backend(
+            Arc::new(super::backend::MemoryBackend::new()),
+            PathBuf::new(),
+        )
+    }
+
+    pub fn with_backend(backend: Arc<dyn StorageBackend>, store_path: PathBuf) -> Self {
+        Store {
+            backend,
+            snapshot_lock: Arc::new(RwLock::new(())),
+            store_path,
+        }
     }
 
     fn key(kind: &str, name: &str) -> String {
         format!("/{kind}/{name}")
     }
 
-    pub fn watch_entities(&self, prefix: &str) -> sled::Subscriber {
-        self.entity_tree.watch_prefix(prefix)
+    fn entity_id(e: &Value) -> Result<(String, String), Error> {
+        let kind = e
+            .get_existing("kind")?
+            .as_str()
+            .ok_or(Error::MissingKey("kind"))?;
+        let name = e
+            .get_existing("metadata")?
+            .as_map()?
+            .get_existing("name")?
+            .as_str()
+            .ok_or(Error::MissingKey("name"))?;
+
+        Ok((kind.into(), name.into()))
+    }
+
+    fn resource_version(e: &Value) -> Result<u64, Error> {
+        Ok(e.get_existing("metadata")?
+            .as_map()?
+            .get("resourceVersion")
+            .and_then(Value::as_u64)
+            .unwrap_or_default())
+    }
+
+    fn deletion_timestamp(e: &Value) -> Result<Option<Value>, Error> {
+        Ok(e.get_existing("metadata")?
+            .as_map()?
+            .get("deletionTimestamp")
+            .filter(|ts| !ts.is_null())
+            .cloned())
+    }
+
+    fn has_finalizers(e: &Value) -> Result<bool, Error> {
+        Ok(e.get_existing("metadata")?
+            .as_map()?
+            .get("finalizers")
+            .and_then(Value::as_array)
+            .map(|f| !f.is_empty())
+            .unwrap_or(false))
+    }
+
+    fn set_deletion_timestamp(e: &mut Value, ts: Option<Value>) -> Result<(), Error> {
+        let metadata = e
+            .as_map_mut()?
+            .get_mut("metadata")
+            .ok_or(Error::MissingKey("metadata"))?
+            .as_map_mut()?;
+        match ts {
+            Some(ts) => metadata.insert("deletionTimestamp".into(), ts),
+            None => metadata.remove("deletionTimestamp"),
+        };
+
+        Ok(())
+    }
+
+    /// Returns a fresh resource version. Versions are shared across all the
+    /// entities and are never 0, which is reserved for the records written
+    /// before versioning was introduced.
+    fn next_version(&self) -> Result<u64, Error> {
+        Ok(self.backend.generate_id()? + 1)
+    }
+
+    fn set_resource_version(e: &mut Value, version: u64) -> Result<(), Error> {
+        e.as_map_mut()?
+            .get_mut("metadata")
+            .ok_or(Error::MissingKey("metadata"))?
+            .as_map_mut()?
+            .insert("resourceVersion".into(), version.into());
+
+        Ok(())
+    }
+
+    /// Stamps the entity with a fresh resource version.
+    fn bump_resource_version(&self, e: &mut Value) -> Result<(), Error> {
+        Self::set_resource_version(e, self.next_version()?)
+    }
+
+    /// The version a removed entity was deleted at is kept next to the
+    /// entities, so that the watchers can resume after the deletion. There's
+    /// one per name ever deleted, overwritten when the name is deleted again.
+    fn tombstone_key(key: &str) -> String {
+        format!("deleted{key}")
+    }
+
+    /// Returns the version the entity under the key was last deleted at.
+    pub fn deletion_version(&self, key: &str) -> Result<Option<u64>, Error> {
+        match self.backend.get(&Self::tombstone_key(key))? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn watch_entities(&self, prefix: &str) -> Watcher {
+        self.backend.watch(prefix)
+    }
+
+    /// Drops the key if it still holds `current`, returning the version of
+    /// the deletion. The writers are kept out meanwhile, so that none slips
+    /// in between the check and the removal, nor gets an older version.
+    fn purge(&self, key: &str, current: &[u8]) -> Result<Option<u64>, Error> {
+        let _guard = self.snapshot_lock.write().unwrap();
+        if self.backend.get(key)?.as_deref() != Some(current) {
+            return Ok(None);
+        }
+
+        let version = self.next_version()?;
+        let tombstone = serde_json::to_vec(&version)?;
+        self.backend
+            .apply_batch(vec![(Self::tombstone_key(key), tombstone)])?;
+        self.backend.delete(key)?;
+
+        Ok(Some(version))
     }
 
     pub fn get_entity(&self, kind: &str, name: &str) -> Result<Option<Value>, Error> {
         let key = Self::key(kind, name);
-        let entity = self.entity_tree.get(key)?;
+        let entity = self.backend.get(&key)?;
 
         match entity {
-            Some(bytes) => Ok(Some(serde_json::from_slice(bytes.as_ref())?)),
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
             None => Ok(None),
         }
     }
 
-    pub fn delete_entity(&self, kind: &str, name: &str) -> Result<(), Error> {
+    /// Deletes the entity if it has no finalizers, otherwise marks it with a
+    /// deletion timestamp so that the controllers can clean up after it. The
+    /// entity is purged once the last finalizer is removed. Returns true if
+    /// the entity was removed right away.
+    pub fn delete_entity(&self, kind: &str, name: &str) -> Result<bool, Error> {
         let key = Self::key(kind, name);
-        self.entity_tree.remove(key)?;
 
-        Ok(())
+        loop {
+            let current = self.backend.get(&key)?.ok_or(Error::NotFound)?;
+            let mut e: Value = serde_json::from_slice(&current)?;
+
+            if !Self::has_finalizers(&e)? {
+                if self.purge(&key, &current)?.is_some() {
+                    return Ok(true);
+                }
+                continue;
+            }
+
+            let _guard = self.snapshot_lock.read().unwrap();
+
+            if Self::deletion_timestamp(&e)?.is_some() {
+                return Ok(false);
+            }
+
+            let now = humantime::format_rfc3339_seconds(SystemTime::now()).to_string();
+            Self::set_deletion_timestamp(&mut e, Some(now.into()))?;
+            self.bump_resource_version(&mut e)?;
+            let data = serde_json::to_string(&e)?;
+
+            if self
+                .backend
+                .compare_and_swap(&key, Some(&current), Some(data.as_bytes()))?
+            {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Address claims, on the ips and macs of a bridge, live outside of the
+    /// entities range, so they are neither listed, exported nor watched.
+    fn claim_key(bridge: &str, address: &str) -> String {
+        format!("ipam/{bridge}/{address}")
+    }
+
+    pub fn get_address_claim(&self, bridge: &str, address: &str) -> Result<Option<Vec<u8>>, Error> {
+        self.backend.get(&Self::claim_key(bridge, address))
+    }
+
+    /// Atomically replaces the claim on the address if it's still `old`.
+    pub fn swap_address_claim(
+        &self,
+        bridge: &str,
+        address: &str,
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool, Error> {
+        let _guard = self.snapshot_lock.read().unwrap();
+        self.backend
+            .compare_and_swap(&Self::claim_key(bridge, address), old, new)
+    }
+
+    /// Returns all the address claims as (bridge, address, claim) tuples.
+    pub fn get_address_claims(&self) -> Result<Vec<(String, String, Vec<u8>)>, Error> {
+        Ok(self
+            .backend
+            .range("ipam/", "ipam0")?
+            .into_iter()
+            .filter_map(|(key, claim)| {
+                let (bridge, address) = key.strip_prefix("ipam/")?.split_once('/')?;
+                Some((bridge.to_string(), address.to_string(), claim))
+            })
+            .collect())
     }
 
     pub fn get_kind(&self, kind: &str) -> Result<Vec<Value>, Error> {
         let start_key = format!("/{kind}/");
         let end_key = format!("/{kind}0");
 
-        self.entity_tree
-            .range(start_key..end_key)
-            .map(|e| {
-                e.map_err(Error::SledError).and_then(|bytes| {
-                    serde_json::from_slice(bytes.1.as_ref()).map_err(Error::Serialize)
-                })
-            })
+        self.range(&start_key, &end_key)
+    }
+
+    /// Returns every entity in the store, regardless of its kind.
+    pub fn get_all(&self) -> Result<Vec<Value>, Error> {
+        // all the keys start with a slash, and 0 is the next character
+        self.range("/", "0")
+    }
+
+    fn range(&self, start: &str, end: &str) -> Result<Vec<Value>, Error> {
+        self.backend
+            .range(start, end)?
+            .into_iter()
+            .map(|(_, bytes)| serde_json::from_slice(&bytes).map_err(Error::Serialize))
             .collect()
     }
 
-    pub fn create_entity(&self, e: Value) -> Result<(), Error> {
-        let kind = e
-            .get_existing("kind")?
-            .as_str()
-            .ok_or(Error::MissingKey("kind"))?;
-        let name = e
-            .get_existing("metadata")?
-            .as_map()?
-            .get_existing("name")?
-            .as_str()
-            .ok_or(Error::MissingKey("name"))?;
-        let key = Self::key(kind, name);
+    /// Returns every entity in the store as of a single point in time.
+    pub fn snapshot(&self) -> Result<Vec<Value>, Error> {
+        let _guard = self.snapshot_lock.write().unwrap();
+        self.get_all()
+    }
+
+    /// Writes the entities in a single atomic batch, each in place of the
+    /// version it was read at, or `None` if it didn't exist. Nothing is
+    /// written if any of them changed since.
+    pub fn replace_entities(&self, entities: Vec<(Value, Option<u64>)>) -> Result<(), Error> {
+        // the writers are kept out between the checks and the batch
+        let _guard = self.snapshot_lock.write().unwrap();
+        let mut batch = vec![];
+        for (mut e, read_at) in entities {
+            let (kind, name) = Self::entity_id(&e)?;
+            let key = Self::key(&kind, &name);
+            let current = match self.backend.get(&key)? {
+                Some(bytes) => Some(Self::resource_version(&serde_json::from_slice(&bytes)?)?),
+                None => None,
+            };
+            if current != read_at {
+                return Err(Error::Conflict { kind, name });
+            }
+
+            self.bump_resource_version(&mut e)?;
+            batch.push((key, serde_json::to_vec(&e)?));
+        }
+
+        self.backend.apply_batch(batch)
+    }
+
+    pub fn create_entity(&self, mut e: Value) -> Result<Value, Error> {
+        let (kind, name) = Self::entity_id(&e)?;
+        let key = Self::key(&kind, &name);
+        let _guard = self.snapshot_lock.read().unwrap();
+        Self::set_deletion_timestamp(&mut e, None)?;
+        self.bump_resource_version(&mut e)?;
         let data = serde_json::to_string(&e)?;
 
-        let result =
-            self.entity_tree
-                .compare_and_swap(key, None as Option<&[u8]>, Some(data.as_bytes()));
-        let result = result?;
+        if !self.backend.put_if_absent(&key, data.as_bytes())? {
+            return Err(Error::KeyExists { kind, name });
+        }
+
+        Ok(e)
+    }
+
+    /// Replaces an existing entity. The resource version of the passed entity
+    /// must match the stored one, otherwise the write is rejected as stale.
+    /// An entity pending deletion is purged once its last finalizer is gone.
+    pub fn update_entity(&self, mut e: Value) -> Result<Value, Error> {
+        let (kind, name) = Self::entity_id(&e)?;
+        let key = Self::key(&kind, &name);
+        let guard = self.snapshot_lock.read().unwrap();
 
-        result.map_err(|_| Error::KeyExists {
-            kind: kind.into(),
-            name: name.into(),
+        let current = self.backend.get(&key)?.ok_or(Error::NotFound)?;
+        let current_value: Value = serde_json::from_slice(&current)?;
+        if Self::resource_version(&e)? != Self::resource_version(&current_value)? {
+            return Err(Error::Conflict { kind, name });
+        }
+
+        let deletion_timestamp = Self::deletion_timestamp(&current_value)?;
+        let purge = deletion_timestamp.is_some() && !Self::has_finalizers(&e)?;
+        Self::set_deletion_timestamp(&mut e, deletion_timestamp)?;
+
+        let written = if purge {
+            drop(guard);
+            let version = self.purge(&key, &current)?;
+            if let Some(version) = version {
+                Self::set_resource_version(&mut e, version)?;
+            }
+            version.is_some()
+        } else {
+            self.bump_resource_version(&mut e)?;
+            let data = serde_json::to_string(&e)?;
+            self.backend
+                .compare_and_swap(&key, Some(&current), Some(data.as_bytes()))?
+        };
+        if !written {
+            return Err(Error::Conflict { kind, name });
+        }
+
+        Ok(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use serde_json::json;
+
+    use super::{super::backend::Event, Error, Store};
+
+    fn entity(name: &str, finalizers: &[&str]) -> serde_json::Value {
+        json!({
+            "apiVersion": "v1alpha1",
+            "kind": "Bridge",
+            "metadata": { "name": name, "finalizers": finalizers },
+            "spec": {},
         })
     }
+
+    #[test]
+    fn rejects_stale_writes() {
+        let store = Store::in_memory();
+        let created = store.create_entity(entity("br0", &[])).unwrap();
+        assert!(matches!(
+            store.create_entity(entity("br0", &[])),
+            Err(Error::KeyExists { .. })
+        ));
+
+        let updated = store.update_entity(created.clone()).unwrap();
+        assert!(matches!(
+            store.update_entity(created),
+            Err(Error::Conflict { .. })
+        ));
+        assert_eq!(store.get_entity("Bridge", "br0").unwrap(), Some(updated));
+    }
+
+    #[test]
+    fn purges_once_the_finalizers_are_gone() {
+        let store = Store::in_memory();
+        store.create_entity(entity("br0", &[])).unwrap();
+        assert!(store.delete_entity("Bridge", "br0").unwrap());
+        assert_eq!(store.get_entity("Bridge", "br0").unwrap(), None);
+
+        store.create_entity(entity("br1", &["test"])).unwrap();
+        assert!(!store.delete_entity("Bridge", "br1").unwrap());
+        let mut deleting = store.get_entity("Bridge", "br1").unwrap().unwrap();
+        assert!(deleting["metadata"]["deletionTimestamp"].is_string());
+
+        deleting["metadata"]["finalizers"] = json!([]);
+        store.update_entity(deleting).unwrap();
+        assert_eq!(store.get_entity("Bridge", "br1").unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn watches_the_changes() {
+        let store = Store::in_memory();
+        let mut watcher = store.watch_entities("/Bridge/");
+
+        store.create_entity(entity("br0", &[])).unwrap();
+        store.delete_entity("Bridge", "br0").unwrap();
+
+        assert!(matches!(
+            watcher.next().await,
+            Some(Ok(Event::Insert { key, .. })) if key == "/Bridge/br0"
+        ));
+        assert!(matches!(
+            watcher.next().await,
+            Some(Ok(Event::Remove { key })) if key == "/Bridge/br0"
+        ));
+    }
+
+    #[tokio::test]
+    async fn tells_the_watchers_that_fell_behind() {
+        let store = Store::in_memory();
+        let mut watcher = store.watch_entities("/Bridge/");
+
+        for i in 0..2000 {
+            store.create_entity(entity(&format!("br{i}"), &[])).unwrap();
+        }
+
+        assert!(matches!(
+            watcher.next().await,
+            Some(Err(Error::WatchLagged(_)))
+        ));
+        assert!(matches!(
+            watcher.next().await,
+            Some(Ok(Event::Insert { .. }))
+        ));
+    }
 }
@@ -1,6 +1,10 @@
 use rand::prelude::*;
 
-pub type VirtualMachine = res::v1alpha3::VirtualMachine;
+pub type VirtualMachine = res::v1alpha5::VirtualMachine;
+pub use res::v1alpha5::{
+    Boot, CacheMode, CloudInit, Condition, Disk, GuestInterface, Interface, Phase, RestartPolicy,
+    RunState, RunStrategy, TokenBucket, VirtualMachineStatus,
+};
 
 mod res {
     use serde_json::value::Value;
@@ -12,6 +16,8 @@ mod res {
             "v1alpha1" => Some(v1alpha1::VirtualMachine::migrate),
             "v1alpha2" => Some(v1alpha2::VirtualMachine::migrate),
             "v1alpha3" => Some(v1alpha3::VirtualMachine::migrate),
+            "v1alpha4" => Some(v1alpha4::VirtualMachine::migrate),
+            "v1alpha5" => Some(v1alpha5::VirtualMachine::migrate),
             _ => None,
         }
     }
@@ -127,7 +133,8 @@ mod res {
     }
 
     pub mod v1alpha3 {
-        use serde_json::value::Value;
+        use serde::{Deserialize, Serialize};
+        use serde_json::{json, value::Value};
         use vmm_entity::{vmm_entity, vmm_entity_struct};
 
         use super::get_migrator;
@@ -135,20 +142,46 @@ mod res {
         use crate::database::{
             entity::{Entity, MigratableEntity},
             error::Error,
+            serde::{EntityObject, ValueGetter},
         };
 
         #[vmm_entity("v1alpha3", "get_migrator")]
         pub struct VirtualMachine {
             #[validate]
             pub spec: VirtualMachineSpec,
+            #[serde(default)]
+            pub status: VirtualMachineStatus,
         }
 
         impl MigratableEntity for VirtualMachine {
-            fn migrate(_entity: Value) -> Result<Value, Error> {
-                Err(Error::NoMigrationAvailable {
-                    kind: Self::KIND,
-                    version: Self::API_VERSION,
-                })
+            fn migrate(entity: Value) -> Result<Value, Error> {
+                let spec = entity.get_existing("spec")?.as_map()?;
+                let mut new_spec = spec.clone();
+                let mut interface = serde_json::Map::new();
+                for field in ["bridge", "mac", "ip"] {
+                    if let Some(value) = new_spec.remove(field) {
+                        interface.insert(field.into(), value);
+                    }
+                }
+                new_spec.insert("interfaces".into(), vec![Value::Object(interface)].into());
+
+                let mut migrated = json! ({
+                    "apiVersion": super::v1alpha4::VirtualMachine::API_VERSION,
+                    "kind": Self::KIND,
+                    "metadata": entity.get_existing("metadata")?,
+                    "spec": new_spec,
+                });
+                // the vms stored before the status existed keep having none
+                if let Some(status) = entity.get("status") {
+                    let mut status = status.clone();
+                    // the tap names are observed again by the unit server
+                    if let Ok(status) = status.as_map_mut() {
+                        status.remove("tapName");
+                    }
+                    migrated.as_map_mut()?.insert("status".into(), status);
+                }
+
+                Ok(migrated)
             }
         }
 
@@ -158,10 +191,28 @@ mod res {
             pub cpus: u8,
             #[validate(pattern = r"^\d+(M|G)$")]
             pub memory: String,
+            /// The vcpus the running vm can be resized up to, defaults to cpus.
+            #[validate(minimum = 1)]
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub max_cpus: Option<u8>,
+            /// The memory the running vm can be resized up to, defaults to memory.
+            #[validate(pattern = r"^\d+(M|G)$")]
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub max_memory: Option<String>,
             #[validate(custom(super::super::disks_path_validation))]
             pub disks: Vec<String>,
+            /// What the vm boots, the daemon default firmware when not set.
+            #[validate]
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub boot: Option<Boot>,
+            /// Served to the guest as a cloud-init NoCloud seed disk.
+            #[validate]
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub cloud_init: Option<CloudInit>,
+            /// Allocated from the bridge network when not set.
             #[validate(pattern = r"^\d+\.\d+\.\d+\.\d+$")]
-            pub ip: String,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub ip: Option<String>,
             #[validate(
                 pattern = r"^[0-9a-f]{2}:[0-9a-f]{2}:[0-9a-f]{2}:[0-9a-f]{2}:[0-9a-f]{2}:[0-9a-f]{2}$"
             )]
@@ -171,10 +222,470 @@ mod res {
                 pattern = r"^[a-z0-9]([-a-z0-9]*[a-z0-9])?(\.[a-z0-9]([-a-z0-9]*[a-z0-9])?)*$"
             )]
             pub bridge: String,
+            #[serde(default)]
+            pub run_strategy: RunStrategy,
+            #[serde(default)]
+            pub restart_policy: RestartPolicy,
+        }
+
+        /// What the unit server does when a running vm no longer matches its
+        /// spec and the changes can't be applied live.
+        #[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+        pub enum RestartPolicy {
+            /// Gracefully restarts the vm.
+            #[default]
+            OnSpecChange,
+            /// Leaves the vm running until it's restarted by other means.
+            Never,
+        }
+
+        /// Either a firmware, or a kernel with its initramfs and command line.
+        #[vmm_entity_struct]
+        #[derive(Clone, PartialEq)]
+        pub struct Boot {
+            #[validate(custom(super::super::boot_path_validation))]
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub firmware: Option<String>,
+            #[validate(custom(super::super::boot_path_validation))]
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub kernel: Option<String>,
+            #[validate(custom(super::super::boot_path_validation))]
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub initramfs: Option<String>,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub cmdline: Option<String>,
+        }
+
+        /// The documents of the seed. The instance-id and the local-hostname
+        /// default to the vm name when missing from the meta data.
+        #[vmm_entity_struct]
+        #[derive(Clone, PartialEq)]
+        pub struct CloudInit {
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub user_data: Option<String>,
+            #[validate(custom(super::super::yaml_mapping_validation))]
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub meta_data: Option<String>,
+            #[validate(custom(super::super::yaml_mapping_validation))]
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub network_config: Option<String>,
+        }
+
+        /// How the unit server keeps the vm running.
+        #[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+        pub enum RunStrategy {
+            /// Started, and restarted whenever it stops.
+            #[default]
+            Always,
+            /// Stopped, and kept stopped.
+            Halted,
+            /// Only started and stopped through the lifecycle actions, the unit
+            /// server keeps it stopped or paused as the last one asked.
+            Manual,
+            /// Started once, and restarted only if it fails.
+            RerunOnFailure,
+        }
+
+        /// The observed state of a vm, maintained by the unit server.
+        #[vmm_entity_struct]
+        #[derive(Clone, PartialEq)]
+        pub struct VirtualMachineStatus {
+            pub phase: Phase,
+            #[serde(default, skip_serializing_if = "Vec::is_empty")]
+            pub conditions: Vec<Condition>,
+            pub tap_name: Option<String>,
+            pub unit_active_state: Option<String>,
+            pub pid: Option<u32>,
+            pub boot_time: Option<String>,
+            pub last_reconcile_error: Option<String>,
+            /// The run state last requested through the lifecycle actions.
+            pub desired_state: Option<RunState>,
+            /// The hash of the spec the running vm was booted with.
+            pub spec_hash: Option<String>,
+        }
+
+        #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+        pub enum RunState {
+            Running,
+            Stopped,
+            Paused,
+        }
+
+        #[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+        pub enum Phase {
+            #[default]
+            Pending,
+            Running,
+            Stopped,
+            Failed,
+        }
+
+        #[vmm_entity_struct]
+        #[derive(Clone, PartialEq)]
+        pub struct Condition {
+            #[serde(rename = "type")]
+            pub condition_type: String,
+            pub status: bool,
+            pub reason: String,
+            pub message: String,
+            pub last_transition_time: String,
+        }
+    }
+    pub mod v1alpha4 {
+        use serde::{Deserialize, Serialize};
+        use serde_json::{json, value::Value};
+        use vmm_entity::{vmm_entity, vmm_entity_struct};
+
+        use super::get_migrator;
+
+        pub use super::v1alpha3::{
+            Boot, CloudInit, Condition, Phase, RestartPolicy, RunState, RunStrategy,
+        };
+
+        use crate::database::{
+            entity::{Entity, MigratableEntity},
+            error::Error,
+            serde::{EntityObject, ValueGetter},
+        };
+
+        #[vmm_entity("v1alpha4", "get_migrator")]
+        pub struct VirtualMachine {
+            #[validate]
+            pub spec: VirtualMachineSpec,
+            #[serde(default)]
+            pub status: VirtualMachineStatus,
+        }
+
+        impl MigratableEntity for VirtualMachine {
+            fn migrate(entity: Value) -> Result<Value, Error> {
+                let spec = entity.get_existing("spec")?.as_map()?;
+                let mut new_spec = spec.clone();
+
+                // the disks paths become disks with the default options
+                let disks = match spec.get("disks") {
+                    Some(Value::Array(paths)) => {
+                        paths.iter().map(|path| json!({ "path": path })).collect()
+                    }
+                    _ => vec![],
+                };
+                new_spec.insert("disks".into(), disks.into());
+
+                let mut migrated = json! ({
+                    "apiVersion": super::v1alpha5::VirtualMachine::API_VERSION,
+                    "kind": Self::KIND,
+                    "metadata": entity.get_existing("metadata")?,
+                    "spec": new_spec,
+                });
+                if let Some(status) = entity.get("status") {
+                    migrated
+                        .as_map_mut()?
+                        .insert("status".into(), status.clone());
+                }
+
+                Ok(migrated)
+            }
+        }
+
+        #[vmm_entity_struct]
+        pub struct VirtualMachineSpec {
+            #[validate(minimum = 1)]
+            pub cpus: u8,
+            #[validate(pattern = r"^\d+(M|G)$")]
+            pub memory: String,
+            /// The vcpus the running vm can be resized up to, defaults to cpus.
+            #[validate(minimum = 1)]
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub max_cpus: Option<u8>,
+            /// The memory the running vm can be resized up to, defaults to memory.
+            #[validate(pattern = r"^\d+(M|G)$")]
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub max_memory: Option<String>,
+            #[validate(custom(super::super::disks_path_validation))]
+            pub disks: Vec<String>,
+            /// What the vm boots, the daemon default firmware when not set.
+            #[validate]
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub boot: Option<Boot>,
+            /// Served to the guest as a cloud-init NoCloud seed disk.
+            #[validate]
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub cloud_init: Option<CloudInit>,
+            /// The network interfaces, in the order the guest sees them.
+            #[validate(min_items = 1)]
+            #[validate]
+            pub interfaces: Vec<Interface>,
+            /// The host directories shared with the guest through virtio-fs.
+            #[validate]
+            #[serde(default, skip_serializing_if = "Vec::is_empty")]
+            pub filesystems: Vec<Filesystem>,
+            #[serde(default)]
+            pub run_strategy: RunStrategy,
+            #[serde(default)]
+            pub restart_policy: RestartPolicy,
+        }
+
+        /// A network interface attached to a bridge through its own tap.
+        #[vmm_entity_struct]
+        #[derive(Clone, PartialEq)]
+        pub struct Interface {
+            #[validate(
+                pattern = r"^[a-z0-9]([-a-z0-9]*[a-z0-9])?(\.[a-z0-9]([-a-z0-9]*[a-z0-9])?)*$"
+            )]
+            pub bridge: String,
+            #[validate(
+                pattern = r"^[0-9a-f]{2}:[0-9a-f]{2}:[0-9a-f]{2}:[0-9a-f]{2}:[0-9a-f]{2}:[0-9a-f]{2}$"
+            )]
+            #[serde(default = "super::super::generate_default_mac")]
+            pub mac: String,
+            /// Allocated from the bridge network when not set.
+            #[validate(pattern = r"^\d+\.\d+\.\d+\.\d+$")]
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub ip: Option<String>,
+            #[validate(minimum = 68)]
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub mtu: Option<u16>,
+        }
+
+        /// A host directory served by its own virtiofsd, mounted in the guest
+        /// with `mount -t virtiofs <tag> <dir>`.
+        #[vmm_entity_struct]
+        #[derive(Clone, PartialEq)]
+        pub struct Filesystem {
+            #[validate(pattern = r"^[A-Za-z0-9_.-]{1,36}$")]
+            pub tag: String,
+            #[validate(custom(super::super::shared_dir_validation))]
+            pub path: String,
+            #[serde(default)]
+            pub read_only: bool,
+            #[serde(default)]
+            pub cache: CacheMode,
+        }
+
+        /// How much of the shared directory the guest caches, see the
+        /// virtiofsd `--cache` option.
+        #[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+        pub enum CacheMode {
+            #[default]
+            Auto,
+            Always,
+            Never,
+        }
+
+        /// The observed state of a vm, maintained by the unit server.
+        #[vmm_entity_struct]
+        #[derive(Clone, PartialEq)]
+        pub struct VirtualMachineStatus {
+            pub phase: Phase,
+            #[serde(default, skip_serializing_if = "Vec::is_empty")]
+            pub conditions: Vec<Condition>,
+            /// The taps of the interfaces, in the same order.
+            #[serde(default)]
+            pub tap_names: Vec<String>,
+            pub unit_active_state: Option<String>,
+            pub pid: Option<u32>,
+            pub boot_time: Option<String>,
+            pub last_reconcile_error: Option<String>,
+            /// The run state last requested through the lifecycle actions.
+            pub desired_state: Option<RunState>,
+            /// The hash of the spec the running vm was booted with.
+            pub spec_hash: Option<String>,
+            /// The interfaces as reported by the guest agent.
+            #[serde(default, skip_serializing_if = "Vec::is_empty")]
+            pub guest_interfaces: Vec<GuestInterface>,
+        }
+
+        #[vmm_entity_struct]
+        #[derive(Clone, PartialEq)]
+        pub struct GuestInterface {
+            pub name: String,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub mac: Option<String>,
+            /// In the `address/prefix` form.
+            #[serde(default)]
+            pub addresses: Vec<String>,
+        }
+    }
+    pub mod v1alpha5 {
+        use serde_json::value::Value;
+        use vmm_entity::{vmm_entity, vmm_entity_struct};
+
+        use super::get_migrator;
+
+        pub use super::v1alpha4::{
+            Boot, CacheMode, CloudInit, Condition, Filesystem, GuestInterface, Interface, Phase,
+            RestartPolicy, RunState, RunStrategy, VirtualMachineStatus,
+        };
+
+        use crate::database::{
+            entity::{Entity, MigratableEntity},
+            error::Error,
+        };
+
+        #[vmm_entity("v1alpha5", "get_migrator")]
+        pub struct VirtualMachine {
+            #[validate]
+            pub spec: VirtualMachineSpec,
+            #[serde(default)]
+            pub status: VirtualMachineStatus,
+        }
+
+        impl MigratableEntity for VirtualMachine {
+            fn migrate(_entity: Value) -> Result<Value, Error> {
+                Err(Error::NoMigrationAvailable {
+                    kind: Self::KIND,
+                    version: Self::API_VERSION,
+                })
+            }
+        }
+
+        #[vmm_entity_struct]
+        pub struct VirtualMachineSpec {
+            #[validate(minimum = 1)]
+            pub cpus: u8,
+            #[validate(pattern = r"^\d+(M|G)$")]
+            pub memory: String,
+            /// The vcpus the running vm can be resized up to, defaults to cpus.
+            #[validate(minimum = 1)]
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub max_cpus: Option<u8>,
+            /// The memory the running vm can be resized up to, defaults to memory.
+            #[validate(pattern = r"^\d+(M|G)$")]
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub max_memory: Option<String>,
+            #[validate]
+            pub disks: Vec<Disk>,
+            /// What the vm boots, the daemon default firmware when not set.
+            #[validate]
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub boot: Option<Boot>,
+            /// Served to the guest as a cloud-init NoCloud seed disk.
+            #[validate]
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub cloud_init: Option<CloudInit>,
+            /// The network interfaces, in the order the guest sees them.
+            #[validate(min_items = 1)]
+            #[validate]
+            pub interfaces: Vec<Interface>,
+            /// The host directories shared with the guest through virtio-fs.
+            #[validate]
+            #[serde(default, skip_serializing_if = "Vec::is_empty")]
+            pub filesystems: Vec<Filesystem>,
+            #[serde(default)]
+            pub run_strategy: RunStrategy,
+            #[serde(default)]
+            pub restart_policy: RestartPolicy,
+        }
+
+        /// A disk image, a volume, or a vhost-user-blk backend listening on
+        /// a socket.
+        #[vmm_entity_struct]
+        #[derive(Clone, PartialEq)]
+        pub struct Disk {
+            #[validate(custom(super::super::optional_disk_path_validation))]
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub path: Option<String>,
+            /// The name of a volume.
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub volume: Option<String>,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub vhost_user_socket: Option<String>,
+            #[serde(default)]
+            pub readonly: bool,
+            /// Opens the image with O_DIRECT, bypassing the host page cache.
+            #[serde(default)]
+            pub direct: bool,
+            /// The id of the disk device in cloud-hypervisor, in place of the
+            /// one derived from the path. The guest doesn't see it: the
+            /// `DiskConfig` of cloud-hypervisor v28.1 has neither a serial
+            /// nor an image type to set, the format is probed from the image.
+            #[validate(pattern = r"^[A-Za-z0-9][A-Za-z0-9_-]{0,19}$")]
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub id: Option<String>,
+            /// The virtio queues, one per vcpu is a good start.
+            #[validate(minimum = 1)]
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub queues: Option<usize>,
+            #[validate]
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub rate_limiter: Option<RateLimiter>,
+        }
+
+        #[vmm_entity_struct]
+        #[derive(Clone, PartialEq)]
+        pub struct RateLimiter {
+            /// In bytes.
+            #[validate]
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub bandwidth: Option<TokenBucket>,
+            /// In I/O operations.
+            #[validate]
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub ops: Option<TokenBucket>,
+        }
+
+        /// Allows `size` tokens every `refillTime` milliseconds, on top of a
+        /// first burst of `oneTimeBurst` tokens.
+        #[vmm_entity_struct]
+        #[derive(Clone, PartialEq)]
+        pub struct TokenBucket {
+            #[validate(minimum = 1)]
+            pub size: u64,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub one_time_burst: Option<u64>,
+            #[validate(minimum = 1)]
+            pub refill_time: u64,
         }
     }
 }
 
+/// Hashes the spec fields that only take effect when the vm boots. The cpus,
+/// memory and disks are left out since they are applied to the running vm,
+/// and so are the run strategy and the restart policy.
+pub fn spec_hash(vm: &VirtualMachine) -> Result<String, serde_json::Error> {
+    let mut spec = serde_json::to_value(&vm.spec)?;
+    if let Some(spec) = spec.as_object_mut() {
+        for field in ["cpus", "memory", "disks", "runStrategy", "restartPolicy"] {
+            spec.remove(field);
+        }
+
+        // a single interface hashes like the fields it replaced, so that the
+        // vms booted before the interfaces list aren't restarted
+        if let [interface @ Interface { mtu: None, .. }] = &vm.spec.interfaces[..] {
+            spec.remove("interfaces");
+            spec.insert("bridge".into(), interface.bridge.clone().into());
+            spec.insert("mac".into(), interface.mac.clone().into());
+            if let Some(ip) = &interface.ip {
+                spec.insert("ip".into(), ip.clone().into());
+            }
+        }
+    }
+
+    hash(&spec)
+}
+
+/// Hashes the whole spec but the run strategy and the restart policy, a
+/// snapshot is only restored onto the spec it was taken with.
+pub fn full_spec_hash(vm: &VirtualMachine) -> Result<String, serde_json::Error> {
+    let mut spec = serde_json::to_value(&vm.spec)?;
+    if let Some(spec) = spec.as_object_mut() {
+        for field in ["runStrategy", "restartPolicy"] {
+            spec.remove(field);
+        }
+    }
+
+    hash(&spec)
+}
+
+fn hash(spec: &serde_json::Value) -> Result<String, serde_json::Error> {
+    let digest = ring::digest::digest(&ring::digest::SHA256, &serde_json::to_vec(spec)?);
+    Ok(data_encoding::HEXLOWER.encode(&digest.as_ref()[..8]))
+}
+
+/// Converts the spec memory, like `512M` or `2G`, to bytes.
+pub fn memory_bytes(memory: &str) -> Result<u64, byte_unit::ByteError> {
+    Ok(byte_unit::Byte::from_str(format!("{}iB", memory))?.get_bytes() as u64)
+}
+
 fn disks_path_validation(paths: &Vec<String>) -> Result<(), serde_valid::validation::Error> {
     for path_str in paths {
         let path = std::path::PathBuf::from(path_str);
@@ -188,10 +699,58 @@ fn disks_path_validation(paths: &Vec<String>) -> Result<(), serde_valid::validat
     Ok(())
 }
 
+fn optional_disk_path_validation(
+    path: &Option<String>,
+) -> Result<(), serde_valid::validation::Error> {
+    match path {
+        Some(path) => disk_path_validation(path),
+        None => Ok(()),
+    }
+}
+
 fn disk_path_validation(path_str: &str) -> Result<(), serde_valid::validation::Error> {
     disks_path_validation(&vec![path_str.into()])
 }
 
+fn shared_dir_validation(path: &str) -> Result<(), serde_valid::validation::Error> {
+    let dir = std::path::Path::new(path);
+    if !dir.is_absolute() || !dir.is_dir() {
+        return Err(serde_valid::validation::Error::Custom(format!(
+            "shared path `{}` isn't an existing absolute directory",
+            path
+        )));
+    }
+    // the path ends up on the exec line of the virtiofsd unit
+    if path.chars().any(char::is_control) {
+        return Err(serde_valid::validation::Error::Custom(format!(
+            "shared path `{}` contains control characters",
+            path.escape_default()
+        )));
+    }
+    Ok(())
+}
+
+fn boot_path_validation(path: &Option<String>) -> Result<(), serde_valid::validation::Error> {
+    match path {
+        Some(path) if !std::path::Path::new(path).exists() => Err(
+            serde_valid::validation::Error::Custom(format!("boot path `{}` doesn't exist", path)),
+        ),
+        _ => Ok(()),
+    }
+}
+
+fn yaml_mapping_validation(yaml: &Option<String>) -> Result<(), serde_valid::validation::Error> {
+    match yaml
+        .as_deref()
+        .map(serde_yaml::from_str::<serde_yaml::Mapping>)
+    {
+        Some(Err(e)) => Err(serde_valid::validation::Error::Custom(format!(
+            "not a yaml mapping: {e}"
+        ))),
+        _ => Ok(()),
+    }
+}
+
 fn generate_default_mac() -> String {
     let mut data = [0u8; 6];
     rand::thread_rng().fill_bytes(&mut data);
@@ -201,3 +760,137 @@ fn generate_default_mac() -> String {
         data[1] as usize, data[2] as usize, data[3] as usize, data[4] as usize, data[5] as usize
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::database::{entity::Entity, migration::migrate_kind};
+
+    fn disk() -> String {
+        std::env::current_exe().unwrap().to_string_lossy().into()
+    }
+
+    fn migrate(entity: Value) -> VirtualMachine {
+        let migrated = migrate_kind(VirtualMachine::KIND, entity).unwrap();
+        serde_json::from_value(migrated).unwrap()
+    }
+
+    #[test]
+    fn migrates_a_v1alpha1_vm_without_status() {
+        let vm = migrate(json!({
+            "apiVersion": "v1alpha1",
+            "kind": "VirtualMachine",
+            "metadata": { "name": "vm1" },
+            "spec": {
+                "cpus": 1,
+                "memory": "512M",
+                "disk": disk(),
+                "ip": "10.0.0.2",
+                "mac": "02:00:00:00:00:01",
+            },
+        }));
+
+        assert_eq!(vm.metadata.name, "vm1");
+        assert_eq!(vm.spec.interfaces.len(), 1);
+        assert_eq!(vm.spec.interfaces[0].bridge, "tvbr0");
+        assert_eq!(vm.spec.interfaces[0].ip.as_deref(), Some("10.0.0.2"));
+        assert_eq!(vm.spec.disks.len(), 1);
+        assert_eq!(vm.spec.disks[0].path, Some(disk()));
+        assert_eq!(vm.status.phase, Phase::Pending);
+    }
+
+    #[test]
+    fn migrates_a_v1alpha3_vm_without_status() {
+        let vm = migrate(json!({
+            "apiVersion": "v1alpha3",
+            "kind": "VirtualMachine",
+            "metadata": { "name": "vm1" },
+            "spec": {
+                "cpus": 1,
+                "memory": "512M",
+                "disks": [disk()],
+                "mac": "02:00:00:00:00:01",
+                "bridge": "br0",
+            },
+        }));
+
+        assert_eq!(vm.spec.interfaces[0].bridge, "br0");
+        assert_eq!(vm.status.phase, Phase::Pending);
+    }
+
+    #[test]
+    fn migrates_a_v1alpha3_vm_with_status() {
+        let vm = migrate(json!({
+            "apiVersion": "v1alpha3",
+            "kind": "VirtualMachine",
+            "metadata": { "name": "vm1" },
+            "spec": {
+                "cpus": 1,
+                "memory": "512M",
+                "disks": [disk()],
+                "mac": "02:00:00:00:00:01",
+                "bridge": "br0",
+            },
+            "status": {
+                "phase": "Running",
+                "tapName": "vmivm1abcde",
+                "specHash": "0123456789abcdef",
+            },
+        }));
+
+        assert_eq!(vm.status.phase, Phase::Running);
+        assert_eq!(vm.status.spec_hash.as_deref(), Some("0123456789abcdef"));
+    }
+
+    #[test]
+    fn migrates_the_disks_of_a_v1alpha4_vm() {
+        let vm = migrate(json!({
+            "apiVersion": "v1alpha4",
+            "kind": "VirtualMachine",
+            "metadata": { "name": "vm1" },
+            "spec": {
+                "cpus": 1,
+                "memory": "512M",
+                "disks": [disk(), disk()],
+                "interfaces": [{ "bridge": "br0", "mac": "02:00:00:00:00:01" }],
+            },
+            "status": { "phase": "Running" },
+        }));
+
+        assert_eq!(vm.spec.disks.len(), 2);
+        for disk_spec in &vm.spec.disks {
+            assert_eq!(disk_spec.path, Some(disk()));
+            assert_eq!(disk_spec.volume, None);
+            assert!(!disk_spec.readonly && !disk_spec.direct);
+            assert_eq!(disk_spec.id, None);
+        }
+        assert_eq!(vm.status.phase, Phase::Running);
+    }
+
+    #[test]
+    fn full_spec_hash_covers_the_hotplugged_fields() {
+        let mut vm = migrate(json!({
+            "apiVersion": "v1alpha3",
+            "kind": "VirtualMachine",
+            "metadata": { "name": "vm1" },
+            "spec": {
+                "cpus": 1,
+                "memory": "512M",
+                "disks": [disk()],
+                "mac": "02:00:00:00:00:01",
+                "bridge": "br0",
+            },
+        }));
+        let (boot_hash, full_hash) = (spec_hash(&vm).unwrap(), full_spec_hash(&vm).unwrap());
+
+        vm.spec.cpus = 2;
+        assert_eq!(spec_hash(&vm).unwrap(), boot_hash);
+        assert_ne!(full_spec_hash(&vm).unwrap(), full_hash);
+
+        vm.spec.cpus = 1;
+        vm.spec.run_strategy = RunStrategy::Manual;
+        assert_eq!(full_spec_hash(&vm).unwrap(), full_hash);
+    }
+}
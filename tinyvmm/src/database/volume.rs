@@ -0,0 +1,156 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use serde_json::value::Value;
+use vmm_entity::{vmm_entity, vmm_entity_struct};
+
+use super::{
+    entity::{Entity, MigratableEntity},
+    error::Error,
+    store::Store,
+    virtual_machine::VirtualMachine,
+};
+
+/// Where the volumes are kept unless their spec says otherwise.
+pub const DEFAULT_STORAGE_DIR: &str = "/var/lib/tinyvmm/volumes";
+
+pub fn get_migrator(version: &str) -> Option<fn(Value) -> Result<Value, Error>> {
+    match version {
+        "v1alpha1" => Some(Volume::migrate),
+        _ => None,
+    }
+}
+
+/// A disk image managed by tinyvmm, attached to the vms by name.
+#[vmm_entity("v1alpha1", "get_migrator")]
+pub struct Volume {
+    #[validate]
+    pub spec: VolumeSpec,
+    #[serde(default)]
+    pub status: VolumeStatus,
+}
+impl MigratableEntity for Volume {}
+
+#[vmm_entity_struct]
+pub struct VolumeSpec {
+    /// The size the guest sees, it can grow but not shrink. The image is only
+    /// grown once the vms using it are stopped.
+    #[validate(pattern = r"^\d+(M|G|T)$")]
+    pub size: String,
+    #[serde(default)]
+    pub format: VolumeFormat,
+    #[validate(custom(storage_dir_validation))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage_dir: Option<String>,
+    /// An image the volume starts as a copy of.
+    #[validate(custom(source_validation))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// An image kind the volume is created from, instead of a source.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    #[serde(default)]
+    pub clone: CloneStrategy,
+}
+
+/// How a volume is created from its image.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CloneStrategy {
+    /// A copy of the image, sharing its blocks when the filesystem can.
+    #[default]
+    Reflink,
+    /// A qcow2 image with the image as its backing file.
+    Overlay,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum VolumeFormat {
+    #[default]
+    Raw,
+    Qcow2,
+}
+
+/// The observed state of a volume, maintained by the unit server.
+#[vmm_entity_struct]
+#[derive(Clone, PartialEq)]
+pub struct VolumeStatus {
+    pub phase: VolumePhase,
+    /// The image file, once it's created.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// The size of the image, in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum VolumePhase {
+    #[default]
+    Pending,
+    Ready,
+    Failed,
+}
+
+/// Where the image of the volume lives, whether it exists yet or not.
+pub fn volume_path(volume: &Volume) -> PathBuf {
+    let extension = match volume.spec.format {
+        VolumeFormat::Raw => "raw",
+        VolumeFormat::Qcow2 => "qcow2",
+    };
+
+    PathBuf::from(
+        volume
+            .spec
+            .storage_dir
+            .as_deref()
+            .unwrap_or(DEFAULT_STORAGE_DIR),
+    )
+    .join(format!("{}.{}", volume.metadata.name, extension))
+}
+
+/// The names of the vms with a disk on the volume.
+pub fn users(store: &Store, name: &str) -> Result<Vec<String>, Error> {
+    Ok(VirtualMachine::list(store)?
+        .into_iter()
+        .filter(|vm| {
+            vm.spec
+                .disks
+                .iter()
+                .any(|disk| disk.volume.as_deref() == Some(name))
+        })
+        .map(|vm| vm.metadata.name)
+        .collect())
+}
+
+/// Converts the spec size, like `20G`, to bytes.
+pub fn size_bytes(size: &str) -> Result<u64, byte_unit::ByteError> {
+    Ok(byte_unit::Byte::from_str(format!("{}iB", size))?.get_bytes() as u64)
+}
+
+fn storage_dir_validation(dir: &Option<String>) -> Result<(), serde_valid::validation::Error> {
+    match dir {
+        Some(dir) if !std::path::Path::new(dir).is_absolute() => {
+            Err(serde_valid::validation::Error::Custom(format!(
+                "storage dir `{}` isn't an absolute path",
+                dir
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn source_validation(source: &Option<String>) -> Result<(), serde_valid::validation::Error> {
+    match source {
+        Some(source) if !std::path::Path::new(source).is_file() => {
+            Err(serde_valid::validation::Error::Custom(format!(
+                "source image `{}` doesn't exist",
+                source
+            )))
+        }
+        _ => Ok(()),
+    }
+}
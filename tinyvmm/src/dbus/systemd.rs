@@ -8,4 +8,27 @@ use zbus::dbus_proxy;
 pub trait Systemd {
     fn load_unit(&self, name: &str) -> zbus::Result<zvariant::OwnedObjectPath>;
     fn start_unit(&self, name: &str, mode: &str) -> zbus::Result<zvariant::OwnedObjectPath>;
+    fn stop_unit(&self, name: &str, mode: &str) -> zbus::Result<zvariant::OwnedObjectPath>;
+    fn restart_unit(&self, name: &str, mode: &str) -> zbus::Result<zvariant::OwnedObjectPath>;
+    fn reload(&self) -> zbus::Result<()>;
+}
+
+#[dbus_proxy(
+    interface = "org.freedesktop.systemd1.Unit",
+    default_service = "org.freedesktop.systemd1"
+)]
+pub trait SystemdUnit {
+    #[dbus_proxy(property)]
+    fn active_state(&self) -> zbus::Result<String>;
+    #[dbus_proxy(property)]
+    fn active_enter_timestamp(&self) -> zbus::Result<u64>;
+}
+
+#[dbus_proxy(
+    interface = "org.freedesktop.systemd1.Service",
+    default_service = "org.freedesktop.systemd1"
+)]
+pub trait SystemdService {
+    #[dbus_proxy(property, name = "MainPID")]
+    fn main_pid(&self) -> zbus::Result<u32>;
 }
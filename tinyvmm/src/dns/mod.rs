@@ -1,7 +1,8 @@
 mod error;
 
 use eyre::Context;
-use log::{debug, error, info};
+use futures::StreamExt;
+use log::{debug, error, info, warn};
 use std::{
     collections::HashMap,
     net::{self, SocketAddr},
@@ -70,13 +71,12 @@ impl DnsHandler {
                 let _err = DnsHandler::reconcile(&store, &mut authority_map, catalog.clone()).await;
             }
 
-            while let Some(event) = (&mut subscriber).await {
-                debug!(
-                    "dns reconciler event: {}",
-                    String::from_utf8(event.key().to_ascii_lowercase())
-                        .or_else(|_| Ok::<String, ()>("?".into()))
-                        .unwrap()
-                );
+            while let Some(event) = subscriber.next().await {
+                match event {
+                    Ok(event) => debug!("dns reconciler event: {}", event.key()),
+                    // the zones are rebuilt from scratch anyway
+                    Err(e) => warn!("dns reconciler resync: {}", e),
+                }
                 let mut authority_map = authority.lock().await;
                 let err = DnsHandler::reconcile(&store, &mut authority_map, catalog.clone()).await;
                 if let Err(err) = err {
@@ -94,47 +94,78 @@ impl DnsHandler {
         let bridges = Bridge::list(store)?;
         let vms = VirtualMachine::list(store)?;
 
-        for bridge in bridges {
-            let vms = vms
-                .iter()
-                .filter(|vm| vm.spec.bridge == bridge.metadata.name);
-
-            let origin = Name::from_str(&bridge.spec.dns_zone)?;
-            let entry = authority
-                .entry(bridge.spec.dns_zone.clone())
-                .or_insert_with(|| {
-                    Arc::new(InMemoryAuthority::empty(
-                        origin.clone(),
-                        ZoneType::Primary,
-                        false,
-                    ))
-                });
-
-            {
-                let mut catalog = catalog.lock().await;
-                catalog.upsert(origin.into(), Box::new(entry.clone()));
-            }
+        let mut zones = HashMap::new();
+        for (zone_name, records) in zone_records(&bridges, &vms) {
+            let origin = Name::from_str(&zone_name)?;
+            let zone = InMemoryAuthority::empty(origin, ZoneType::Primary, false);
 
-            for vm in vms {
-                let name =
-                    Name::from_str(&format!("{}.{}", vm.metadata.name, bridge.spec.dns_zone))?;
+            for (name, ip) in records {
                 let record = Record::from_rdata(
-                    name.clone(),
+                    Name::from_str(&name)?,
                     500,
-                    rr::RData::A(net::Ipv4Addr::from_str(&vm.spec.ip)?),
+                    rr::RData::A(net::Ipv4Addr::from_str(&ip)?),
                 );
 
-                entry.upsert(record, 0).await;
-                // TODO: how do I even clean those?..
+                zone.upsert(record, 0).await;
             }
 
-            debug!("updated dns zone for {}", bridge.spec.dns_zone);
+            zones.insert(zone_name, Arc::new(zone));
         }
 
+        {
+            let mut catalog = catalog.lock().await;
+            for zone in authority.keys() {
+                if !zones.contains_key(zone) {
+                    catalog.remove(&Name::from_str(zone)?.into());
+                    debug!("removed dns zone {}", zone);
+                }
+            }
+            for (zone, entry) in zones.iter() {
+                catalog.upsert(Name::from_str(zone)?.into(), Box::new(entry.clone()));
+                debug!("updated dns zone for {}", zone);
+            }
+        }
+        *authority = zones;
+
         Ok(())
     }
 }
 
+/// The names and addresses of the vms in the zone of each of their bridges.
+/// The zones are rebuilt from scratch on every change, so that the records of
+/// the vms and bridges are dropped as soon as their deletion starts, without
+/// holding it up when the dns server isn't running.
+fn zone_records(
+    bridges: &[Bridge],
+    vms: &[VirtualMachine],
+) -> HashMap<String, Vec<(String, String)>> {
+    let mut zones = HashMap::new();
+
+    for bridge in bridges {
+        if bridge.metadata.deletion_timestamp.is_some() {
+            continue;
+        }
+
+        let records: &mut Vec<_> = zones.entry(bridge.spec.dns_zone.clone()).or_default();
+        for vm in vms {
+            if vm.metadata.deletion_timestamp.is_some() {
+                continue;
+            }
+            let name = format!("{}.{}", vm.metadata.name, bridge.spec.dns_zone);
+            records.extend(
+                vm.spec
+                    .interfaces
+                    .iter()
+                    .filter(|interface| interface.bridge == bridge.metadata.name)
+                    .filter_map(|interface| interface.ip.clone())
+                    .map(|ip| (name.clone(), ip)),
+            );
+        }
+    }
+
+    zones
+}
+
 pub async fn run_server(
     addr: SocketAddr,
     store: Store,
@@ -162,3 +193,46 @@ pub async fn run_server(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::zone_records;
+    use crate::fixtures::{test_bridge, test_vm};
+
+    #[test]
+    fn names_the_vms_in_the_zone_of_each_bridge() {
+        let mut gone = test_bridge("br2", json!({ "dnsZone": "gone.local" }));
+        gone.metadata.deletion_timestamp = Some("2023-01-01T00:00:00Z".into());
+        let bridges = [
+            test_bridge("br0", json!({})),
+            test_bridge(
+                "br1",
+                json!({ "address": "10.1.0.1/24", "dnsZone": "data.local" }),
+            ),
+            gone,
+        ];
+        let vms = [test_vm(
+            "vm1",
+            json!({
+                "interfaces": [
+                    { "bridge": "br0", "mac": "02:00:00:00:00:01", "ip": "10.0.0.2" },
+                    { "bridge": "br1", "mac": "02:00:00:00:01:01", "ip": "10.1.0.2" },
+                    { "bridge": "br2", "mac": "02:00:00:00:02:01", "ip": "10.2.0.2" },
+                ],
+            }),
+        )];
+
+        let zones = zone_records(&bridges, &vms);
+        assert_eq!(zones.len(), 2);
+        assert_eq!(
+            zones["vms.local"],
+            [("vm1.vms.local".into(), "10.0.0.2".into())]
+        );
+        assert_eq!(
+            zones["data.local"],
+            [("vm1.data.local".into(), "10.1.0.2".into())]
+        );
+    }
+}
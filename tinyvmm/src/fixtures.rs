@@ -0,0 +1,68 @@
+//! The entities and the scratch dirs the tests start from.
+
+use std::path::PathBuf;
+
+use serde_json::{json, Value};
+
+use crate::database::{
+    bridge::Bridge, entity::Entity, image::Image, snapshot::Snapshot,
+    virtual_machine::VirtualMachine,
+};
+
+/// The entity of the kind at its latest version, with the given spec fields
+/// over the defaults.
+fn entity<E: Entity>(name: &str, defaults: Value, spec: Value) -> E::Type {
+    let mut entity = json!({
+        "apiVersion": E::API_VERSION,
+        "kind": E::KIND,
+        "metadata": { "name": name },
+        "spec": defaults,
+    });
+    for (field, value) in spec.as_object().cloned().unwrap_or_default() {
+        entity["spec"][field] = value;
+    }
+
+    serde_json::from_value(entity).unwrap()
+}
+
+/// A vm with two vcpus and 1G of memory at most on br0.
+pub fn test_vm(name: &str, spec: Value) -> VirtualMachine {
+    let defaults = json!({
+        "cpus": 1,
+        "memory": "512M",
+        "maxCpus": 2,
+        "maxMemory": "1G",
+        "disks": [{ "path": format!("/var/lib/{name}/root.raw") }],
+        "interfaces": [{ "bridge": "br0", "mac": "02:00:00:00:00:01" }],
+    });
+
+    entity::<VirtualMachine>(name, defaults, spec)
+}
+
+/// A bridge on 10.0.0.1/24 serving the vms.local zone.
+pub fn test_bridge(name: &str, spec: Value) -> Bridge {
+    let defaults = json!({
+        "address": "10.0.0.1/24",
+        "dnsZone": "vms.local",
+        "dnsServer": "10.0.0.1",
+    });
+
+    entity::<Bridge>(name, defaults, spec)
+}
+
+pub fn test_image(name: &str, spec: Value) -> Image {
+    entity::<Image>(name, json!({}), spec)
+}
+
+pub fn test_snapshot(name: &str, spec: Value) -> Snapshot {
+    entity::<Snapshot>(name, json!({ "vm": "vm1" }), spec)
+}
+
+/// An empty dir of the test in the temp dir.
+pub fn scratch_dir(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("tinyvmm-{}-{}", test, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    dir
+}
@@ -5,6 +5,8 @@ pub mod client;
 mod database;
 mod dbus;
 mod dns;
+#[cfg(test)]
+mod fixtures;
 mod systemd;
 mod unitserver;
 
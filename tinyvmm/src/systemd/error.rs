@@ -12,6 +12,9 @@ pub enum SystemdUnitCreationError {
     #[error("failed to read the unit file: {0}")]
     CannotReadUnitFile(#[source] io::Error),
 
+    #[error("failed to list the unit files: {0}")]
+    CannotListUnitFiles(#[source] io::Error),
+
     #[error("failed to remove the unit file {0}: {1}")]
     CannotRemoveUnitFile(PathBuf, #[source] io::Error),
 
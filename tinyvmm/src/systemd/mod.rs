@@ -2,5 +2,14 @@ pub(crate) mod error;
 mod networkd;
 mod service;
 
+use handlebars::Handlebars;
+
 pub use networkd::*;
 pub use service::*;
+
+/// The units aren't html, so their values are rendered as they are.
+fn unit_templates() -> Handlebars<'static> {
+    let mut handlebars = Handlebars::new();
+    handlebars.register_escape_fn(handlebars::no_escape);
+    handlebars
+}
@@ -1,14 +1,13 @@
-use handlebars::Handlebars;
 use indoc::indoc;
 use serde::Serialize;
 use serde_json::json;
 
-use crate::systemd::error::SystemdUnitCreationError::{self};
+use crate::systemd::{error::SystemdUnitCreationError, unit_templates};
 
 use super::create_and_start_unit;
 
 pub async fn create_bridge(name: &str) -> Result<(), SystemdUnitCreationError> {
-    let ini = Handlebars::new().render_template(
+    let ini = unit_templates().render_template(
         indoc! {"
             [NetDev]
             Name={{name}}
@@ -36,7 +35,7 @@ pub async fn create_bridge_network(
     dns_server: &str,
     leases: Vec<Lease>,
 ) -> Result<(), SystemdUnitCreationError> {
-    let ini = Handlebars::new().render_template(
+    let ini = unit_templates().render_template(
         indoc! {"
             [Match]
             Name={{name}}
@@ -1,17 +1,17 @@
-use handlebars::Handlebars;
 use indoc::indoc;
 use serde_json::json;
 
 use super::create_and_start_unit;
 
-use crate::systemd::error::SystemdUnitCreationError;
+use crate::systemd::{error::SystemdUnitCreationError, unit_templates};
 
 pub async fn create_tap_network(
     name: &str,
     bridge: &str,
     mac: &str,
+    mtu: Option<u16>,
 ) -> Result<(), SystemdUnitCreationError> {
-    let ini = Handlebars::new().render_template(
+    let ini = unit_templates().render_template(
         indoc! {"
             [Match]
             Name={{name}}
@@ -21,11 +21,15 @@ pub async fn create_tap_network(
 
             [Link]
             MACAddress={{mac}}
+            {{#if mtu}}
+            MTUBytes={{mtu}}
+            {{/if}}
         "},
         &json!({
             "name": name,
             "bridge": bridge,
             "mac": vm_mac_to_tap_mac(mac),
+            "mtu": mtu,
         }),
     )?;
 
@@ -33,7 +37,7 @@ pub async fn create_tap_network(
 }
 
 pub async fn create_tap(name: &str, mac: &str) -> Result<(), SystemdUnitCreationError> {
-    let ini = Handlebars::new().render_template(
+    let ini = unit_templates().render_template(
         indoc! {"
             [NetDev]
             Name={{name}}
@@ -1,6 +1,6 @@
-use handlebars::Handlebars;
 use indoc::indoc;
 use log::trace;
+use serde::Serialize;
 use serde_json::json;
 use std::{
     collections::HashMap,
@@ -9,12 +9,37 @@ use std::{
 };
 use zbus::Connection;
 
-use super::error::SystemdUnitCreationError;
+use super::{error::SystemdUnitCreationError, unit_templates};
 
-use crate::dbus::systemd::SystemdProxy;
+use crate::dbus::systemd::{SystemdProxy, SystemdServiceProxy, SystemdUnitProxy};
 
 const RUNTIME_NETWORK_DIR: &str = "/run/systemd/system";
 
+const RUNTIME_DIR: &str = "/run";
+
+/// The virtiofsd serving the shared directories, unless the daemon is told
+/// otherwise.
+pub const DEFAULT_VIRTIOFSD: &str = "/run/current-system/sw/bin/virtiofsd";
+
+/// A host directory shared with the vm by its own virtiofsd.
+#[derive(Serialize, Debug)]
+pub struct SharedDir {
+    pub path: String,
+    pub read_only: bool,
+    /// One of the virtiofsd cache modes.
+    pub cache: String,
+}
+
+/// Escapes the value for a double quoted argument of an exec line, where
+/// systemd would otherwise expand the specifiers and the variables.
+fn escape_argument(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$")
+}
+
 fn get_unit_path(name: &str) -> PathBuf {
     let unit_name = format!("{}.service", name);
 
@@ -42,11 +67,23 @@ async fn create_and_start_systemd_unit(
 
 pub async fn create_vm_service(
     name: &str,
-    bridge_name: &str,
+    bridges: &[&str],
+    shared_dirs: &[SharedDir],
     self_exe: &str,
     api_server: &str,
+    default_firmware: &str,
+    virtiofsd: &str,
 ) -> Result<(), SystemdUnitCreationError> {
-    let units = generate_vm_service(name, bridge_name, self_exe, api_server).await?;
+    let units = generate_vm_service(
+        name,
+        bridges,
+        shared_dirs,
+        self_exe,
+        api_server,
+        default_firmware,
+        virtiofsd,
+    )
+    .await?;
     for (name, config) in units {
         create_and_start_systemd_unit(&name, &config).await?;
     }
@@ -65,13 +102,139 @@ pub async fn start_service(name: &str) -> Result<(), SystemdUnitCreationError> {
     Ok(())
 }
 
+/// Runtime facts about a vm service as seen by systemd.
+#[derive(Debug)]
+pub struct ServiceState {
+    pub active_state: String,
+    pub main_pid: u32,
+    /// Microseconds since the epoch, 0 if the unit was never active.
+    pub active_enter_timestamp: u64,
+}
+
+pub async fn get_service_state(name: &str) -> Result<ServiceState, SystemdUnitCreationError> {
+    let connection = Connection::system().await?;
+    let proxy = SystemdProxy::new(&connection).await?;
+
+    let unit_name = format!("{}.service", get_systemd_unit_name(name));
+    let path = proxy.load_unit(&unit_name).await?;
+
+    let unit = SystemdUnitProxy::builder(&connection)
+        .path(path.clone())?
+        .build()
+        .await?;
+    let service = SystemdServiceProxy::builder(&connection)
+        .path(path)?
+        .build()
+        .await?;
+
+    Ok(ServiceState {
+        active_state: unit.active_state().await?,
+        main_pid: service.main_pid().await?,
+        active_enter_timestamp: unit.active_enter_timestamp().await?,
+    })
+}
+
+pub async fn stop_service(name: &str) -> Result<(), SystemdUnitCreationError> {
+    let connection = Connection::system().await?;
+    let proxy = SystemdProxy::new(&connection).await?;
+
+    let unit_name = format!("{}.service", get_systemd_unit_name(name));
+    trace!("stopping {}", unit_name);
+    proxy.stop_unit(&unit_name, "replace").await?;
+
+    Ok(())
+}
+
+pub async fn restart_service(name: &str) -> Result<(), SystemdUnitCreationError> {
+    let connection = Connection::system().await?;
+    let proxy = SystemdProxy::new(&connection).await?;
+
+    let unit_name = format!("{}.service", get_systemd_unit_name(name));
+    trace!("restarting {}", unit_name);
+    proxy.restart_unit(&unit_name, "replace").await?;
+
+    Ok(())
+}
+
+/// Lists the names of all the runtime units created for the vms.
+pub fn list_vm_units() -> Result<Vec<String>, SystemdUnitCreationError> {
+    let runtime_dir = Path::new(RUNTIME_NETWORK_DIR);
+    if !runtime_dir.is_dir() {
+        return Ok(vec![]);
+    }
+
+    let mut units = vec![];
+    for entry in fs::read_dir(runtime_dir).map_err(SystemdUnitCreationError::CannotListUnitFiles)? {
+        let entry = entry.map_err(SystemdUnitCreationError::CannotListUnitFiles)?;
+        let file_name = entry.file_name();
+        let Some(unit) = file_name
+            .to_str()
+            .and_then(|f| f.strip_suffix(".service"))
+            .filter(|u| u.starts_with("tinyvmi-"))
+        else {
+            continue;
+        };
+        units.push(unit.to_string());
+    }
+
+    Ok(units)
+}
+
+/// Stops the runtime unit and removes its unit file.
+pub async fn remove_unit(unit: &str) -> Result<(), SystemdUnitCreationError> {
+    let path = get_unit_path(unit);
+    if !path.exists() {
+        return Ok(());
+    }
+
+    let connection = Connection::system().await?;
+    let proxy = SystemdProxy::new(&connection).await?;
+
+    let unit_name = format!("{}.service", unit);
+    trace!("stopping {}", unit_name);
+    proxy.stop_unit(&unit_name, "replace").await?;
+
+    fs::remove_file(&path).map_err(|e| SystemdUnitCreationError::CannotRemoveUnitFile(path, e))?;
+    proxy.reload().await?;
+
+    Ok(())
+}
+
+pub async fn remove_vm_service(
+    name: &str,
+    interfaces: usize,
+    filesystems: usize,
+) -> Result<(), SystemdUnitCreationError> {
+    for interface in 0..interfaces {
+        remove_unit(&get_systemd_tap_unit_name(name, interface)).await?;
+    }
+    for filesystem in 0..filesystems {
+        remove_unit(&get_systemd_fs_unit_name(name, filesystem)).await?;
+    }
+    remove_unit(&get_systemd_unit_name(name)).await?;
+
+    Ok(())
+}
+
 pub async fn has_diffs(
     name: &str,
-    bridge_name: &str,
+    bridges: &[&str],
+    shared_dirs: &[SharedDir],
     self_exe: &str,
     api_server: &str,
+    default_firmware: &str,
+    virtiofsd: &str,
 ) -> Result<bool, SystemdUnitCreationError> {
-    let units = generate_vm_service(name, bridge_name, self_exe, api_server).await?;
+    let units = generate_vm_service(
+        name,
+        bridges,
+        shared_dirs,
+        self_exe,
+        api_server,
+        default_firmware,
+        virtiofsd,
+    )
+    .await?;
     for (name, config) in units {
         let path = get_unit_path(&name);
         if !path.exists() {
@@ -87,75 +250,181 @@ pub async fn has_diffs(
     Ok(false)
 }
 
+/// Generates the vm service, one tap service per interface and one virtiofsd
+/// service per shared directory. The bridges are those of the interfaces in
+/// the same order. The restore env file turns the next start of the vm
+/// service into the restore of a snapshot.
 pub async fn generate_vm_service(
     name: &str,
-    bridge_name: &str,
+    bridges: &[&str],
+    shared_dirs: &[SharedDir],
     self_exe: &str,
     api_server: &str,
+    default_firmware: &str,
+    virtiofsd: &str,
 ) -> Result<HashMap<String, String>, SystemdUnitCreationError> {
     let mut res = HashMap::new();
 
-    let ini = Handlebars::new().render_template(
+    let interfaces: Vec<_> = bridges
+        .iter()
+        .enumerate()
+        .map(|(interface, bridge)| {
+            json!({
+                "netbr": bridge,
+                "netdev": get_systemd_tap_unit_name(name, interface),
+            })
+        })
+        .collect();
+    let filesystems: Vec<_> = (0..shared_dirs.len())
+        .map(|filesystem| get_systemd_fs_unit_name(name, filesystem))
+        .collect();
+
+    let ini = unit_templates().render_template(
         indoc! {r#"
             [Unit]
-            Requires=sys-subsystem-net-devices-{{netbr}}.device
-            Requires={{netdev}}.service
-            After=sys-subsystem-net-devices-{{netbr}}.device
-            After={{netdev}}.service
+            {{#each interfaces as |i|}}
+            Requires=sys-subsystem-net-devices-{{i.netbr}}.device
+            Requires={{i.netdev}}.service
+            After=sys-subsystem-net-devices-{{i.netbr}}.device
+            After={{i.netdev}}.service
+            {{/each}}
+            {{#each filesystems as |f|}}
+            Requires={{f}}.service
+            After={{f}}.service
+            {{/each}}
 
             [Service]
             Type=simple
-            ExecStart=/run/wrappers/bin/cloud-hypervisor --api-socket path=${RUNTIME_DIRECTORY}/api.sock
+            EnvironmentFile=-{{restore_env}}
+            ExecStartPre={{self_exe}} systemd --api-server {{api_server}} restore-disks {{name}} $SNAPSHOT
+            ExecStart=/run/wrappers/bin/cloud-hypervisor --api-socket path=${RUNTIME_DIRECTORY}/api.sock $RESTORE
 
-            ExecStartPost={{self_exe}} systemd --api-server {{api_server}} bootstrap-post {{name}}
+            ExecStartPost={{self_exe}} systemd --api-server {{api_server}} --default-firmware {{default_firmware}} bootstrap-post {{name}}
             ExecStartPost={{self_exe}} start {{name}}
 
             ExecStop={{self_exe}} stop {{name}}
 
-            RuntimeDirectory=tinyvmi-{{name}}
+            RuntimeDirectory={{unit}}
             "#},
         &json!({
             "name": name,
+            "unit": get_systemd_unit_name(name),
             "self_exe": format!("{}", std::fs::canonicalize(self_exe).unwrap().to_string_lossy()),
-            "netbr": bridge_name,
-            "netdev": get_systemd_tap_unit_name(name),
+            "interfaces": interfaces,
+            "filesystems": filesystems,
             "api_server": api_server,
+            "default_firmware": default_firmware,
+            "restore_env": crate::ch::snapshot::restore_env_path(name),
         }),
     )?;
 
     res.insert(get_systemd_unit_name(name), ini);
 
-    let ini = Handlebars::new().render_template(
-        indoc! {"
-            [Unit]
-            # Requires=sys-subsystem-net-devices-{{netbr}}.device
-            # After=sys-subsystem-net-devices-{{netbr}}.device
-            PartOf={{vmservice}}.service
+    for (interface, bridge) in bridges.iter().enumerate() {
+        let ini = unit_templates().render_template(
+            indoc! {"
+                [Unit]
+                # Requires=sys-subsystem-net-devices-{{netbr}}.device
+                # After=sys-subsystem-net-devices-{{netbr}}.device
+                PartOf={{vmservice}}.service
 
-            [Service]
-            Type=oneshot
-            RemainAfterExit=yes
-            ExecStart={{self_exe}} systemd --api-server {{api_server}} bootstrap-pre {{name}}
-            ExecStop={{self_exe}} systemd --api-server {{api_server}} teardown {{name}}
-            "},
-        &json!({
-            "name": name,
-            "self_exe": format!("{}", std::fs::canonicalize(self_exe).unwrap().to_string_lossy()),
-            "netbr": bridge_name,
-            "vmservice": get_systemd_unit_name(name),
-            "api_server": api_server,
-        }),
-    )?;
+                [Service]
+                Type=oneshot
+                RemainAfterExit=yes
+                ExecStart={{self_exe}} systemd --api-server {{api_server}} bootstrap-pre {{name}} {{interface}}
+                ExecStop={{self_exe}} systemd --api-server {{api_server}} teardown {{name}} {{interface}}
+                "},
+            &json!({
+                "name": name,
+                "interface": interface,
+                "self_exe": format!("{}", std::fs::canonicalize(self_exe).unwrap().to_string_lossy()),
+                "netbr": bridge,
+                "vmservice": get_systemd_unit_name(name),
+                "api_server": api_server,
+            }),
+        )?;
+
+        res.insert(get_systemd_tap_unit_name(name, interface), ini);
+    }
+
+    for (filesystem, shared_dir) in shared_dirs.iter().enumerate() {
+        // virtiofsd serves a single connection, so it's restarted with the vm
+        let ini = unit_templates().render_template(
+            indoc! {r#"
+                [Unit]
+                BindsTo={{vmservice}}.service
+                PartOf={{vmservice}}.service
+
+                [Service]
+                Type=simple
+                ExecStart={{virtiofsd}} --socket-path=${RUNTIME_DIRECTORY}/virtiofsd.sock "--shared-dir={{path}}" --cache={{dir.cache}}{{#if dir.read_only}} --readonly{{/if}}
 
-    res.insert(get_systemd_tap_unit_name(name), ini);
+                RuntimeDirectory={{unit}}
+                "#},
+            &json!({
+                "unit": get_systemd_fs_unit_name(name, filesystem),
+                "vmservice": get_systemd_unit_name(name),
+                "virtiofsd": virtiofsd,
+                "dir": shared_dir,
+                "path": escape_argument(&shared_dir.path),
+            }),
+        )?;
+
+        res.insert(get_systemd_fs_unit_name(name, filesystem), ini);
+    }
 
     Ok(res)
 }
 
+/// The runtime directory of the unit, the vm units name theirs after
+/// themselves.
+pub fn get_runtime_dir(unit: &str) -> PathBuf {
+    Path::new(RUNTIME_DIR).join(unit)
+}
+
 pub fn get_systemd_unit_name(name: &str) -> String {
     format!("tinyvmi-{}", name)
 }
 
-pub fn get_systemd_tap_unit_name(name: &str) -> String {
-    format!("tinyvmi-tap-{}", name)
+/// The first interface keeps the unit name from before the vms had several.
+pub fn get_systemd_tap_unit_name(name: &str, interface: usize) -> String {
+    match interface {
+        0 => format!("tinyvmi-tap-{}", name),
+        _ => format!("tinyvmi-tap{}-{}", interface, name),
+    }
+}
+
+pub fn get_systemd_fs_unit_name(name: &str, filesystem: usize) -> String {
+    format!("tinyvmi-fs{}-{}", filesystem, name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generate_vm_service, get_systemd_fs_unit_name, SharedDir};
+
+    #[tokio::test]
+    async fn passes_the_shared_dirs_as_they_are() {
+        let shared_dirs = [SharedDir {
+            path: r#"/srv/50% & <$HOME> "q""#.into(),
+            read_only: false,
+            cache: "auto".into(),
+        }];
+        let units = generate_vm_service(
+            "vm1",
+            &["br0"],
+            &shared_dirs,
+            "/proc/self/exe",
+            "/run/tinyvmm.sock",
+            "/firmware",
+            "/bin/virtiofsd",
+        )
+        .await
+        .unwrap();
+
+        let unit = &units[&get_systemd_fs_unit_name("vm1", 0)];
+        assert!(
+            unit.contains(r#" "--shared-dir=/srv/50%% & <$$HOME> \"q\"" --cache=auto"#),
+            "{unit}"
+        );
+    }
 }
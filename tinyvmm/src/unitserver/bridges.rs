@@ -1,8 +1,16 @@
+use log::{info, warn};
+
 use crate::{
     database::{bridge::Bridge, entity::Entity, store::Store, virtual_machine::VirtualMachine},
-    systemd::bridge::{create_bridge, create_bridge_network, Lease},
+    systemd::{
+        self,
+        bridge::{create_bridge, create_bridge_network, Lease},
+        error::SystemdUnitCreationError,
+    },
 };
 
+use super::FINALIZER;
+
 pub async fn reconcile(store: &Store, dns_listener: &str) -> eyre::Result<()> {
     let bridges = Bridge::list(store)?;
     let vms = VirtualMachine::list(store)?;
@@ -10,10 +18,35 @@ pub async fn reconcile(store: &Store, dns_listener: &str) -> eyre::Result<()> {
     // generate the units for existing bridges and check the diffs
     // if there are any diffs, commit, daemon-reload and start them
     // TODO: actually diff
-    for bridge in bridges {
-        let name = &bridge.metadata.name;
+    for mut bridge in bridges {
+        if bridge.metadata.deletion_timestamp.is_some() {
+            if bridge.metadata.has_finalizer(FINALIZER) {
+                info!(
+                    "{} is being deleted, removing the bridge",
+                    bridge.metadata.name
+                );
+                match systemd::destroy_netdev(&bridge.metadata.name).await {
+                    Ok(()) | Err(SystemdUnitCreationError::LinkNotFound(_)) => {}
+                    Err(e) => return Err(e.into()),
+                }
+                bridge.metadata.remove_finalizer(FINALIZER);
+                bridge.update(store)?;
+            }
+            continue;
+        }
+
+        if !bridge.metadata.has_finalizer(FINALIZER) {
+            bridge.metadata.add_finalizer(FINALIZER);
+            bridge = match bridge.update(store) {
+                Ok(bridge) => bridge,
+                Err(e) => {
+                    warn!("failed to add the finalizer: {}", e);
+                    continue;
+                }
+            };
+        }
 
-        let vms = vms.iter().filter(|vm| &vm.spec.bridge == name);
+        let name = &bridge.metadata.name;
 
         create_bridge(name).await?;
 
@@ -23,11 +56,7 @@ pub async fn reconcile(store: &Store, dns_listener: &str) -> eyre::Result<()> {
             &bridge.spec.address.parse().unwrap(),
             dns_listener,
             &bridge.spec.dns_server,
-            vms.map(|vm| Lease {
-                mac: vm.spec.mac.clone(),
-                ip: vm.spec.ip.clone(),
-            })
-            .collect(),
+            leases(&vms, name),
         )
         .await?;
     }
@@ -36,3 +65,64 @@ pub async fn reconcile(store: &Store, dns_listener: &str) -> eyre::Result<()> {
 
     Ok(())
 }
+
+/// The static leases of the interfaces with an address on the bridge, the vms
+/// being deleted give theirs up.
+fn leases(vms: &[VirtualMachine], bridge: &str) -> Vec<Lease> {
+    vms.iter()
+        .filter(|vm| vm.metadata.deletion_timestamp.is_none())
+        .flat_map(|vm| &vm.spec.interfaces)
+        .filter(|interface| interface.bridge == bridge)
+        .filter_map(|interface| {
+            Some(Lease {
+                mac: interface.mac.clone(),
+                ip: interface.ip.clone()?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::leases;
+    use crate::fixtures::test_vm;
+
+    #[test]
+    fn leases_the_addresses_on_each_bridge() {
+        let vm1 = test_vm(
+            "vm1",
+            json!({
+                "interfaces": [
+                    { "bridge": "br0", "mac": "02:00:00:00:00:01", "ip": "10.0.0.2" },
+                    { "bridge": "br1", "mac": "02:00:00:00:01:01", "ip": "10.1.0.2" },
+                    { "bridge": "br1", "mac": "02:00:00:00:01:02" },
+                ],
+            }),
+        );
+        let mut vm2 = test_vm(
+            "vm2",
+            json!({
+                "interfaces": [{ "bridge": "br1", "mac": "02:00:00:00:01:03", "ip": "10.1.0.3" }],
+            }),
+        );
+        vm2.metadata.deletion_timestamp = Some("2023-01-01T00:00:00Z".into());
+        let vms = [vm1, vm2];
+
+        let leased = |bridge| -> Vec<_> {
+            leases(&vms, bridge)
+                .into_iter()
+                .map(|lease| (lease.mac, lease.ip))
+                .collect()
+        };
+        assert_eq!(
+            leased("br0"),
+            [("02:00:00:00:00:01".into(), "10.0.0.2".into())]
+        );
+        assert_eq!(
+            leased("br1"),
+            [("02:00:00:00:01:01".into(), "10.1.0.2".into())]
+        );
+    }
+}
@@ -0,0 +1,360 @@
+use std::{
+    ffi::{OsStr, OsString},
+    fs,
+    path::Path,
+    time::Duration,
+};
+
+use data_encoding::HEXLOWER;
+use eyre::{bail, eyre, WrapErr};
+use hyper::{body::HttpBody, Client, StatusCode};
+use log::{debug, info, warn};
+use ring::digest::{Context, SHA256};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::timeout,
+};
+
+use crate::database::{
+    entity::Entity,
+    image::{dependents, image_path, Image, ImagePhase, ImageStatus},
+    store::Store,
+};
+
+use super::{
+    tasks::{update_latest, Tasks},
+    volumes::run,
+    FINALIZER,
+};
+
+/// How long the server has to answer, and then to send each chunk of the
+/// image.
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub async fn reconcile(store: &Store, tasks: &Tasks) -> eyre::Result<()> {
+    for mut image in Image::list(store)? {
+        let name = image.metadata.name.clone();
+        let key = format!("Image/{name}");
+        let volumes = dependents(store, &name)?;
+
+        if image.metadata.deletion_timestamp.is_some() {
+            if image.metadata.has_finalizer(FINALIZER) {
+                // the overlays keep reading their backing file
+                if !volumes.is_empty() {
+                    debug!("{} is still the image of {}", name, volumes.join(", "));
+                    continue;
+                }
+                if !tasks.forget(&key) {
+                    debug!("{} is still being cached", name);
+                    continue;
+                }
+                info!("{} is being deleted, removing its cached copy", name);
+                match fs::remove_file(image_path(&image)) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                        warn!("failed to remove the cached copy of {}: {}", name, e);
+                        continue;
+                    }
+                    _ => {}
+                }
+                image.metadata.remove_finalizer(FINALIZER);
+                image.update(store)?;
+            }
+            continue;
+        }
+
+        if !image.metadata.has_finalizer(FINALIZER) {
+            image.metadata.add_finalizer(FINALIZER);
+            image = match image.update(store) {
+                Ok(image) => image,
+                Err(e) => {
+                    warn!("failed to add the finalizer: {}", e);
+                    continue;
+                }
+            };
+        }
+
+        if image.status.volumes != volumes {
+            image.status.volumes = volumes;
+            image = match image.update(store) {
+                Ok(image) => image,
+                Err(e) => {
+                    warn!("failed to update the status of {}: {}", name, e);
+                    continue;
+                }
+            };
+        }
+
+        // a failed image is retried with a backoff by the tasks
+        if image.status.phase == ImagePhase::Ready && image_path(&image).exists() {
+            continue;
+        }
+        let store = store.clone();
+        tasks.spawn(key, async move {
+            let path = image_path(&image);
+            let result = cache(&image, &path).await;
+            let status = match &result {
+                Ok((size, sha256)) => ImageStatus {
+                    phase: ImagePhase::Ready,
+                    path: Some(path.to_string_lossy().into()),
+                    size: Some(*size),
+                    sha256: Some(sha256.clone()),
+                    last_error: None,
+                    ..Default::default()
+                },
+                Err(e) => ImageStatus {
+                    phase: ImagePhase::Failed,
+                    last_error: Some(format!("{e:#}")),
+                    ..image.status.clone()
+                },
+            };
+            debug!("updating the status of {name}: {:?}", status.phase);
+            update_latest(&store, &name, |image: &mut Image| {
+                image.status = ImageStatus {
+                    volumes: image.status.volumes.clone(),
+                    ..status.clone()
+                };
+            })?;
+
+            result.map(|_| ())
+        });
+    }
+
+    Ok(())
+}
+
+async fn sha256(path: &Path) -> eyre::Result<(u64, String)> {
+    let mut file = File::open(path).await?;
+    let mut context = Context::new(&SHA256);
+    let mut buffer = vec![0; 1 << 20];
+    let mut size = 0;
+    loop {
+        let n = file.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        context.update(&buffer[..n]);
+        size += n as u64;
+    }
+
+    Ok((size, HEXLOWER.encode(context.finish().as_ref())))
+}
+
+/// Downloads the url to the path, returning the size and checksum of what
+/// was written.
+async fn download(url: &str, path: &Path) -> eyre::Result<(u64, String)> {
+    let uri = url.parse().wrap_err_with(|| format!("invalid url {url}"))?;
+    let mut response = timeout(DOWNLOAD_TIMEOUT, Client::new().get(uri))
+        .await
+        .map_err(|_| eyre!("timed out getting {url}"))?
+        .wrap_err_with(|| format!("failed to get {url}"))?;
+    if response.status() != StatusCode::OK {
+        bail!("failed to get {}: {}", url, response.status());
+    }
+
+    let mut file = File::create(path).await?;
+    let mut context = Context::new(&SHA256);
+    let mut size = 0;
+    while let Some(chunk) = timeout(DOWNLOAD_TIMEOUT, response.body_mut().data())
+        .await
+        .map_err(|_| eyre!("timed out reading {url}"))?
+    {
+        let chunk = chunk.wrap_err_with(|| format!("failed to read {url}"))?;
+        context.update(&chunk);
+        file.write_all(&chunk).await?;
+        size += chunk.len() as u64;
+    }
+    file.sync_all().await?;
+
+    Ok((size, HEXLOWER.encode(context.finish().as_ref())))
+}
+
+/// Copies or downloads the image to the path, returning the size and
+/// checksum of what was written.
+async fn fetch(image: &Image, path: &Path) -> eyre::Result<(u64, String)> {
+    match (&image.spec.path, &image.spec.url) {
+        (Some(source), _) => {
+            run(
+                "cp",
+                &[
+                    "--reflink=auto".as_ref(),
+                    "--sparse=always".as_ref(),
+                    OsStr::new(source),
+                    path.as_os_str(),
+                ],
+            )
+            .await?;
+            sha256(path).await
+        }
+        (None, Some(url)) => download(url, path).await,
+        (None, None) => bail!("the image has neither a path nor an url"),
+    }
+}
+
+/// Fetches the image to the path unless it's already there, returning the
+/// size and checksum of the cached copy. A cached copy that doesn't match the
+/// expected checksum is fetched again.
+async fn cache(image: &Image, path: &Path) -> eyre::Result<(u64, String)> {
+    if path.exists() {
+        if let (Some(size), Some(sha256)) = (image.status.size, &image.status.sha256) {
+            return Ok((size, sha256.clone()));
+        }
+        let (size, sha256) = sha256(path).await?;
+        match &image.spec.sha256 {
+            Some(expected) if &sha256 != expected => {
+                warn!(
+                    "the cached {} has the checksum {} instead of {}, fetching it again",
+                    image.metadata.name, sha256, expected
+                );
+                fs::remove_file(path)?;
+            }
+            _ => return Ok((size, sha256)),
+        }
+    }
+
+    info!("caching {} at {}", image.metadata.name, path.display());
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    // the copy is only moved in place once it's verified
+    let mut partial = OsString::from(path);
+    partial.push(".partial");
+    let partial = Path::new(&partial);
+
+    let (size, sha256) = match fetch(image, partial).await {
+        Ok(fetched) => fetched,
+        Err(e) => {
+            let _ = fs::remove_file(partial);
+            return Err(e);
+        }
+    };
+    if let Some(expected) = &image.spec.sha256 {
+        if &sha256 != expected {
+            let _ = fs::remove_file(partial);
+            bail!("the checksum is {sha256} instead of {expected}");
+        }
+    }
+    fs::rename(partial, path)?;
+
+    Ok((size, sha256))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use data_encoding::HEXLOWER;
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Response, Server, StatusCode,
+    };
+    use ring::digest::{digest, SHA256};
+    use serde_json::json;
+
+    use super::{cache, download};
+    use crate::fixtures::{scratch_dir, test_image};
+
+    const CONTENT: &[u8] = b"the disk of a tiny vm";
+
+    /// Serves the content at /base.raw, returning the url of the server.
+    fn serve() -> String {
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|request| async move {
+                let response = match request.uri().path() {
+                    "/base.raw" => Response::new(Body::from(CONTENT)),
+                    _ => Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(Body::empty())
+                        .unwrap(),
+                };
+                Ok::<_, Infallible>(response)
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+
+        url
+    }
+
+    fn content_sha256() -> String {
+        HEXLOWER.encode(digest(&SHA256, CONTENT).as_ref())
+    }
+
+    #[tokio::test]
+    async fn downloads_the_image() {
+        let url = serve();
+        let dir = scratch_dir("download");
+
+        let path = dir.join("base.raw");
+        let (size, sha256) = download(&format!("{url}/base.raw"), &path).await.unwrap();
+        assert_eq!(size, CONTENT.len() as u64);
+        assert_eq!(sha256, content_sha256());
+        assert_eq!(std::fs::read(&path).unwrap(), CONTENT);
+
+        assert!(
+            download(&format!("{url}/missing.raw"), &dir.join("missing.raw"))
+                .await
+                .is_err()
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn caches_the_image_once_verified() {
+        let url = serve();
+        let dir = scratch_dir("cache");
+        let path = dir.join("base.raw");
+
+        let image = test_image(
+            "base",
+            json!({ "url": format!("{url}/base.raw"), "sha256": content_sha256() }),
+        );
+        let (size, sha256) = cache(&image, &path).await.unwrap();
+        assert_eq!(size, CONTENT.len() as u64);
+        assert_eq!(sha256, content_sha256());
+        assert_eq!(std::fs::read(&path).unwrap(), CONTENT);
+
+        // the cached copy is hashed again without a status
+        assert_eq!(
+            cache(&image, &path).await.unwrap(),
+            (size, content_sha256())
+        );
+
+        // and fetched again when it doesn't match
+        std::fs::write(&path, "corrupted").unwrap();
+        assert_eq!(
+            cache(&image, &path).await.unwrap(),
+            (size, content_sha256())
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), CONTENT);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn drops_the_image_on_a_checksum_mismatch() {
+        let url = serve();
+        let dir = scratch_dir("mismatch");
+        let path = dir.join("base.raw");
+
+        let image = test_image(
+            "base",
+            json!({ "url": format!("{url}/base.raw"), "sha256": "0".repeat(64) }),
+        );
+        let e = cache(&image, &path).await.unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "the checksum is {} instead of {}",
+                content_sha256(),
+                "0".repeat(64)
+            )
+        );
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,24 +1,77 @@
 mod bridges;
+mod images;
+mod snapshots;
+mod tasks;
 mod virtualmachines;
+mod volumes;
 
+use std::time::Duration;
+
+use futures::StreamExt;
 use log::{debug, info, warn};
 use tokio::sync::mpsc::{Receiver, Sender};
 
 use crate::database::store::Store;
 
+use self::tasks::Tasks;
+
+/// How often the units are reconciled even without store changes, so that the
+/// vm statuses follow the systemd state.
+const RESYNC_PERIOD: Duration = Duration::from_secs(30);
+
+/// Keeps the vms and bridges in the store until their units are torn down,
+/// the volumes until their images are removed and the images and snapshots
+/// until their files are.
+const FINALIZER: &str = "tinyvmm/units";
+
 pub struct Config {
     pub shutdown_signal: Receiver<()>,
     pub store: Store,
     pub dns_listener: String,
     pub api_server: String,
+    pub max_concurrent_restarts: usize,
+    pub default_firmware: String,
+    pub virtiofsd: String,
 }
 
-async fn reconcile(store: &Store, dns_listener: &str, api_server: &str) {
+async fn reconcile(
+    store: &Store,
+    tasks: &Tasks,
+    dns_listener: &str,
+    api_server: &str,
+    max_concurrent_restarts: usize,
+    default_firmware: &str,
+    virtiofsd: &str,
+) {
+    // the volumes are created once their images are cached, and the vms
+    // are only booted once their volumes are ready
+    info!("reconciling images");
+    let res = images::reconcile(store, tasks).await;
+    if let Err(e) = res {
+        warn!("failed reconciling images: {}", e);
+    }
+    info!("reconciling volumes");
+    let res = volumes::reconcile(store, tasks).await;
+    if let Err(e) = res {
+        warn!("failed reconciling volumes: {}", e);
+    }
     info!("reconciling vm units");
-    let res = virtualmachines::reconcile(store, api_server).await;
+    let res = virtualmachines::reconcile(
+        store,
+        api_server,
+        max_concurrent_restarts,
+        default_firmware,
+        virtiofsd,
+    )
+    .await;
     if let Err(e) = res {
         warn!("failed reconciling vm units: {}", e);
     }
+    info!("reconciling snapshots");
+    let res = snapshots::reconcile(store, tasks).await;
+    if let Err(e) = res {
+        warn!("failed reconciling snapshots: {}", e);
+    }
     info!("reconciling bridges");
     let res = bridges::reconcile(store, dns_listener).await;
     if let Err(e) = res {
@@ -32,15 +85,32 @@ pub async fn main(mut config: Config, shutdown: Sender<eyre::Result<()>>) -> eyr
     let mut subscriber = config.store.watch_entities("/");
 
     tokio::spawn(async move {
-        reconcile(&config.store, &config.dns_listener, &config.api_server).await;
-        while let Some(event) = (&mut subscriber).await {
-            debug!(
-                "unit reconciler event: {}",
-                String::from_utf8(event.key().to_ascii_lowercase())
-                    .or_else(|_| Ok::<String, ()>("?".into()))
-                    .unwrap()
-            );
-            reconcile(&config.store, &config.dns_listener, &config.api_server).await;
+        let tasks = Tasks::default();
+        let mut resync = tokio::time::interval(RESYNC_PERIOD);
+        loop {
+            tokio::select! {
+                event = subscriber.next() => {
+                    match event {
+                        Some(Ok(event)) => debug!("unit reconciler event: {}", event.key()),
+                        // everything is reconciled anyway
+                        Some(Err(e)) => warn!("unit reconciler resync: {}", e),
+                        None => break,
+                    }
+                }
+                _ = resync.tick() => {
+                    debug!("unit reconciler resync");
+                }
+            }
+            reconcile(
+                &config.store,
+                &tasks,
+                &config.dns_listener,
+                &config.api_server,
+                config.max_concurrent_restarts,
+                &config.default_firmware,
+                &config.virtiofsd,
+            )
+            .await;
         }
     });
 
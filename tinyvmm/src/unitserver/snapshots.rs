@@ -0,0 +1,195 @@
+use std::{fs, time::SystemTime};
+
+use eyre::{bail, WrapErr};
+use log::{debug, info, warn};
+
+use crate::{
+    ch::{api::Client, snapshot::take_snapshot},
+    database::{
+        entity::Entity,
+        snapshot::{snapshot_dir, Snapshot, SnapshotPhase, SnapshotStatus},
+        store::Store,
+        virtual_machine::{full_spec_hash, spec_hash, VirtualMachine},
+    },
+    systemd,
+};
+
+use super::{
+    tasks::{update_latest, Tasks},
+    FINALIZER,
+};
+
+pub async fn reconcile(store: &Store, tasks: &Tasks) -> eyre::Result<()> {
+    for mut snapshot in Snapshot::list(store)? {
+        let name = snapshot.metadata.name.clone();
+        let key = format!("Snapshot/{name}");
+
+        if snapshot.metadata.deletion_timestamp.is_some() {
+            if snapshot.metadata.has_finalizer(FINALIZER) {
+                if !tasks.forget(&key) {
+                    debug!("{} is still being taken", name);
+                    continue;
+                }
+                info!("{} is being deleted, removing its files", name);
+                match fs::remove_dir_all(snapshot_dir(&snapshot)) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                        warn!("failed to remove the files of {}: {}", name, e);
+                        continue;
+                    }
+                    _ => {}
+                }
+                snapshot.metadata.remove_finalizer(FINALIZER);
+                snapshot.update(store)?;
+            }
+            continue;
+        }
+
+        if !snapshot.metadata.has_finalizer(FINALIZER) {
+            snapshot.metadata.add_finalizer(FINALIZER);
+            snapshot = match snapshot.update(store) {
+                Ok(snapshot) => snapshot,
+                Err(e) => {
+                    warn!("failed to add the finalizer: {}", e);
+                    continue;
+                }
+            };
+        }
+
+        match snapshot.status.phase {
+            SnapshotPhase::Pending => {}
+            SnapshotPhase::Ready if expired(&snapshot) => {
+                info!("{} is past its retention, deleting", name);
+                if let Err(e) = Snapshot::delete(store, &name) {
+                    warn!("failed to delete {}: {}", name, e);
+                }
+                continue;
+            }
+            // a snapshot is only taken once
+            SnapshotPhase::Ready | SnapshotPhase::Failed => continue,
+        }
+
+        let store = store.clone();
+        tasks.spawn(key, async move {
+            let status = match take(&store, &snapshot).await {
+                Ok(status) => status,
+                Err(e) => {
+                    warn!("failed to take {}: {:#}", name, e);
+                    let _ = fs::remove_dir_all(snapshot_dir(&snapshot));
+                    SnapshotStatus {
+                        phase: SnapshotPhase::Failed,
+                        last_error: Some(format!("{e:#}")),
+                        ..Default::default()
+                    }
+                }
+            };
+            debug!("updating the status of {name}: {:?}", status.phase);
+            update_latest(&store, &name, |snapshot: &mut Snapshot| {
+                snapshot.status = status.clone();
+            })
+        });
+    }
+
+    Ok(())
+}
+
+fn expired(snapshot: &Snapshot) -> bool {
+    let (Some(taken_at), Some(retention)) = (&snapshot.status.taken_at, &snapshot.spec.retention)
+    else {
+        return false;
+    };
+
+    match (
+        humantime::parse_rfc3339(taken_at),
+        humantime::parse_duration(retention),
+    ) {
+        (Ok(taken_at), Ok(retention)) => taken_at + retention <= SystemTime::now(),
+        _ => false,
+    }
+}
+
+async fn take(store: &Store, snapshot: &Snapshot) -> eyre::Result<SnapshotStatus> {
+    let vm_name = &snapshot.spec.vm;
+    let vm = VirtualMachine::get(store, vm_name)
+        .wrap_err_with(|| format!("failed to get the vm {vm_name}"))?;
+
+    let state = systemd::get_service_state(vm_name).await?;
+    if state.active_state != "active" {
+        bail!(
+            "{} isn't running, its unit is {}",
+            vm_name,
+            state.active_state
+        );
+    }
+    // a vm waiting for a restart doesn't run its spec yet
+    if vm.status.spec_hash != Some(spec_hash(&vm)?) {
+        bail!("{} is waiting for a restart to apply its spec", vm_name);
+    }
+
+    let dir = snapshot_dir(snapshot);
+    info!(
+        "taking {} of {} in {}",
+        snapshot.metadata.name,
+        vm_name,
+        dir.display()
+    );
+    // the leftovers of an interrupted attempt
+    let _ = fs::remove_dir_all(&dir);
+    let disks = take_snapshot(&Client::for_vm(vm_name), &dir).await?;
+
+    Ok(SnapshotStatus {
+        phase: SnapshotPhase::Ready,
+        path: Some(dir.to_string_lossy().into()),
+        taken_at: Some(humantime::format_rfc3339_seconds(SystemTime::now()).to_string()),
+        spec_hash: Some(full_spec_hash(&vm)?),
+        disks,
+        last_error: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{reconcile, Tasks, FINALIZER};
+    use crate::{
+        database::{
+            entity::Entity,
+            error::Error,
+            snapshot::{Snapshot, SnapshotPhase},
+            store::Store,
+        },
+        fixtures::test_snapshot,
+    };
+
+    #[tokio::test]
+    async fn deletes_the_snapshots_past_their_retention() {
+        let store = Store::in_memory();
+        for (name, taken_at) in [
+            ("expired", "2020-01-01T00:00:00Z"),
+            ("kept", "2999-01-01T00:00:00Z"),
+        ] {
+            let mut snapshot = test_snapshot(
+                &format!("tinyvmm-test-{name}"),
+                json!({ "retention": "7d" }),
+            );
+            snapshot.status.phase = SnapshotPhase::Ready;
+            snapshot.status.taken_at = Some(taken_at.into());
+            snapshot.create(&store).unwrap();
+        }
+        let tasks = Tasks::default();
+
+        reconcile(&store, &tasks).await.unwrap();
+        let expired = Snapshot::get(&store, "tinyvmm-test-expired").unwrap();
+        assert!(expired.metadata.deletion_timestamp.is_some());
+        assert!(expired.metadata.has_finalizer(FINALIZER));
+
+        // its files are removed before the finalizer
+        reconcile(&store, &tasks).await.unwrap();
+        assert!(matches!(
+            Snapshot::get(&store, "tinyvmm-test-expired"),
+            Err(Error::NotFound)
+        ));
+        let kept = Snapshot::get(&store, "tinyvmm-test-kept").unwrap();
+        assert!(kept.metadata.deletion_timestamp.is_none());
+    }
+}
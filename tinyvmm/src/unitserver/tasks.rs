@@ -0,0 +1,110 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::warn;
+use serde::Serialize;
+use tokio::time::Instant;
+
+use crate::database::{entity::Entity, error::Error, store::Store};
+
+/// A failed task is retried after that long at first, doubling with each
+/// failure.
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// How many times a status is written again when the entity changed under
+/// the task.
+const UPDATE_ATTEMPTS: usize = 5;
+
+#[derive(Default)]
+struct Task {
+    running: bool,
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+/// The slow work of the reconcilers, like fetching the images, runs in its
+/// own tasks so that it doesn't hold up the other entities. The tasks report
+/// through the statuses of their entities.
+#[derive(Clone, Default)]
+pub(super) struct Tasks {
+    tasks: Arc<Mutex<HashMap<String, Task>>>,
+}
+
+impl Tasks {
+    /// Runs the future unless the task of the key is still running, or
+    /// failed and isn't due for a retry yet.
+    pub(super) fn spawn<F>(&self, key: String, future: F)
+    where
+        F: Future<Output = eyre::Result<()>> + Send + 'static,
+    {
+        {
+            let mut tasks = self.tasks.lock().unwrap();
+            let task = tasks.entry(key.clone()).or_default();
+            if task.running || task.retry_at.is_some_and(|at| at > Instant::now()) {
+                return;
+            }
+            task.running = true;
+        }
+
+        let tasks = self.tasks.clone();
+        tokio::spawn(async move {
+            let result = future.await;
+
+            let mut tasks = tasks.lock().unwrap();
+            match result {
+                Ok(()) => {
+                    tasks.remove(&key);
+                }
+                Err(e) => {
+                    let task = tasks.entry(key.clone()).or_default();
+                    let delay = RETRY_DELAY
+                        .saturating_mul(1 << task.failures.min(16))
+                        .min(MAX_RETRY_DELAY);
+                    warn!("{} failed, retrying in {:?}: {:#}", key, delay, e);
+                    task.running = false;
+                    task.failures += 1;
+                    task.retry_at = Some(Instant::now() + delay);
+                }
+            }
+        });
+    }
+
+    /// Forgets the failures of the task, so that an entity created again
+    /// under the same name starts over. Returns false if it's still running.
+    pub(super) fn forget(&self, key: &str) -> bool {
+        let mut tasks = self.tasks.lock().unwrap();
+        match tasks.get(key) {
+            Some(task) if task.running => false,
+            _ => {
+                tasks.remove(key);
+                true
+            }
+        }
+    }
+}
+
+/// Applies the change to the latest version of the entity, the reconcilers
+/// might have updated it while the task ran.
+pub(super) fn update_latest<E>(
+    store: &Store,
+    name: &str,
+    change: impl Fn(&mut E),
+) -> eyre::Result<()>
+where
+    E: Entity<Type = E> + Serialize,
+{
+    let mut attempts = 0;
+    loop {
+        let mut entity = E::get(store, name)?;
+        change(&mut entity);
+        match entity.update(store) {
+            Err(Error::Conflict { .. }) if attempts + 1 < UPDATE_ATTEMPTS => attempts += 1,
+            result => return Ok(result.map(|_| ())?),
+        }
+    }
+}
@@ -1,60 +1,581 @@
+use std::{
+    collections::HashSet,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
 use log::{debug, info, trace, warn};
 
 use crate::{
-    database::{entity::Entity, store::Store, virtual_machine::VirtualMachine},
-    systemd,
+    ch::{
+        self,
+        agent::Agent,
+        api::{Client, VmState, VmmApi},
+        bootstrap, get_vm_tap_name,
+        hotplug::{self, Outcome},
+        runtime,
+    },
+    database::{
+        entity::Entity,
+        ipam,
+        store::Store,
+        virtual_machine::{
+            spec_hash, CacheMode, Condition, GuestInterface, Phase, RestartPolicy, RunState,
+            RunStrategy, VirtualMachine, VirtualMachineStatus,
+        },
+        volume::Volume,
+    },
+    systemd::{self, SharedDir},
 };
 
-pub async fn reconcile(store: &Store, api_server: &str) -> eyre::Result<()> {
+use super::FINALIZER;
+
+pub async fn reconcile(
+    store: &Store,
+    api_server: &str,
+    max_concurrent_restarts: usize,
+    default_firmware: &str,
+    virtiofsd: &str,
+) -> eyre::Result<()> {
     let self_exe = &std::env::args().next().unwrap();
 
     let vms = VirtualMachine::list(store)?;
+    let volumes = Volume::list(store)?;
 
     debug!("got {} vms to reconcile", vms.len());
 
+    let mut known_units = HashSet::new();
+    for vm in &vms {
+        known_units.insert(systemd::get_systemd_unit_name(&vm.metadata.name));
+        for interface in 0..vm.spec.interfaces.len() {
+            known_units.insert(systemd::get_systemd_tap_unit_name(
+                &vm.metadata.name,
+                interface,
+            ));
+        }
+        for filesystem in 0..vm.spec.filesystems.len() {
+            known_units.insert(systemd::get_systemd_fs_unit_name(
+                &vm.metadata.name,
+                filesystem,
+            ));
+        }
+    }
+
+    // the units already starting or stopping count against the restarts
+    let mut in_flight = 0;
+    for vm in &vms {
+        if let Ok(state) = systemd::get_service_state(&vm.metadata.name).await {
+            if matches!(
+                state.active_state.as_str(),
+                "activating" | "deactivating" | "reloading"
+            ) {
+                in_flight += 1;
+            }
+        }
+    }
+    let mut restarts = max_concurrent_restarts.saturating_sub(in_flight);
+
     // generate the units for existing vms and check the diffs
     // if there are any diffs, commit, daemon-reload and start them
-    for vm in vms {
+    for mut vm in vms {
+        if vm.metadata.deletion_timestamp.is_some() {
+            if vm.metadata.has_finalizer(FINALIZER) {
+                let name = vm.metadata.name.clone();
+                if let Err(e) = finalize(store, vm).await {
+                    warn!("failed to tear down {}: {}", name, e);
+                }
+            }
+            continue;
+        }
+
+        if !vm.metadata.has_finalizer(FINALIZER) {
+            vm.metadata.add_finalizer(FINALIZER);
+            vm = match vm.update(store) {
+                Ok(vm) => vm,
+                Err(e) => {
+                    warn!("failed to add the finalizer: {}", e);
+                    continue;
+                }
+            };
+        }
+
         let name = &vm.metadata.name;
-        let bridge_name = &vm.spec.bridge;
+        let bridges: Vec<&str> = vm.spec.interfaces.iter().map(|i| &*i.bridge).collect();
+        let shared_dirs = shared_dirs(&vm);
+        let mut last_error = None;
 
         debug!("reconciling vm {name}");
 
-        let has_diffs = systemd::has_diffs(name, bridge_name, self_exe, api_server).await;
+        let has_diffs = systemd::has_diffs(
+            name,
+            &bridges,
+            &shared_dirs,
+            self_exe,
+            api_server,
+            default_firmware,
+            virtiofsd,
+        )
+        .await;
         debug!("vm {name} diffs: {has_diffs:?}");
-        match has_diffs {
+        let changed = match has_diffs {
             Err(e) => {
                 info!("failed to check for diffs for {}: {}", name, e);
-                info!("will try to reconcile");
-                if let Err(e) =
-                    systemd::create_vm_service(name, bridge_name, self_exe, api_server).await
-                {
-                    warn!("systemd::create_vm_service failed for {}: {}", name, e);
-                }
-                if let Err(e) = systemd::start_service(name).await {
-                    warn!("systemd::start_service failed for {}: {}", name, e);
-                }
-                debug!("reconciled and started {}", name);
-            }
-            Ok(true) => {
-                info!("{} changed, will try to reconcile", name);
-                if let Err(e) =
-                    systemd::create_vm_service(name, bridge_name, self_exe, api_server).await
-                {
-                    warn!("systemd::create_vm_service failed for {}: {}", name, e);
-                }
-                if let Err(e) = systemd::start_service(name).await {
-                    warn!("systemd::start_service failed for {}: {}", name, e);
-                }
-                debug!("reconciled and started {}", name);
+                true
+            }
+            Ok(changed) => changed,
+        };
+        if changed {
+            info!("{} changed, will try to reconcile", name);
+            if let Err(e) = systemd::create_vm_service(
+                name,
+                &bridges,
+                &shared_dirs,
+                self_exe,
+                api_server,
+                default_firmware,
+                virtiofsd,
+            )
+            .await
+            {
+                warn!("systemd::create_vm_service failed for {}: {}", name, e);
+                last_error = Some(format!("failed to create the vm service: {e}"));
+            }
+        } else {
+            trace!("already reconciled {}", name);
+        }
+
+        if let Err(e) =
+            converge_run_state(name, vm.spec.run_strategy, vm.status.desired_state).await
+        {
+            warn!("failed to converge the run state of {}: {}", name, e);
+            last_error = Some(format!("failed to converge the run state: {e}"));
+        }
+
+        let pending_restart = match apply_spec(&vm, default_firmware, &volumes, &mut restarts).await
+        {
+            Ok(pending_restart) => pending_restart,
+            Err(e) => {
+                warn!("failed to apply the spec of {}: {}", name, e);
+                last_error = Some(format!("failed to apply the spec: {e}"));
+                None
             }
-            Ok(false) => {
-                trace!("already reconciled {}", name);
+        };
+
+        let status = observe_status(&vm, last_error, pending_restart).await;
+        if status != vm.status {
+            debug!("updating the status of {name}: {:?}", status.phase);
+            let name = name.clone();
+            vm.status = status;
+            if let Err(e) = vm.update(store) {
+                warn!("failed to update the status of {}: {}", name, e);
             }
         }
     }
 
-    // list all tinyvmi units, stop and cleanup nonexistent ones. Daemon-reload after
+    match ipam::release_stale(store) {
+        Ok(0) => {}
+        Ok(released) => info!("released {} stale address claims", released),
+        Err(e) => warn!("failed to release the stale address claims: {}", e),
+    }
+
+    // stop and cleanup the units of the vms that are long gone
+    for unit in systemd::list_vm_units()? {
+        if !known_units.contains(&unit) {
+            info!("removing the orphaned unit {}", unit);
+            if let Err(e) = systemd::remove_unit(&unit).await {
+                warn!("failed to remove {}: {}", unit, e);
+            }
+        }
+    }
 
     Ok(())
 }
+
+fn shared_dirs(vm: &VirtualMachine) -> Vec<SharedDir> {
+    vm.spec
+        .filesystems
+        .iter()
+        .map(|filesystem| SharedDir {
+            path: filesystem.path.clone(),
+            read_only: filesystem.read_only,
+            cache: match filesystem.cache {
+                CacheMode::Auto => "auto",
+                CacheMode::Always => "always",
+                CacheMode::Never => "never",
+            }
+            .into(),
+        })
+        .collect()
+}
+
+/// What the vm service needs for its active state to match the run strategy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RunStateChange {
+    Start,
+    Stop,
+    /// Pauses or resumes the running vm.
+    Pause,
+    Keep,
+}
+
+/// Manual vms are left to the lifecycle actions, but kept in the state the
+/// last one asked for.
+fn run_state_change(
+    strategy: RunStrategy,
+    active_state: &str,
+    never_started: bool,
+    desired: Option<RunState>,
+) -> RunStateChange {
+    match (strategy, active_state) {
+        (RunStrategy::Always, "inactive" | "failed") | (RunStrategy::RerunOnFailure, "failed") => {
+            RunStateChange::Start
+        }
+        (RunStrategy::RerunOnFailure, "inactive") if never_started => RunStateChange::Start,
+        (RunStrategy::Halted, "active" | "activating" | "reloading") => RunStateChange::Stop,
+        (RunStrategy::Manual, "active" | "activating" | "reloading")
+            if desired == Some(RunState::Stopped) =>
+        {
+            RunStateChange::Stop
+        }
+        (RunStrategy::Manual, "active") => RunStateChange::Pause,
+        _ => RunStateChange::Keep,
+    }
+}
+
+/// Starts or stops the vm service so that its active state matches the run
+/// strategy.
+async fn converge_run_state(
+    name: &str,
+    strategy: RunStrategy,
+    desired: Option<RunState>,
+) -> eyre::Result<()> {
+    let state = systemd::get_service_state(name).await?;
+    let never_started = state.active_enter_timestamp == 0;
+
+    match run_state_change(strategy, &state.active_state, never_started, desired) {
+        RunStateChange::Start => {
+            info!(
+                "{} is {} under {:?}, starting",
+                name, state.active_state, strategy
+            );
+            systemd::start_service(name).await?;
+        }
+        RunStateChange::Stop => {
+            info!(
+                "{} is {} under {:?}, stopping",
+                name, state.active_state, strategy
+            );
+            systemd::stop_service(name).await?;
+        }
+        RunStateChange::Pause => converge_pause(&Client::for_vm(name), name, desired).await?,
+        RunStateChange::Keep => {}
+    }
+
+    Ok(())
+}
+
+/// Pauses or resumes the running vm as the last lifecycle action asked.
+async fn converge_pause(
+    api: &dyn VmmApi,
+    name: &str,
+    desired: Option<RunState>,
+) -> Result<(), ch::error::Error> {
+    match (desired, api.info().await?.state) {
+        (Some(RunState::Paused), VmState::Running) => {
+            info!("{} was paused, pausing", name);
+            runtime::pause_vm(api).await
+        }
+        (Some(RunState::Running), VmState::Paused) => {
+            info!("{} was resumed, resuming", name);
+            runtime::resume_vm(api).await
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Applies the spec changes to the running vm, restarting it for the changes
+/// that can't be made live when the restart policy and the restart budget
+/// allow it. Returns why the vm still needs a restart otherwise.
+async fn apply_spec(
+    vm: &VirtualMachine,
+    default_firmware: &str,
+    volumes: &[Volume],
+    restarts: &mut usize,
+) -> eyre::Result<Option<String>> {
+    let name = &vm.metadata.name;
+
+    // the vm is only created once the unit is done activating
+    let state = systemd::get_service_state(name).await?;
+    if state.active_state != "active" {
+        return Ok(None);
+    }
+
+    let api = Client::for_vm(name);
+    let desired = bootstrap::vm_config(vm, name, Path::new(default_firmware), volumes)?;
+    let reason = match hotplug::apply(&api, &desired).await? {
+        Outcome::Unchanged => None,
+        Outcome::Applied(count) => {
+            info!("applied {} changes to the running {}", count, name);
+            None
+        }
+        Outcome::Restart(reason) => Some(reason),
+    };
+
+    let hash = spec_hash(vm)?;
+    let reason = reason.or_else(|| match &vm.status.spec_hash {
+        Some(booted) if booted != &hash => {
+            Some(format!("the spec changed from {booted} to {hash}"))
+        }
+        _ => None,
+    });
+    let Some(reason) = reason else {
+        trace!("{} runs with its spec", name);
+        return Ok(None);
+    };
+
+    if vm.spec.restart_policy == RestartPolicy::Never {
+        debug!("{} needs a restart: {}", name, reason);
+        return Ok(Some(reason));
+    }
+    if *restarts == 0 {
+        info!("{} waits for the other restarts: {}", name, reason);
+        return Ok(Some(reason));
+    }
+
+    info!("restarting {}: {}", name, reason);
+    systemd::restart_service(name).await?;
+    *restarts -= 1;
+
+    Ok(None)
+}
+
+/// Stops the vm, removes its units and releases its addresses. The finalizer
+/// is only dropped once the service is down, otherwise it's retried on the
+/// next reconcile.
+async fn finalize(store: &Store, mut vm: VirtualMachine) -> eyre::Result<()> {
+    let name = &vm.metadata.name;
+
+    let state = systemd::get_service_state(name).await?;
+    if !matches!(state.active_state.as_str(), "inactive" | "failed") {
+        info!("{} is being deleted, stopping", name);
+        systemd::stop_service(name).await?;
+        return Ok(());
+    }
+
+    systemd::remove_vm_service(name, vm.spec.interfaces.len(), vm.spec.filesystems.len()).await?;
+    ch::remove_vm_state(name)?;
+    debug!("removed the units and the state of {}", name);
+
+    vm.metadata.remove_finalizer(FINALIZER);
+    vm.update(store)?;
+    // only once the vm is gone, so that no other vm gets its addresses before
+    if vm.metadata.finalizers.is_empty() {
+        ipam::release_all(store, &vm)?;
+    }
+
+    Ok(())
+}
+
+async fn observe_status(
+    vm: &VirtualMachine,
+    last_error: Option<String>,
+    pending_restart: Option<String>,
+) -> VirtualMachineStatus {
+    let name = &vm.metadata.name;
+    let previous = &vm.status;
+    let mut status = VirtualMachineStatus {
+        conditions: previous.conditions.clone(),
+        desired_state: previous.desired_state,
+        spec_hash: previous.spec_hash.clone(),
+        tap_names: (0..vm.spec.interfaces.len())
+            .map(|interface| get_vm_tap_name(name, interface))
+            .collect(),
+        ..Default::default()
+    };
+
+    match systemd::get_service_state(name).await {
+        Ok(state) => {
+            status.phase = match state.active_state.as_str() {
+                "active" => Phase::Running,
+                "inactive" | "deactivating" => Phase::Stopped,
+                "failed" => Phase::Failed,
+                _ => Phase::Pending,
+            };
+            status.pid = Some(state.main_pid).filter(|pid| *pid != 0);
+            status.boot_time = Some(state.active_enter_timestamp)
+                .filter(|ts| *ts != 0 && status.phase == Phase::Running)
+                .map(|ts| {
+                    humantime::format_rfc3339_seconds(
+                        SystemTime::UNIX_EPOCH + Duration::from_micros(ts),
+                    )
+                    .to_string()
+                });
+            set_condition(
+                &mut status.conditions,
+                "Ready",
+                status.phase == Phase::Running,
+                &state.active_state,
+                "",
+            );
+            status.unit_active_state = Some(state.active_state);
+        }
+        Err(e) => {
+            warn!("failed to get the service state for {}: {}", name, e);
+            status.phase = previous.phase;
+            status.unit_active_state = previous.unit_active_state.clone();
+            status.pid = previous.pid;
+            status.boot_time = previous.boot_time.clone();
+        }
+    }
+
+    if status.phase == Phase::Running {
+        status.guest_interfaces = match guest_interfaces(name).await {
+            Ok(interfaces) => interfaces,
+            Err(e) => {
+                trace!(
+                    "failed to ask the agent of {} for its interfaces: {}",
+                    name,
+                    e
+                );
+                previous.guest_interfaces.clone()
+            }
+        };
+    }
+
+    match &pending_restart {
+        Some(reason) => set_condition(
+            &mut status.conditions,
+            "RestartRequired",
+            true,
+            "SpecChanged",
+            reason,
+        ),
+        None => set_condition(&mut status.conditions, "RestartRequired", false, "", ""),
+    }
+
+    match &last_error {
+        Some(e) => set_condition(&mut status.conditions, "Reconciled", false, "Error", e),
+        None => set_condition(&mut status.conditions, "Reconciled", true, "", ""),
+    }
+    status.last_reconcile_error = last_error;
+
+    status
+}
+
+/// The guests without an agent don't answer, so they aren't waited for long.
+async fn guest_interfaces(name: &str) -> Result<Vec<GuestInterface>, ch::error::Error> {
+    let agent = Agent::for_vm(name).with_timeout(Duration::from_secs(2));
+
+    Ok(agent
+        .interfaces()
+        .await?
+        .into_iter()
+        .map(|interface| GuestInterface {
+            name: interface.name,
+            mac: interface.mac,
+            addresses: interface.addresses,
+        })
+        .collect())
+}
+
+/// Upserts a condition, only bumping the transition time if its state flipped.
+fn set_condition(
+    conditions: &mut Vec<Condition>,
+    condition_type: &str,
+    state: bool,
+    reason: &str,
+    message: &str,
+) {
+    let condition = match conditions
+        .iter_mut()
+        .find(|c| c.condition_type == condition_type)
+    {
+        Some(condition) => condition,
+        None => {
+            conditions.push(Condition {
+                condition_type: condition_type.into(),
+                status: !state,
+                ..Default::default()
+            });
+            conditions.last_mut().unwrap()
+        }
+    };
+
+    if condition.status != state {
+        condition.status = state;
+        condition.last_transition_time =
+            humantime::format_rfc3339_seconds(SystemTime::now()).to_string();
+    }
+    condition.reason = reason.into();
+    condition.message = message.into();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        ch::{bootstrap::bootstrap_vm, fake::FakeVmm},
+        fixtures::test_vm,
+    };
+
+    #[tokio::test]
+    async fn keeps_the_vm_paused_as_asked() {
+        let fake = FakeVmm::for_test("converge-pause");
+        let api = fake.client();
+        bootstrap_vm(
+            &api,
+            &test_vm("vm1", json!({})),
+            "vm1",
+            Path::new("/fw"),
+            &[],
+        )
+        .await
+        .unwrap();
+        runtime::start_vm(&api).await.unwrap();
+
+        converge_pause(&api, "vm1", Some(RunState::Paused))
+            .await
+            .unwrap();
+        assert_eq!(api.info().await.unwrap().state, VmState::Paused);
+        converge_pause(&api, "vm1", None).await.unwrap();
+        assert_eq!(api.info().await.unwrap().state, VmState::Paused);
+
+        converge_pause(&api, "vm1", Some(RunState::Running))
+            .await
+            .unwrap();
+        assert_eq!(api.info().await.unwrap().state, VmState::Running);
+    }
+
+    #[test]
+    fn converges_to_the_run_strategy() {
+        use RunStateChange::*;
+        use RunStrategy::*;
+
+        for (strategy, active_state, never_started, desired, change) in [
+            (Always, "inactive", false, None, Start),
+            (Always, "failed", false, None, Start),
+            (Always, "active", false, None, Keep),
+            (RerunOnFailure, "failed", false, None, Start),
+            (RerunOnFailure, "inactive", true, None, Start),
+            (RerunOnFailure, "inactive", false, None, Keep),
+            (Halted, "active", false, None, Stop),
+            (Halted, "activating", false, None, Stop),
+            (Halted, "inactive", false, None, Keep),
+            (Manual, "inactive", true, None, Keep),
+            (Manual, "inactive", false, Some(RunState::Running), Keep),
+            (Manual, "activating", false, Some(RunState::Stopped), Stop),
+            (Manual, "active", false, Some(RunState::Stopped), Stop),
+            (Manual, "active", false, Some(RunState::Paused), Pause),
+        ] {
+            assert_eq!(
+                run_state_change(strategy, active_state, never_started, desired),
+                change,
+                "{strategy:?} {active_state}"
+            );
+        }
+    }
+}
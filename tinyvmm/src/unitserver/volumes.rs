@@ -0,0 +1,367 @@
+use std::{
+    ffi::{OsStr, OsString},
+    fs,
+    path::Path,
+};
+
+use eyre::{bail, WrapErr};
+use log::{debug, info, warn};
+use tokio::process::Command;
+
+use crate::{
+    database::{
+        entity::Entity,
+        image::{image_path, Image, ImagePhase},
+        store::Store,
+        volume::{
+            size_bytes, users, volume_path, CloneStrategy, Volume, VolumeFormat, VolumePhase,
+            VolumeStatus,
+        },
+    },
+    systemd,
+};
+
+use super::{
+    tasks::{update_latest, Tasks},
+    FINALIZER,
+};
+
+/// Looked up in the path of the daemon.
+const QEMU_IMG: &str = "qemu-img";
+
+pub async fn reconcile(store: &Store, tasks: &Tasks) -> eyre::Result<()> {
+    let images = Image::list(store)?;
+
+    for mut volume in Volume::list(store)? {
+        let name = volume.metadata.name.clone();
+        let key = format!("Volume/{name}");
+
+        if volume.metadata.deletion_timestamp.is_some() {
+            if volume.metadata.has_finalizer(FINALIZER) {
+                if !tasks.forget(&key) {
+                    debug!("{} is still being provisioned", name);
+                    continue;
+                }
+                info!("{} is being deleted, removing its image", name);
+                match fs::remove_file(volume_path(&volume)) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                        warn!("failed to remove the image of {}: {}", name, e);
+                        continue;
+                    }
+                    _ => {}
+                }
+                volume.metadata.remove_finalizer(FINALIZER);
+                volume.update(store)?;
+            }
+            continue;
+        }
+
+        if !volume.metadata.has_finalizer(FINALIZER) {
+            volume.metadata.add_finalizer(FINALIZER);
+            volume = match volume.update(store) {
+                Ok(volume) => volume,
+                Err(e) => {
+                    warn!("failed to add the finalizer: {}", e);
+                    continue;
+                }
+            };
+        }
+
+        let image = match source_image(&volume, &images) {
+            Ok(image) => image.map(|image| image.metadata.name.clone()),
+            Err(waiting) => {
+                let status = VolumeStatus {
+                    phase: VolumePhase::Pending,
+                    last_error: Some(waiting),
+                    ..volume.status.clone()
+                };
+                if status != volume.status {
+                    debug!("updating the status of {name}: {:?}", status.phase);
+                    volume.status = status;
+                    if let Err(e) = volume.update(store) {
+                        warn!("failed to update the status of {}: {}", name, e);
+                    }
+                }
+                continue;
+            }
+        };
+
+        if provisioned(&volume) {
+            continue;
+        }
+        // cloud-hypervisor doesn't expect its disks to change size under it
+        if volume_path(&volume).exists() {
+            let running = match running_users(store, &name).await {
+                Ok(running) => running,
+                Err(e) => {
+                    warn!("failed to get the vms using {}: {:#}", name, e);
+                    continue;
+                }
+            };
+            if !running.is_empty() {
+                let status = VolumeStatus {
+                    last_error: Some(format!(
+                        "waiting for {} to stop to resize",
+                        running.join(", ")
+                    )),
+                    ..volume.status.clone()
+                };
+                if status != volume.status {
+                    debug!("deferring the resize of {name}");
+                    volume.status = status;
+                    if let Err(e) = volume.update(store) {
+                        warn!("failed to update the status of {}: {}", name, e);
+                    }
+                }
+                continue;
+            }
+        }
+        let store = store.clone();
+        tasks.spawn(key, async move {
+            let image = image.map(|image| Image::get(&store, image)).transpose()?;
+            let result = provision(&volume, image.as_ref()).await;
+            let status = match &result {
+                Ok(size) => VolumeStatus {
+                    phase: VolumePhase::Ready,
+                    path: Some(volume_path(&volume).to_string_lossy().into()),
+                    size: Some(*size),
+                    last_error: None,
+                },
+                Err(e) => VolumeStatus {
+                    phase: VolumePhase::Failed,
+                    last_error: Some(format!("{e:#}")),
+                    ..volume.status.clone()
+                },
+            };
+            debug!("updating the status of {name}: {:?}", status.phase);
+            update_latest(&store, &name, |volume: &mut Volume| {
+                volume.status = status.clone();
+            })?;
+
+            result.map(|_| ())
+        });
+    }
+
+    Ok(())
+}
+
+/// Whether the image of the volume exists, at least as large as the spec
+/// wants it.
+fn provisioned(volume: &Volume) -> bool {
+    volume.status.phase == VolumePhase::Ready
+        && volume_path(volume).exists()
+        && matches!(
+            (size_bytes(&volume.spec.size), volume.status.size),
+            (Ok(size), Some(current)) if size <= current
+        )
+}
+
+/// The vms using the volume whose vmm might have its image open.
+async fn running_users(store: &Store, name: &str) -> eyre::Result<Vec<String>> {
+    let mut running = vec![];
+    for vm in users(store, name)? {
+        let state = systemd::get_service_state(&vm).await?;
+        if !matches!(state.active_state.as_str(), "inactive" | "failed") {
+            running.push(vm);
+        }
+    }
+
+    Ok(running)
+}
+
+/// The image to create the volume from, an error tells what it's waiting
+/// for.
+fn source_image<'a>(volume: &Volume, images: &'a [Image]) -> Result<Option<&'a Image>, String> {
+    let Some(name) = &volume.spec.image else {
+        return Ok(None);
+    };
+    // a reflink clone doesn't need its image anymore once it's created
+    if volume.spec.clone == CloneStrategy::Reflink && volume_path(volume).exists() {
+        return Ok(None);
+    }
+
+    match images.iter().find(|image| &image.metadata.name == name) {
+        Some(image) if image.status.phase == ImagePhase::Ready => Ok(Some(image)),
+        _ => Err(format!("waiting for image {name}")),
+    }
+}
+
+pub(super) async fn run(program: &str, args: &[&OsStr]) -> eyre::Result<Vec<u8>> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .await
+        .wrap_err_with(|| format!("failed to run {program}"))?;
+    if !output.status.success() {
+        bail!(
+            "{} failed with {}: {}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(output.stdout)
+}
+
+/// The size of the disk in the image, which isn't the file size for qcow2.
+async fn image_size(path: &Path, format: VolumeFormat) -> eyre::Result<u64> {
+    match format {
+        VolumeFormat::Raw => Ok(fs::metadata(path)?.len()),
+        VolumeFormat::Qcow2 => {
+            let info = run(
+                QEMU_IMG,
+                &["info".as_ref(), "--output=json".as_ref(), path.as_os_str()],
+            )
+            .await?;
+            let info: serde_json::Value = serde_json::from_slice(&info)?;
+            info["virtual-size"]
+                .as_u64()
+                .ok_or_else(|| eyre::eyre!("qemu-img didn't tell the size of {}", path.display()))
+        }
+    }
+}
+
+async fn grow(path: &Path, format: VolumeFormat, size: u64) -> eyre::Result<()> {
+    match format {
+        VolumeFormat::Raw => Ok(fs::OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(size)?),
+        VolumeFormat::Qcow2 => {
+            let size = size.to_string();
+            run(
+                QEMU_IMG,
+                &["resize".as_ref(), path.as_os_str(), size.as_ref()],
+            )
+            .await?;
+            Ok(())
+        }
+    }
+}
+
+/// Creates the volume from its image, as a copy or as an overlay on top of
+/// the cached copy.
+async fn clone_image(image: &Image, strategy: CloneStrategy, path: &Path) -> eyre::Result<()> {
+    let image_path = image_path(image);
+    match strategy {
+        CloneStrategy::Reflink => {
+            run(
+                "cp",
+                &[
+                    "--reflink=auto".as_ref(),
+                    "--sparse=always".as_ref(),
+                    image_path.as_os_str(),
+                    path.as_os_str(),
+                ],
+            )
+            .await?;
+        }
+        CloneStrategy::Overlay => {
+            let backing_format = match image.spec.format {
+                VolumeFormat::Raw => "raw",
+                VolumeFormat::Qcow2 => "qcow2",
+            };
+            run(
+                QEMU_IMG,
+                &[
+                    "create".as_ref(),
+                    "-f".as_ref(),
+                    "qcow2".as_ref(),
+                    "-b".as_ref(),
+                    image_path.as_os_str(),
+                    "-F".as_ref(),
+                    backing_format.as_ref(),
+                    path.as_os_str(),
+                ],
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Creates the image of the volume or grows it to the spec size, returning
+/// the size of the image.
+async fn provision(volume: &Volume, image: Option<&Image>) -> eyre::Result<u64> {
+    let path = volume_path(volume);
+    let format = volume.spec.format;
+    let size = size_bytes(&volume.spec.size)?;
+
+    if !path.exists() {
+        info!(
+            "creating the image of {} at {}",
+            volume.metadata.name,
+            path.display()
+        );
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        // the image is only moved in place once complete
+        let mut partial = OsString::from(&path);
+        partial.push(".partial");
+        let partial = Path::new(&partial);
+
+        match (image, &volume.spec.source, format) {
+            (Some(image), _, _) => clone_image(image, volume.spec.clone, partial).await?,
+            (None, Some(source), VolumeFormat::Raw) => {
+                run(
+                    "cp",
+                    &[
+                        "--reflink=auto".as_ref(),
+                        "--sparse=always".as_ref(),
+                        source.as_ref(),
+                        partial.as_os_str(),
+                    ],
+                )
+                .await?;
+            }
+            (None, Some(source), VolumeFormat::Qcow2) => {
+                run(
+                    QEMU_IMG,
+                    &[
+                        "convert".as_ref(),
+                        "-O".as_ref(),
+                        "qcow2".as_ref(),
+                        source.as_ref(),
+                        partial.as_os_str(),
+                    ],
+                )
+                .await?;
+            }
+            (None, None, VolumeFormat::Raw) => fs::File::create(partial)?.set_len(size)?,
+            (None, None, VolumeFormat::Qcow2) => {
+                let size = size.to_string();
+                run(
+                    QEMU_IMG,
+                    &[
+                        "create".as_ref(),
+                        "-f".as_ref(),
+                        "qcow2".as_ref(),
+                        partial.as_os_str(),
+                        size.as_ref(),
+                    ],
+                )
+                .await?;
+            }
+        }
+        if image_size(partial, format).await? < size {
+            grow(partial, format, size).await?;
+        }
+        fs::rename(partial, &path)?;
+    }
+
+    let current = image_size(&path, format).await?;
+    if current < size {
+        info!(
+            "growing the image of {} from {} to {} bytes",
+            volume.metadata.name, current, size
+        );
+        grow(&path, format, size).await?;
+        return image_size(&path, format).await;
+    }
+
+    Ok(current)
+}
@@ -0,0 +1,91 @@
+//! The protocol between tinyvmm and the agent running in its guests. Each
+//! connection carries a single request and its response, both a line of json.
+//! The file contents and the command outputs are base64 encoded.
+
+use serde::{Deserialize, Serialize};
+
+/// The vsock port the agent listens on in the guest.
+pub const PORT: u32 = 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Request {
+    Ping,
+    /// Lists the guest network interfaces with their addresses.
+    Interfaces,
+    /// Powers the guest off once the response is sent.
+    Shutdown,
+    Exec(Exec),
+    ReadFile(ReadFile),
+    WriteFile(WriteFile),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Response {
+    Pong { version: String },
+    Interfaces { interfaces: Vec<Interface> },
+    ShuttingDown,
+    Exited(ExecResult),
+    File(FileContent),
+    Written,
+    Error { message: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Interface {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+    /// In the `address/prefix` form.
+    #[serde(default)]
+    pub addresses: Vec<String>,
+}
+
+/// Runs the command without a shell and waits for it to exit.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Exec {
+    pub command: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ExecResult {
+    /// Not set if the command was killed by a signal.
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadFile {
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FileContent {
+    pub content: String,
+    pub mode: u32,
+}
+
+/// Replaces the file, keeping its mode unless one is given.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct WriteFile {
+    pub path: String,
+    pub content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+}
+
+pub fn encode(data: &[u8]) -> String {
+    data_encoding::BASE64.encode(data)
+}
+
+pub fn decode(data: &str) -> Result<Vec<u8>, data_encoding::DecodeError> {
+    data_encoding::BASE64.decode(data.as_bytes())
+}
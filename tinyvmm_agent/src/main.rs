@@ -0,0 +1,364 @@
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read, Write},
+    net::Ipv4Addr,
+    os::unix::{fs::PermissionsExt, io::FromRawFd, net::UnixListener},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+};
+
+use clap::Parser;
+use log::{debug, info, warn};
+use nix::{
+    ifaddrs::getifaddrs,
+    net::if_::InterfaceFlags,
+    sys::socket::{accept, bind, listen, socket, AddressFamily, SockFlag, SockType, VsockAddr},
+};
+use tinyvmm_agent::{
+    decode, encode, Exec, ExecResult, FileContent, Interface, ReadFile, Request, Response,
+    WriteFile, PORT,
+};
+
+/// Accepts the connections from any cid, only the host can reach the guest.
+const CID_ANY: u32 = u32::MAX;
+
+/// The guest side of the tinyvmm agent protocol
+#[derive(Debug, Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[clap(flatten)]
+    verbose: clap_verbosity_flag::Verbosity,
+
+    /// The vsock port to listen on
+    #[clap(long, default_value_t = PORT)]
+    port: u32,
+
+    /// Listens on a unix socket instead of vsock, for testing
+    #[clap(long)]
+    unix: Option<PathBuf>,
+}
+
+fn invalid(message: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message.to_string())
+}
+
+fn interfaces() -> io::Result<Vec<Interface>> {
+    let mut interfaces: Vec<Interface> = vec![];
+
+    for ifaddr in getifaddrs()? {
+        if ifaddr.flags.contains(InterfaceFlags::IFF_LOOPBACK) {
+            continue;
+        }
+        let position = match interfaces
+            .iter()
+            .position(|i| i.name == ifaddr.interface_name)
+        {
+            Some(position) => position,
+            None => {
+                interfaces.push(Interface {
+                    name: ifaddr.interface_name.clone(),
+                    mac: None,
+                    addresses: vec![],
+                });
+                interfaces.len() - 1
+            }
+        };
+        let interface = &mut interfaces[position];
+
+        let (Some(address), netmask) = (ifaddr.address, ifaddr.netmask) else {
+            continue;
+        };
+        if let Some(mac) = address.as_link_addr().and_then(|link| link.addr()) {
+            interface.mac = Some(
+                mac.iter()
+                    .map(|b| format!("{b:02x}"))
+                    .collect::<Vec<_>>()
+                    .join(":"),
+            );
+        } else if let Some(ip) = address.as_sockaddr_in() {
+            let prefix = netmask
+                .and_then(|mask| mask.as_sockaddr_in().map(|mask| mask.ip().count_ones()))
+                .unwrap_or(32);
+            let ip = Ipv4Addr::from(ip.ip());
+            interface.addresses.push(format!("{ip}/{prefix}"));
+        } else if let Some(ip) = address.as_sockaddr_in6() {
+            let prefix = netmask
+                .and_then(|mask| {
+                    mask.as_sockaddr_in6()
+                        .map(|mask| u128::from(mask.ip()).count_ones())
+                })
+                .unwrap_or(128);
+            interface.addresses.push(format!("{}/{prefix}", ip.ip()));
+        }
+    }
+
+    Ok(interfaces)
+}
+
+fn exec(exec: &Exec) -> io::Result<ExecResult> {
+    let Some((program, args)) = exec.command.split_first() else {
+        return Err(invalid("the command is empty"));
+    };
+
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()?;
+
+    Ok(ExecResult {
+        exit_code: output.status.code(),
+        stdout: encode(&output.stdout),
+        stderr: encode(&output.stderr),
+    })
+}
+
+fn read_file(read: &ReadFile) -> io::Result<FileContent> {
+    let content = fs::read(&read.path)?;
+    let mode = fs::metadata(&read.path)?.permissions().mode() & 0o7777;
+
+    Ok(FileContent {
+        content: encode(&content),
+        mode,
+    })
+}
+
+fn write_file(write: &WriteFile) -> io::Result<()> {
+    let content = decode(&write.content).map_err(invalid)?;
+    let path = Path::new(&write.path);
+    let mode = match (write.mode, fs::metadata(path)) {
+        (Some(mode), _) => Some(mode),
+        (None, Ok(metadata)) => Some(metadata.permissions().mode() & 0o7777),
+        (None, Err(_)) => None,
+    };
+
+    // the file is replaced at once, so that it's never seen half written
+    let mut partial = OsString::from(path);
+    partial.push(".partial");
+    fs::write(&partial, content)?;
+    if let Some(mode) = mode {
+        fs::set_permissions(&partial, fs::Permissions::from_mode(mode))?;
+    }
+    fs::rename(&partial, path)
+}
+
+fn power_off() {
+    info!("powering off");
+    let systemctl = Command::new("systemctl").arg("poweroff").status();
+    if !matches!(systemctl, Ok(status) if status.success()) {
+        if let Err(e) = Command::new("poweroff").status() {
+            warn!("failed to power off: {}", e);
+        }
+    }
+}
+
+fn handle(request: Request) -> Response {
+    let response = match request {
+        Request::Ping => Ok(Response::Pong {
+            version: env!("CARGO_PKG_VERSION").into(),
+        }),
+        Request::Interfaces => interfaces().map(|interfaces| Response::Interfaces { interfaces }),
+        Request::Shutdown => Ok(Response::ShuttingDown),
+        Request::Exec(request) => exec(&request).map(Response::Exited),
+        Request::ReadFile(request) => read_file(&request).map(Response::File),
+        Request::WriteFile(request) => write_file(&request).map(|_| Response::Written),
+    };
+
+    response.unwrap_or_else(|e| Response::Error {
+        message: e.to_string(),
+    })
+}
+
+/// Answers the request read from the connection, the caller powers off once
+/// a shutdown is answered.
+fn serve(reader: impl Read, mut writer: impl Write) -> io::Result<Response> {
+    let mut line = String::new();
+    BufReader::new(reader).read_line(&mut line)?;
+
+    let response = match serde_json::from_str::<Request>(&line) {
+        Ok(request) => {
+            debug!("got {:?}", request);
+            handle(request)
+        }
+        Err(e) => Response::Error {
+            message: format!("invalid request: {e}"),
+        },
+    };
+
+    let mut line = serde_json::to_vec(&response)?;
+    line.push(b'\n');
+    writer.write_all(&line)?;
+    writer.flush()?;
+
+    Ok(response)
+}
+
+fn spawn_serve(reader: impl Read + Send + 'static, writer: impl Write + Send + 'static) {
+    thread::spawn(move || match serve(reader, writer) {
+        Ok(Response::ShuttingDown) => power_off(),
+        Ok(_) => {}
+        Err(e) => warn!("failed to serve a request: {}", e),
+    });
+}
+
+fn main() -> io::Result<()> {
+    let cli = Cli::parse();
+
+    env_logger::Builder::new()
+        .filter_level(cli.verbose.log_level_filter())
+        .init();
+
+    if let Some(path) = &cli.unix {
+        let _ = fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        info!("listening on {}", path.display());
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => match stream.try_clone() {
+                    Ok(reader) => spawn_serve(reader, stream),
+                    Err(e) => warn!("failed to clone the connection: {}", e),
+                },
+                Err(e) => warn!("failed to accept: {}", e),
+            }
+        }
+        return Ok(());
+    }
+
+    let listener = socket(
+        AddressFamily::Vsock,
+        SockType::Stream,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    bind(listener, &VsockAddr::new(CID_ANY, cli.port))?;
+    listen(listener, 16)?;
+    info!("listening on vsock port {}", cli.port);
+
+    loop {
+        let fd = match accept(listener) {
+            Ok(fd) => fd,
+            Err(e) => {
+                warn!("failed to accept: {}", e);
+                continue;
+            }
+        };
+        // SAFETY: the connection was just accepted, nothing else owns it
+        let stream = unsafe { File::from_raw_fd(fd) };
+        match stream.try_clone() {
+            Ok(reader) => spawn_serve(reader, stream),
+            Err(e) => warn!("failed to clone the connection: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+
+    use super::*;
+
+    /// Sends the request through serve and returns the response the client
+    /// reads back.
+    fn round_trip(request: &str) -> Response {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        writeln!(client, "{request}").unwrap();
+
+        let served = serve(server.try_clone().unwrap(), server).unwrap();
+        let mut line = String::new();
+        BufReader::new(client).read_line(&mut line).unwrap();
+        let response: Response = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            serde_json::to_value(&response).unwrap(),
+            serde_json::to_value(&served).unwrap()
+        );
+
+        response
+    }
+
+    fn request(request: Request) -> Response {
+        round_trip(&serde_json::to_string(&request).unwrap())
+    }
+
+    fn scratch_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("tinyvmm-agent-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn answers_a_ping() {
+        assert!(matches!(
+            request(Request::Ping),
+            Response::Pong { version } if version == env!("CARGO_PKG_VERSION")
+        ));
+    }
+
+    #[test]
+    fn lists_the_interfaces() {
+        let Response::Interfaces { interfaces } = request(Request::Interfaces) else {
+            panic!("no interfaces");
+        };
+        assert!(interfaces.iter().all(|i| i.name != "lo"));
+    }
+
+    #[test]
+    fn answers_a_shutdown_without_powering_off() {
+        assert!(matches!(request(Request::Shutdown), Response::ShuttingDown));
+    }
+
+    #[test]
+    fn runs_a_command() {
+        let response = request(Request::Exec(Exec {
+            command: vec![
+                "sh".into(),
+                "-c".into(),
+                "echo out; echo err >&2; exit 3".into(),
+            ],
+        }));
+        let Response::Exited(result) = response else {
+            panic!("unexpected {response:?}");
+        };
+        assert_eq!(result.exit_code, Some(3));
+        assert_eq!(decode(&result.stdout).unwrap(), b"out\n");
+        assert_eq!(decode(&result.stderr).unwrap(), b"err\n");
+
+        assert!(matches!(
+            request(Request::Exec(Exec { command: vec![] })),
+            Response::Error { message } if message == "the command is empty"
+        ));
+    }
+
+    #[test]
+    fn writes_and_reads_a_file() {
+        let path = scratch_file("file");
+        let path_str = path.to_string_lossy().to_string();
+
+        let response = request(Request::WriteFile(WriteFile {
+            path: path_str.clone(),
+            content: encode(b"hello"),
+            mode: Some(0o640),
+        }));
+        assert!(matches!(response, Response::Written), "{response:?}");
+
+        let response = request(Request::ReadFile(ReadFile { path: path_str }));
+        let Response::File(file) = response else {
+            panic!("unexpected {response:?}");
+        };
+        assert_eq!(decode(&file.content).unwrap(), b"hello");
+        assert_eq!(file.mode, 0o640);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reports_the_errors() {
+        let path = scratch_file("missing").to_string_lossy().to_string();
+        assert!(matches!(
+            request(Request::ReadFile(ReadFile { path })),
+            Response::Error { .. }
+        ));
+        assert!(matches!(
+            round_trip("{\"type\":\"reboot\"}"),
+            Response::Error { message } if message.starts_with("invalid request")
+        ));
+    }
+}